@@ -0,0 +1,224 @@
+use crate::musical_keyboard::{Note, NoteEvent};
+use crate::random::Random;
+use crate::tuning::Tuning;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    /// Up and back down, without playing the top and bottom notes twice.
+    UpDown,
+    Random,
+    /// In the order the keys were pressed.
+    AsPlayed,
+    /// Every held note at once, an octave higher on each step.
+    Chord,
+}
+
+impl ArpMode {
+    pub fn from_name(name: &str) -> Option<ArpMode> {
+        match name {
+            "up" => Some(ArpMode::Up),
+            "down" => Some(ArpMode::Down),
+            "up-down" => Some(ArpMode::UpDown),
+            "random" => Some(ArpMode::Random),
+            "played" => Some(ArpMode::AsPlayed),
+            "chord" => Some(ArpMode::Chord),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct ArpSettings {
+    pub mode: ArpMode,
+    /// How many octaves the held notes are repeated over, 1 to 4.
+    pub octaves: u8,
+    /// Length of a step as a fraction of a whole note, 0.0625 for sixteenths.
+    pub division: f32,
+    /// How long each note is held, as a fraction of the step.
+    pub gate: f32,
+    /// Keep playing the notes after the keys are let go, until new ones are
+    /// pressed.
+    pub latch: bool,
+}
+
+impl Default for ArpSettings {
+    fn default() -> Self {
+        ArpSettings {
+            mode: ArpMode::Up,
+            octaves: 1,
+            division: 1.0 / 16.0,
+            gate: 0.5,
+            latch: false,
+        }
+    }
+}
+
+/// Plays the held notes one after the other in time with the tempo. It
+/// takes the presses and releases meant for the voices and sends its own.
+pub struct Arpeggiator {
+    sample_rate: f32,
+    settings: ArpSettings,
+    tuning: Tuning,
+    tempo: f32,
+    random: Random,
+    /// The notes to play, in the order they were pressed.
+    held: Vec<Note>,
+    /// Keys that are down, which differs from `held` when latching.
+    down: Vec<u8>,
+    /// Steps played since the notes started.
+    position: usize,
+    /// Samples since the current step started, None before the first step.
+    elapsed: Option<f32>,
+    step_length: f32,
+    /// Keys the current step is playing.
+    sounding: Vec<u8>,
+}
+
+impl Arpeggiator {
+    pub fn new(sample_rate: u32, settings: ArpSettings, tempo: f32, tuning: Tuning) -> Arpeggiator {
+        Arpeggiator {
+            sample_rate: sample_rate as f32,
+            settings,
+            tuning,
+            tempo,
+            random: Random::new(sample_rate as u64),
+            held: Vec::with_capacity(128),
+            down: Vec::with_capacity(128),
+            position: 0,
+            elapsed: None,
+            step_length: 0.0,
+            sounding: Vec::with_capacity(128),
+        }
+    }
+
+    /// Beats per minute, taken up from the next step.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.clamp(20.0, 400.0);
+    }
+
+    /// Takes a press or release of a key.
+    pub fn handle_event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::Press(note) => {
+                // with latch, a new chord replaces the one left playing
+                if self.settings.latch && self.down.is_empty() {
+                    self.held.clear();
+                }
+                self.down.retain(|&key| key != note.key);
+                self.held.retain(|held| held.key != note.key);
+                if self.held.len() < self.held.capacity() {
+                    self.down.push(note.key);
+                    self.held.push(note);
+                }
+            }
+            NoteEvent::Up(key) => {
+                self.down.retain(|&down| down != key);
+                if !self.settings.latch {
+                    self.held.retain(|held| held.key != key);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances by one sample, playing what is due.
+    pub fn tick<F: FnMut(NoteEvent)>(&mut self, mut play: F) {
+        if self.held.is_empty() {
+            self.release(&mut play);
+            // the next note pressed starts right away
+            self.elapsed = None;
+            self.position = 0;
+            return;
+        }
+
+        let elapsed = match self.elapsed {
+            Some(elapsed) if elapsed < self.step_length => elapsed,
+            // what is left over keeps the steps from drifting
+            elapsed => {
+                let elapsed = elapsed.map_or(0.0, |elapsed| elapsed - self.step_length);
+                self.start_step(&mut play);
+                elapsed
+            }
+        };
+        if elapsed >= self.settings.gate * self.step_length {
+            self.release(&mut play);
+        }
+        self.elapsed = Some(elapsed + 1.0);
+    }
+
+    fn release<F: FnMut(NoteEvent)>(&mut self, play: &mut F) {
+        for key in self.sounding.drain(..) {
+            play(NoteEvent::Up(key));
+        }
+    }
+
+    fn start_step<F: FnMut(NoteEvent)>(&mut self, play: &mut F) {
+        self.release(play);
+        let beats = self.settings.division * 4.0;
+        self.step_length = self.sample_rate * 60.0 * beats / self.tempo;
+
+        let count = self.held.len();
+        let octaves = self.settings.octaves.clamp(1, 4) as usize;
+        let length = count * octaves;
+        let position = self.position;
+        self.position += 1;
+
+        // the step as an index into the held notes sorted or as played,
+        // repeated an octave higher for every octave
+        let step = match self.settings.mode {
+            ArpMode::Chord => {
+                for index in 0..count {
+                    self.play(index, position % octaves, play);
+                }
+                return;
+            }
+            ArpMode::Up | ArpMode::AsPlayed => position % length,
+            ArpMode::Down => length - 1 - position % length,
+            ArpMode::UpDown if length > 1 => {
+                let step = position % (2 * length - 2);
+                if step < length {
+                    step
+                } else {
+                    2 * length - 2 - step
+                }
+            }
+            ArpMode::UpDown => 0,
+            ArpMode::Random => (self.random.next_u64() % length as u64) as usize,
+        };
+        let (octave, index) = (step / count, step % count);
+        let index = if self.settings.mode == ArpMode::AsPlayed {
+            index
+        } else {
+            // the held note with `index` notes below it
+            let held = &self.held;
+            held.iter()
+                .position(|note| held.iter().filter(|other| other.key < note.key).count() == index)
+                .unwrap_or(0)
+        };
+        self.play(index, octave, play);
+    }
+
+    /// Plays a held note some octaves up.
+    fn play<F: FnMut(NoteEvent)>(&mut self, index: usize, octave: usize, play: &mut F) {
+        let held = self.held[index];
+        let note = if octave == 0 {
+            Some(held)
+        } else {
+            let key = held.key as usize + 12 * octave;
+            if key > 127 {
+                None
+            } else {
+                self.tuning.note(key as u8, held.velocity)
+            }
+        };
+        if let Some(note) = note {
+            if !self.sounding.contains(&note.key) && self.sounding.len() < self.sounding.capacity()
+            {
+                self.sounding.push(note.key);
+                play(NoteEvent::Press(note));
+            }
+        }
+    }
+}
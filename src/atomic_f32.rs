@@ -0,0 +1,24 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// An `f32` that can be changed through a shared reference without locking,
+/// kept as its bits in an `AtomicU32`.
+#[derive(Default)]
+pub struct AtomicF32 {
+    bits: AtomicU32,
+}
+
+impl AtomicF32 {
+    pub fn new(value: f32) -> AtomicF32 {
+        AtomicF32 {
+            bits: AtomicU32::new(value.to_bits()),
+        }
+    }
+
+    pub fn set(&self, value: f32) {
+        self.bits.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,196 @@
+//! Second order filters from the Audio EQ Cookbook by Robert Bristow-Johnson,
+//! https://www.w3.org/TR/audio-eq-cookbook/
+
+/// How long a change of coefficients takes to settle, in seconds.
+const SMOOTHING_SECONDS: f32 = 0.02;
+/// Corner frequency of the DC blocker in Hz.
+const DC_BLOCKER_FREQUENCY: f32 = 10.0;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum BiquadKind {
+    LowPass,
+    HighPass,
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+impl BiquadKind {
+    pub fn from_name(name: &str) -> Option<BiquadKind> {
+        match name {
+            "lowpass" => Some(BiquadKind::LowPass),
+            "highpass" => Some(BiquadKind::HighPass),
+            "peak" => Some(BiquadKind::Peaking),
+            "lowshelf" => Some(BiquadKind::LowShelf),
+            "highshelf" => Some(BiquadKind::HighShelf),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BiquadKind::LowPass => "lowpass",
+            BiquadKind::HighPass => "highpass",
+            BiquadKind::Peaking => "peak",
+            BiquadKind::LowShelf => "lowshelf",
+            BiquadKind::HighShelf => "highshelf",
+        }
+    }
+}
+
+/// Filter coefficients, normalized so a0 is 1.
+#[derive(Copy, Clone, Default)]
+pub struct Coefficients {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Coefficients {
+    /// `gain` in dB is only used by the peaking and shelving kinds. For the
+    /// shelves `q` is the shelf slope, 1.0 being the steepest without a bump.
+    pub fn new(
+        kind: BiquadKind,
+        sample_rate: u32,
+        frequency: f32,
+        q: f32,
+        gain: f32,
+    ) -> Coefficients {
+        let sample_rate = sample_rate as f32;
+        let frequency = frequency.clamp(1.0, sample_rate * 0.49);
+        let w0 = 2.0 * std::f32::consts::PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let a = 10.0_f32.powf(gain / 40.0);
+        let q = q.max(0.01);
+        let alpha = match kind {
+            BiquadKind::LowShelf | BiquadKind::HighShelf => {
+                sin / 2.0 * ((a + 1.0 / a) * (1.0 / q - 1.0) + 2.0).max(0.0).sqrt()
+            }
+            _ => sin / (2.0 * q),
+        };
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            BiquadKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BiquadKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            BiquadKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// A first order high pass just below hearing, taking out any offset
+    /// that asymmetric processing leaves in the signal.
+    pub fn dc_blocker(sample_rate: u32) -> Coefficients {
+        let r = 1.0 - 2.0 * std::f32::consts::PI * DC_BLOCKER_FREQUENCY / sample_rate as f32;
+        Coefficients {
+            b0: 1.0,
+            b1: -1.0,
+            b2: 0.0,
+            a1: -r,
+            a2: 0.0,
+        }
+    }
+}
+
+/// The state of one channel through a biquad, in transposed direct form II.
+#[derive(Copy, Clone, Default)]
+pub struct Biquad {
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    pub fn process(&mut self, c: &Coefficients, input: f32) -> f32 {
+        let output = c.b0 * input + self.z1;
+        self.z1 = c.b1 * input - c.a1 * output + self.z2;
+        self.z2 = c.b2 * input - c.a2 * output;
+        output
+    }
+}
+
+/// Coefficients that glide to new values instead of jumping, so changing a
+/// filter while it plays does not click.
+#[derive(Copy, Clone)]
+pub struct SmoothedCoefficients {
+    current: Coefficients,
+    target: Coefficients,
+    rate: f32,
+}
+
+impl SmoothedCoefficients {
+    pub fn new(coefficients: Coefficients, sample_rate: u32) -> SmoothedCoefficients {
+        SmoothedCoefficients {
+            current: coefficients,
+            target: coefficients,
+            rate: 1.0 - (-1.0 / (SMOOTHING_SECONDS * sample_rate as f32)).exp(),
+        }
+    }
+
+    pub fn set_target(&mut self, target: Coefficients) {
+        self.target = target;
+    }
+
+    /// Jumps to `coefficients` without smoothing.
+    pub fn set(&mut self, coefficients: Coefficients) {
+        self.current = coefficients;
+        self.target = coefficients;
+    }
+
+    /// Moves one sample closer to the target and returns the coefficients to use.
+    pub fn next(&mut self) -> &Coefficients {
+        let rate = self.rate;
+        let step = |current: &mut f32, target: f32| *current += (target - *current) * rate;
+        step(&mut self.current.b0, self.target.b0);
+        step(&mut self.current.b1, self.target.b1);
+        step(&mut self.current.b2, self.target.b2);
+        step(&mut self.current.a1, self.target.a1);
+        step(&mut self.current.a2, self.target.a2);
+        &self.current
+    }
+}
@@ -0,0 +1,122 @@
+use crate::effect::Effect;
+use crate::lfo::{Lfo, ModulationSettings};
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+/// A delay line read at a time swept by the LFO, with feedback. Chorus and
+/// flanger differ only in how long the delay is and how far it moves.
+struct ModulatedDelay {
+    sample_rate: f32,
+    settings: ModulationSettings,
+    lfo: Lfo,
+    /// Shortest delay in seconds.
+    base: f32,
+    /// How much longer the delay gets at full depth, in seconds.
+    sweep: f32,
+    left_line: Vec<f32>,
+    right_line: Vec<f32>,
+    write_index: usize,
+}
+
+impl ModulatedDelay {
+    fn new(
+        sample_rate: u32,
+        settings: ModulationSettings,
+        base: f32,
+        sweep: f32,
+    ) -> ModulatedDelay {
+        let length = ((base + sweep) * sample_rate as f32) as usize + 3;
+        ModulatedDelay {
+            sample_rate: sample_rate as f32,
+            settings,
+            lfo: Lfo::new(sample_rate, settings.rate),
+            base,
+            sweep,
+            left_line: vec![0.0; length],
+            right_line: vec![0.0; length],
+            write_index: 0,
+        }
+    }
+
+    /// Reads `delay` samples back from `line`, between samples.
+    fn read(&self, line: &[f32], delay: f32) -> f32 {
+        let position = self.write_index as f32 + line.len() as f32 - delay;
+        let index = position.floor() as usize;
+        let fraction = position - position.floor();
+        let a = line[index % line.len()];
+        let b = line[(index + 1) % line.len()];
+        a + (b - a) * fraction
+    }
+
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let (left_lfo, right_lfo) = self.lfo.next_stereo();
+        let depth = self.settings.depth.clamp(0.0, 1.0);
+        let delay =
+            |lfo: f32| (self.base + self.sweep * depth * (lfo + 1.0) * 0.5) * self.sample_rate;
+        let (left_delay, right_delay) = (delay(left_lfo).max(1.0), delay(right_lfo).max(1.0));
+
+        let delayed_left = self.read(&self.left_line, left_delay);
+        let delayed_right = self.read(&self.right_line, right_delay);
+
+        let feedback = self.settings.feedback.clamp(-0.95, 0.95);
+        self.left_line[self.write_index] = left + delayed_left * feedback;
+        self.right_line[self.write_index] = right + delayed_right * feedback;
+        self.write_index = (self.write_index + 1) % self.left_line.len();
+
+        let mix = self.settings.mix.clamp(0.0, 1.0);
+        (
+            left * (1.0 - mix) + delayed_left * mix,
+            right * (1.0 - mix) + delayed_right * mix,
+        )
+    }
+}
+
+/// Thickens the sound with a copy delayed by 10 - 30 ms that drifts in pitch.
+pub struct Chorus {
+    delay: ModulatedDelay,
+}
+
+impl Chorus {
+    pub fn new(sample_rate: u32, settings: ModulationSettings) -> Chorus {
+        Chorus {
+            delay: ModulatedDelay::new(sample_rate, settings, 0.01, 0.02),
+        }
+    }
+}
+
+impl Effect for Chorus {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.delay.process(left, right)
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        if let NoteEvent::Parameter(Parameter::ChorusMix, mix) = event {
+            self.delay.settings.mix = mix;
+        }
+    }
+}
+
+/// A comb filter sweeping up and down, from a delay of 0.5 - 5 ms.
+pub struct Flanger {
+    delay: ModulatedDelay,
+}
+
+impl Flanger {
+    pub fn new(sample_rate: u32, settings: ModulationSettings) -> Flanger {
+        Flanger {
+            delay: ModulatedDelay::new(sample_rate, settings, 0.0005, 0.0045),
+        }
+    }
+}
+
+impl Effect for Flanger {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.delay.process(left, right)
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        if let NoteEvent::Parameter(Parameter::FlangerMix, mix) = event {
+            self.delay.settings.mix = mix;
+        }
+    }
+}
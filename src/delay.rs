@@ -0,0 +1,253 @@
+use crate::effect::Effect;
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+/// Longest delay time, which sets the size of the delay lines.
+const MAX_DELAY_SECONDS: f32 = 4.0;
+/// Time constant of the slide to a new delay time. Sliding rather than
+/// jumping bends the pitch of the echoes for a moment, like a tape delay,
+/// instead of clicking.
+const TIME_SMOOTHING_SECONDS: f32 = 0.1;
+/// Time constant of the slide to a new mix or feedback, short enough to
+/// follow a knob but long enough not to click.
+const LEVEL_SMOOTHING_SECONDS: f32 = 0.02;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum DelayTime {
+    Milliseconds(f32),
+    /// A fraction of a whole note at the delay's tempo, e.g. 0.125 for an
+    /// eighth note.
+    Division(f32),
+}
+
+impl DelayTime {
+    /// Parses milliseconds (`350`) or a note division (`1/8`), optionally
+    /// dotted (`1/8d`) or triplet (`1/8t`).
+    pub fn from_name(name: &str) -> Option<DelayTime> {
+        if let Some((numerator, denominator)) = name.split_once('/') {
+            let (denominator, factor) = match denominator.strip_suffix('d') {
+                Some(denominator) => (denominator, 1.5),
+                None => match denominator.strip_suffix('t') {
+                    Some(denominator) => (denominator, 2.0 / 3.0),
+                    None => (denominator, 1.0),
+                },
+            };
+            let numerator: f32 = numerator.parse().ok()?;
+            let denominator: f32 = denominator.parse().ok()?;
+            if denominator <= 0.0 {
+                return None;
+            }
+            return Some(DelayTime::Division(numerator / denominator * factor));
+        }
+        name.parse().ok().map(DelayTime::Milliseconds)
+    }
+
+    /// The name `from_name` reads back, writing a note length as `1/8`, `1/8d`
+    /// or `1/8t` where it is one.
+    pub fn name(&self) -> String {
+        match *self {
+            DelayTime::Milliseconds(ms) => format!("{}", ms),
+            DelayTime::Division(fraction) => {
+                for (suffix, factor) in [("", 1.0), ("d", 1.5), ("t", 2.0 / 3.0)] {
+                    let denominator = factor / fraction;
+                    if (denominator - denominator.round()).abs() < 1e-3 {
+                        return format!("1/{}{}", denominator.round(), suffix);
+                    }
+                }
+                format!("{}/1", fraction)
+            }
+        }
+    }
+
+    pub fn seconds(&self, tempo: f32) -> f32 {
+        match self {
+            DelayTime::Milliseconds(ms) => ms / 1000.0,
+            // four beats to a whole note
+            DelayTime::Division(fraction) => fraction * 4.0 * 60.0 / tempo.max(1.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct DelaySettings {
+    pub time: DelayTime,
+    /// Beats per minute that note divisions are timed against.
+    pub tempo: f32,
+    /// How much of each echo is fed back, 0.0 to just below 1.0.
+    pub feedback: f32,
+    /// Cutoff in Hz of the high pass in the feedback loop, thinning out
+    /// each repeat.
+    pub low_cut: f32,
+    /// Cutoff in Hz of the low pass in the feedback loop, darkening each repeat.
+    pub high_cut: f32,
+    /// Echoes bounce between the left and right channels.
+    pub ping_pong: bool,
+    /// 0.0 (dry only) to 1.0 (echoes only).
+    pub mix: f32,
+}
+
+impl Default for DelaySettings {
+    fn default() -> Self {
+        DelaySettings {
+            time: DelayTime::Milliseconds(350.0),
+            tempo: 120.0,
+            feedback: 0.4,
+            low_cut: 80.0,
+            high_cut: 6000.0,
+            ping_pong: false,
+            mix: 0.0,
+        }
+    }
+}
+
+/// A one pole low pass and high pass in series.
+#[derive(Copy, Clone, Default)]
+struct FeedbackFilter {
+    low: f32,
+    high: f32,
+}
+
+impl FeedbackFilter {
+    fn process(&mut self, input: f32, low_pass: f32, high_pass: f32) -> f32 {
+        self.low += low_pass * (input - self.low);
+        self.high += high_pass * (self.low - self.high);
+        self.low - self.high
+    }
+}
+
+/// A stereo delay for the mixed down voices.
+pub struct Delay {
+    sample_rate: f32,
+    settings: DelaySettings,
+    left_line: Vec<f32>,
+    right_line: Vec<f32>,
+    write_index: usize,
+    /// Delay time in samples, gliding towards `target_time`.
+    current_time: f32,
+    target_time: f32,
+    smoothing: f32,
+    /// Mix and feedback, gliding towards the settings.
+    current_mix: f32,
+    current_feedback: f32,
+    level_smoothing: f32,
+    /// One pole coefficients of the feedback filters.
+    low_pass: f32,
+    high_pass: f32,
+    left_filter: FeedbackFilter,
+    right_filter: FeedbackFilter,
+}
+
+impl Delay {
+    pub fn new(sample_rate: u32, settings: DelaySettings) -> Delay {
+        let sample_rate = sample_rate as f32;
+        let length = (MAX_DELAY_SECONDS * sample_rate) as usize + 2;
+        let one_pole = |cutoff: f32| {
+            1.0 - (-2.0 * std::f32::consts::PI * cutoff.clamp(1.0, sample_rate * 0.45)
+                / sample_rate)
+                .exp()
+        };
+
+        let mut delay = Delay {
+            sample_rate,
+            settings,
+            left_line: vec![0.0; length],
+            right_line: vec![0.0; length],
+            write_index: 0,
+            current_time: 0.0,
+            target_time: 0.0,
+            smoothing: 1.0 / (TIME_SMOOTHING_SECONDS * sample_rate).max(1.0),
+            current_mix: settings.mix.clamp(0.0, 1.0),
+            current_feedback: settings.feedback.clamp(0.0, 0.99),
+            level_smoothing: 1.0 / (LEVEL_SMOOTHING_SECONDS * sample_rate).max(1.0),
+            low_pass: one_pole(settings.high_cut),
+            high_pass: one_pole(settings.low_cut),
+            left_filter: FeedbackFilter::default(),
+            right_filter: FeedbackFilter::default(),
+        };
+        delay.set_time(settings.time);
+        delay.current_time = delay.target_time;
+        delay
+    }
+
+    fn set_time(&mut self, time: DelayTime) {
+        self.settings.time = time;
+        let samples = time.seconds(self.settings.tempo) * self.sample_rate;
+        self.target_time = samples.clamp(1.0, (self.left_line.len() - 2) as f32);
+    }
+
+    /// Reads a delay line `self.current_time` samples back, between samples.
+    fn read(&self, line: &[f32]) -> f32 {
+        let position = self.write_index as f32 + line.len() as f32 - self.current_time;
+        let index = position.floor() as usize;
+        let fraction = position - position.floor();
+        let a = line[index % line.len()];
+        let b = line[(index + 1) % line.len()];
+        a + (b - a) * fraction
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        self.current_time += (self.target_time - self.current_time) * self.smoothing;
+        let target_mix = self.settings.mix.clamp(0.0, 1.0);
+        self.current_mix += (target_mix - self.current_mix) * self.level_smoothing;
+        let target_feedback = self.settings.feedback.clamp(0.0, 0.99);
+        self.current_feedback += (target_feedback - self.current_feedback) * self.level_smoothing;
+
+        let delayed_left = self.read(&self.left_line);
+        let delayed_right = self.read(&self.right_line);
+        let feedback = self.current_feedback;
+        let echo_left = feedback
+            * self
+                .left_filter
+                .process(delayed_left, self.low_pass, self.high_pass);
+        let echo_right = feedback
+            * self
+                .right_filter
+                .process(delayed_right, self.low_pass, self.high_pass);
+
+        let (write_left, write_right) = if self.settings.ping_pong {
+            // everything enters on the left and then crosses over on every repeat
+            ((left + right) * 0.5 + echo_right, echo_left)
+        } else {
+            (left + echo_left, right + echo_right)
+        };
+        self.left_line[self.write_index] = write_left;
+        self.right_line[self.write_index] = write_right;
+        self.write_index = (self.write_index + 1) % self.left_line.len();
+
+        let mix = self.current_mix;
+        (
+            left * (1.0 - mix) + delayed_left * mix,
+            right * (1.0 - mix) + delayed_right * mix,
+        )
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::Parameter(Parameter::DelayTime, ms) => {
+                self.set_time(DelayTime::Milliseconds(ms))
+            }
+            NoteEvent::Parameter(Parameter::DelayNote, fraction) => {
+                let time = if fraction > 0.0 {
+                    DelayTime::Division(fraction)
+                } else {
+                    // back to milliseconds, keeping the length playing
+                    let seconds = self.settings.time.seconds(self.settings.tempo);
+                    DelayTime::Milliseconds(seconds * 1000.0)
+                };
+                self.set_time(time)
+            }
+            // a note length keeps in time with the sequencer
+            NoteEvent::Parameter(Parameter::Tempo, tempo) => {
+                self.settings.tempo = tempo;
+                self.set_time(self.settings.time)
+            }
+            NoteEvent::Parameter(Parameter::DelayFeedback, feedback) => {
+                self.settings.feedback = feedback
+            }
+            NoteEvent::Parameter(Parameter::DelayMix, mix) => self.settings.mix = mix,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use rodio::Source;
+use std::{f32::consts::PI, sync::mpsc::Receiver, time::Duration};
+
+use crate::biquad::{Biquad, BiquadKind, Coefficients};
+use crate::envvelope::EnvelopeSettings;
+use crate::music_data::MusicData;
+use crate::musical_keyboard::{Note, NoteEvent};
+use crate::random::Random;
+
+/// Frequencies of the square waves in the hi-hat cluster, from the TR-808.
+const HAT_FREQUENCIES: [f32; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+/// A hit ends once its longest decay has fallen this many time constants,
+/// about -60 dB.
+const TIME_CONSTANTS: f32 = 7.0;
+/// Seconds between the bursts of a clap.
+const CLAP_SPACING: f32 = 0.011;
+const CLAP_BURSTS: usize = 3;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Drum {
+    Kick,
+    Snare,
+    ClosedHat,
+    OpenHat,
+    Clap,
+}
+
+impl Drum {
+    pub fn from_name(name: &str) -> Option<Drum> {
+        match name {
+            "kick" => Some(Drum::Kick),
+            "snare" => Some(Drum::Snare),
+            "closed-hat" => Some(Drum::ClosedHat),
+            "open-hat" => Some(Drum::OpenHat),
+            "clap" => Some(Drum::Clap),
+            _ => None,
+        }
+    }
+
+    /// The drum a key plays, on the General MIDI percussion keys: 36 (C2)
+    /// kick, 38 snare, 39 clap, 42 closed and 46 open hi-hat. The kick and
+    /// snare also answer on their second keys, 35 and 40, and the closed
+    /// hi-hat on the pedal hi-hat, 44.
+    pub fn from_key(key: u8) -> Option<Drum> {
+        match key {
+            35 | 36 => Some(Drum::Kick),
+            38 | 40 => Some(Drum::Snare),
+            39 => Some(Drum::Clap),
+            42 | 44 => Some(Drum::ClosedHat),
+            46 => Some(Drum::OpenHat),
+            _ => None,
+        }
+    }
+}
+
+/// How a drum sounds, each relative to the drum's own sound.
+#[derive(Copy, Clone)]
+pub struct DrumSettings {
+    /// Semitones up or down.
+    pub tune: f32,
+    /// How long it rings, 2.0 for twice as long.
+    pub decay: f32,
+    /// 0.0 to 1.0: the click of the kick, the noise against the tone of the
+    /// snare, the noise against the metallic cluster of the hi-hats and how
+    /// bright the clap is.
+    pub tone: f32,
+}
+
+impl Default for DrumSettings {
+    fn default() -> Self {
+        DrumSettings {
+            tune: 0.0,
+            decay: 1.0,
+            tone: 0.5,
+        }
+    }
+}
+
+/// The settings of every drum.
+#[derive(Copy, Clone, Default)]
+pub struct DrumKit {
+    drums: [DrumSettings; 5],
+}
+
+impl DrumKit {
+    pub fn get(&self, drum: Drum) -> DrumSettings {
+        self.drums[drum as usize]
+    }
+
+    /// Reads a drum's settings from `<drum>:<tune>[:<decay>[:<tone>]]`, e.g.
+    /// `kick:-3:1.5:0.8`.
+    pub fn set_from_spec(&mut self, spec: &str) -> Option<()> {
+        let mut parts = spec.split(':');
+        let drum = Drum::from_name(parts.next()?)?;
+        let mut settings = DrumSettings {
+            tune: parts.next()?.parse().ok()?,
+            ..DrumSettings::default()
+        };
+        if let Some(decay) = parts.next() {
+            settings.decay = decay.parse().ok()?;
+        }
+        if let Some(tone) = parts.next() {
+            settings.tone = tone.parse().ok()?;
+        }
+        settings.decay = settings.decay.max(0.01);
+        settings.tone = settings.tone.clamp(0.0, 1.0);
+        self.drums[drum as usize] = settings;
+        Some(())
+    }
+}
+
+/// One drum being played, up to when it has died away.
+struct Hit {
+    drum: Drum,
+    key: u8,
+    settings: DrumSettings,
+    /// 1.0 untuned, 2.0 an octave up.
+    pitch: f32,
+    /// Samples since the hit.
+    age: usize,
+    length: usize,
+    /// Phases of the oscillators, from 0.0 to 1.0.
+    phases: [f32; 6],
+    high_pass: Coefficients,
+    low_pass: Coefficients,
+    high: Biquad,
+    low: Biquad,
+}
+
+/// A voice of the drum machine. Every drum is synthesized from sine and
+/// square oscillators and filtered noise, and is played by its key with the
+/// key's velocity. The drums play to their end whatever happens to the key,
+/// and the envelope after it is only let go once they have died away.
+pub struct DrumVoice {
+    sample_rate: u32,
+    receiver: Receiver<NoteEvent>,
+    kit: DrumKit,
+    random: Random,
+    hit: Option<Hit>,
+    current_event: Option<NoteEvent>,
+    envelope: Option<EnvelopeSettings>,
+}
+
+impl DrumVoice {
+    pub fn new(
+        sample_rate: u32,
+        kit: DrumKit,
+        seed: u64,
+        receiver: Receiver<NoteEvent>,
+    ) -> DrumVoice {
+        DrumVoice {
+            sample_rate,
+            receiver,
+            kit,
+            random: Random::new(seed),
+            hit: None,
+            current_event: None,
+            envelope: None,
+        }
+    }
+
+    fn start(&mut self, note: Note) -> bool {
+        let drum = match Drum::from_key(note.key) {
+            Some(drum) => drum,
+            None => return false,
+        };
+        let settings = self.kit.get(drum);
+        let pitch = 2.0_f32.powf(settings.tune / 12.0);
+        let sample_rate = self.sample_rate as f32;
+        // the longest decay of the drum, in seconds
+        let longest = match drum {
+            Drum::Kick => 0.4,
+            Drum::Snare => 0.15,
+            Drum::ClosedHat => 0.04,
+            Drum::OpenHat => 0.3,
+            Drum::Clap => 0.15,
+        } * settings.decay;
+        let (high_pass, low_pass) = match drum {
+            Drum::Kick => (20.0, 20000.0),
+            Drum::Snare => (1500.0 * pitch, 12000.0),
+            Drum::ClosedHat | Drum::OpenHat => (7000.0 * pitch, 16000.0),
+            Drum::Clap => (800.0 * pitch, (1500.0 + 4000.0 * settings.tone) * pitch),
+        };
+        let filter =
+            |kind, frequency| Coefficients::new(kind, self.sample_rate, frequency, 1.0, 0.0);
+        self.hit = Some(Hit {
+            drum,
+            key: note.key,
+            settings,
+            pitch,
+            age: 0,
+            length: ((longest * TIME_CONSTANTS + CLAP_SPACING * CLAP_BURSTS as f32) * sample_rate)
+                as usize,
+            phases: [0.0; 6],
+            high_pass: filter(BiquadKind::HighPass, high_pass),
+            low_pass: filter(BiquadKind::LowPass, low_pass),
+            high: Biquad::default(),
+            low: Biquad::default(),
+        });
+        // the drums shape themselves, so the envelope only follows the velocity
+        self.envelope = Some(EnvelopeSettings {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.005,
+            ..EnvelopeSettings::default()
+        });
+        true
+    }
+
+    fn get_sample(&mut self) -> f32 {
+        self.current_event = None;
+        self.envelope = None;
+        if let Ok(event) = self.receiver.try_recv() {
+            self.current_event = Some(event);
+            match event {
+                NoteEvent::Press(note) => {
+                    // keys without a drum do not start the envelope
+                    if !self.start(note) {
+                        self.current_event = None;
+                    }
+                }
+                // drums are not held, the end of the hit lets the envelope go
+                NoteEvent::Up(_) | NoteEvent::Legato(_) => self.current_event = None,
+                NoteEvent::Hold
+                | NoteEvent::PitchBend(_)
+                | NoteEvent::Modulation(_)
+                | NoteEvent::Pressure(_)
+                | NoteEvent::Pedal(..)
+                | NoteEvent::Parameter(..)
+                | NoteEvent::Transport(_) => {}
+            }
+        }
+
+        let sample_rate = self.sample_rate as f32;
+        let noise = self.random.next_bipolar();
+        let hit = match self.hit.as_mut() {
+            Some(hit) => hit,
+            None => return 0.0,
+        };
+        if hit.age >= hit.length {
+            self.current_event = Some(NoteEvent::Up(hit.key));
+            self.hit = None;
+            return 0.0;
+        }
+
+        let time = hit.age as f32 / sample_rate;
+        let (tone, ring) = (hit.settings.tone, hit.settings.decay);
+        let decay = |seconds: f32| (-time / (seconds * ring)).exp();
+        let pitch = hit.pitch;
+        let phases = &mut hit.phases;
+        let mut advance = |index: usize, frequency: f32| {
+            phases[index] = (phases[index] + frequency / sample_rate).fract();
+            phases[index]
+        };
+        let sample = match hit.drum {
+            Drum::Kick => {
+                // the pitch falls from two octaves up in the first moments
+                let base = 50.0 * pitch;
+                let frequency = base * (1.0 + 3.0 * (-time / 0.03).exp());
+                let body = (2.0 * PI * advance(0, frequency)).sin() * decay(0.4);
+                let click = noise * (-time / 0.003).exp() * tone;
+                body + click
+            }
+            Drum::Snare => {
+                let body = ((2.0 * PI * advance(0, 185.0 * pitch)).sin()
+                    + 0.6 * (2.0 * PI * advance(1, 330.0 * pitch)).sin())
+                    * decay(0.08);
+                let noise = hit.high.process(&hit.high_pass, noise) * decay(0.15);
+                body * (1.0 - tone) + noise * 1.2 * tone
+            }
+            Drum::ClosedHat | Drum::OpenHat => {
+                let mut metal = 0.0;
+                for (index, frequency) in HAT_FREQUENCIES.iter().enumerate() {
+                    metal += if advance(index, frequency * pitch) < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    };
+                }
+                let source = metal / HAT_FREQUENCIES.len() as f32 * (1.0 - tone) + noise * tone;
+                let length = if hit.drum == Drum::OpenHat { 0.3 } else { 0.04 };
+                hit.high.process(&hit.high_pass, source) * decay(length)
+            }
+            Drum::Clap => {
+                // a few quick bursts, then the tail of the room
+                let spacing = CLAP_SPACING / pitch;
+                let burst = (0..CLAP_BURSTS)
+                    .map(|burst| time - burst as f32 * spacing)
+                    .filter(|&since| since >= 0.0)
+                    .map(|since| (-since / 0.005).exp())
+                    .fold(0.0, f32::max);
+                let tail_start = CLAP_BURSTS as f32 * spacing;
+                let tail = if time < tail_start {
+                    0.0
+                } else {
+                    0.5 * (-(time - tail_start) / (0.15 * ring)).exp()
+                };
+                let noise = hit.high.process(&hit.high_pass, noise);
+                hit.low.process(&hit.low_pass, noise) * 2.0 * burst.max(tail)
+            }
+        };
+        hit.age += 1;
+        sample
+    }
+}
+
+impl Source for DrumVoice {
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Iterator for DrumVoice {
+    type Item = MusicData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let wave_data = self.get_sample();
+        Some(MusicData {
+            current_event: self.current_event,
+            wave_data,
+            envelope: self.envelope,
+            pan: 0.0,
+            right: None,
+        })
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+
+use crate::effect::Effect;
+use crate::meter::Meter;
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+fn to_decibels(gain: f32) -> f32 {
+    20.0 * gain.max(1e-6).log10()
+}
+
+fn from_decibels(decibels: f32) -> f32 {
+    10.0_f32.powf(decibels / 20.0)
+}
+
+/// One pole smoothing coefficient reaching ~63% of a step in `seconds`.
+fn time_constant(seconds: f32, sample_rate: f32) -> f32 {
+    1.0 - (-1.0 / (seconds * sample_rate).max(1.0)).exp()
+}
+
+#[derive(Copy, Clone)]
+pub struct CompressorSettings {
+    /// Level in dBFS above which the gain is reduced.
+    pub threshold: f32,
+    /// Decibels in for every decibel out above the threshold.
+    pub ratio: f32,
+    /// Seconds to react to a rising level.
+    pub attack: f32,
+    /// Seconds to recover once the level drops.
+    pub release: f32,
+    /// Width in dB of the soft knee around the threshold.
+    pub knee: f32,
+    /// Gain in dB added after compressing.
+    pub makeup: f32,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        CompressorSettings {
+            threshold: -12.0,
+            ratio: 4.0,
+            attack: 0.01,
+            release: 0.1,
+            knee: 6.0,
+            makeup: 0.0,
+        }
+    }
+}
+
+/// A feed forward compressor acting on both channels together, so the
+/// stereo image does not move.
+pub struct Compressor {
+    settings: CompressorSettings,
+    attack: f32,
+    release: f32,
+    /// Smoothed gain reduction in dB, positive.
+    reduction: f32,
+    meter: Meter,
+}
+
+impl Compressor {
+    /// `meter` is kept up to date with the gain reduction in dB.
+    pub fn new(sample_rate: u32, settings: CompressorSettings, meter: Meter) -> Compressor {
+        let sample_rate = sample_rate as f32;
+        Compressor {
+            settings,
+            attack: time_constant(settings.attack, sample_rate),
+            release: time_constant(settings.release, sample_rate),
+            reduction: 0.0,
+            meter,
+        }
+    }
+
+    /// The level in dB that `level` comes out at, before makeup gain.
+    fn output_level(&self, level: f32) -> f32 {
+        let CompressorSettings {
+            threshold,
+            ratio,
+            knee,
+            ..
+        } = self.settings;
+        let slope = 1.0 / ratio.max(1.0) - 1.0;
+        let over = level - threshold;
+        // a knee of 0 is a hard knee, the soft knee would divide by it
+        if 2.0 * over < -knee || (knee <= 0.0 && over <= 0.0) {
+            level
+        } else if knee > 0.0 && 2.0 * over.abs() <= knee {
+            level + slope * (over + knee / 2.0).powi(2) / (2.0 * knee)
+        } else {
+            threshold + over / ratio.max(1.0)
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let level = to_decibels(left.abs().max(right.abs()));
+        let target = level - self.output_level(level);
+        let coefficient = if target > self.reduction {
+            self.attack
+        } else {
+            self.release
+        };
+        self.reduction += (target - self.reduction) * coefficient;
+        self.meter.set(self.reduction);
+
+        let gain = from_decibels(self.settings.makeup - self.reduction);
+        (left * gain, right * gain)
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        if let NoteEvent::Parameter(Parameter::CompressorThreshold, threshold) = event {
+            self.settings.threshold = threshold;
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct LimiterSettings {
+    /// Highest level let through, in dBFS.
+    pub ceiling: f32,
+    /// Seconds the limiter looks ahead, which is also how long it takes
+    /// to pull the gain down and how much it delays the sound.
+    pub lookahead: f32,
+    /// Seconds to recover once the peak has passed.
+    pub release: f32,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        LimiterSettings {
+            ceiling: -0.3,
+            lookahead: 0.005,
+            release: 0.05,
+        }
+    }
+}
+
+/// A look-ahead brickwall limiter, the last stage before the device.
+///
+/// The sound is delayed by the look-ahead window. The gain needed for every
+/// incoming sample is held at its minimum over the window and then averaged
+/// over the window again, which ramps the gain down smoothly and has it fully
+/// down by the time the peak leaves the delay.
+pub struct Limiter {
+    ceiling: f32,
+    window: usize,
+    release: f32,
+    delay: VecDeque<(f32, f32)>,
+    /// Indices and gains of the running minimum, increasing gains only.
+    minimum: VecDeque<(u64, f32)>,
+    held: VecDeque<f32>,
+    held_sum: f64,
+    gain: f32,
+    count: u64,
+    meter: Meter,
+}
+
+impl Limiter {
+    /// `meter` is kept up to date with the gain reduction in dB.
+    pub fn new(sample_rate: u32, settings: LimiterSettings, meter: Meter) -> Limiter {
+        let sample_rate = sample_rate as f32;
+        let window = ((settings.lookahead * sample_rate) as usize).max(1);
+        let mut held = VecDeque::with_capacity(window + 1);
+        held.extend(std::iter::repeat_n(1.0, window));
+        Limiter {
+            ceiling: from_decibels(settings.ceiling.min(0.0)),
+            window,
+            release: time_constant(settings.release, sample_rate),
+            delay: VecDeque::with_capacity(window),
+            minimum: VecDeque::with_capacity(window + 1),
+            held,
+            held_sum: window as f64,
+            gain: 1.0,
+            count: 0,
+            meter,
+        }
+    }
+}
+
+impl Effect for Limiter {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let peak = left.abs().max(right.abs());
+        let required = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        // running minimum of the required gain over the window
+        while matches!(self.minimum.back(), Some(&(_, gain)) if gain >= required) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.count, required));
+        let window = self.window as u64;
+        while matches!(self.minimum.front(), Some(&(index, _)) if index + window <= self.count) {
+            self.minimum.pop_front();
+        }
+        self.count += 1;
+        let held = self.minimum.front().map(|&(_, gain)| gain).unwrap_or(1.0);
+
+        // average of the held minimum over the window
+        self.held.push_back(held);
+        self.held_sum += held as f64;
+        if self.held.len() > self.window {
+            self.held_sum -= self.held.pop_front().unwrap_or(1.0) as f64;
+        }
+        let smoothed = (self.held_sum / self.window as f64) as f32;
+
+        self.gain = if smoothed < self.gain {
+            smoothed
+        } else {
+            self.gain + (smoothed - self.gain) * self.release
+        };
+        self.meter.set(-to_decibels(self.gain));
+
+        self.delay.push_back((left, right));
+        let (left, right) = if self.delay.len() >= self.window {
+            self.delay.pop_front().unwrap_or((0.0, 0.0))
+        } else {
+            (0.0, 0.0)
+        };
+        // guards against rounding in the running sum
+        let ceiling = self.ceiling;
+        (
+            (left * self.gain).clamp(-ceiling, ceiling),
+            (right * self.gain).clamp(-ceiling, ceiling),
+        )
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        if let NoteEvent::Parameter(Parameter::Ceiling, ceiling) = event {
+            self.ceiling = from_decibels(ceiling.min(0.0));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::music_data::MusicData;
+use crate::musical_keyboard::NoteEvent;
+
+/// Audio processing of an effect, one stereo frame at a time. An
+/// `EffectStage` takes care of fitting it into a stream.
+pub trait Effect {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32);
+
+    /// Processes a mono voice. Effects that treat the channels separately
+    /// can skip the work on the right channel.
+    fn process_mono(&mut self, input: f32) -> f32 {
+        self.process(input, input).0
+    }
+
+    /// Called with every event passing through, for picking out parameter changes.
+    fn handle_event(&mut self, _event: NoteEvent) {}
+}
+
+/// Runs an `Effect` on the stream of its upstream source.
+///
+/// After the voices are mixed the stream is interleaved stereo. A single
+/// voice is one MusicData per frame, mono voices go through
+/// `Effect::process_mono`.
+pub struct EffectStage<I, E>
+where
+    I: Iterator<Item = MusicData> + Source,
+    E: Effect,
+{
+    upstream_source: I,
+    effect: E,
+    right: Option<MusicData>,
+}
+
+impl<T, E> EffectStage<T, E>
+where
+    T: Iterator<Item = MusicData> + Source,
+    E: Effect,
+{
+    pub fn new(upstream: T, effect: E) -> EffectStage<T, E> {
+        EffectStage {
+            upstream_source: upstream,
+            effect,
+            right: None,
+        }
+    }
+
+    fn next_voice_frame(&mut self) -> Option<MusicData> {
+        let mut music_data = self.upstream_source.next()?;
+        if let Some(event) = music_data.current_event {
+            self.effect.handle_event(event);
+        }
+        match music_data.right {
+            Some(right) => {
+                let (left, right) = self.effect.process(music_data.wave_data, right);
+                music_data.wave_data = left;
+                music_data.right = Some(right);
+            }
+            None => music_data.wave_data = self.effect.process_mono(music_data.wave_data),
+        }
+        Some(music_data)
+    }
+
+    fn next_interleaved_frame(&mut self) -> Option<MusicData> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+
+        let mut left = self.upstream_source.next()?;
+        let mut right = self.upstream_source.next()?;
+        for event in [left.current_event, right.current_event].iter().flatten() {
+            self.effect.handle_event(*event);
+        }
+
+        let (left_out, right_out) = self.effect.process(left.wave_data, right.wave_data);
+        left.wave_data = left_out;
+        right.wave_data = right_out;
+        self.right = Some(right);
+        Some(left)
+    }
+}
+
+impl<T, E> Iterator for EffectStage<T, E>
+where
+    T: Iterator<Item = MusicData> + Source,
+    E: Effect,
+{
+    type Item = MusicData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.upstream_source.channels() == 2 {
+            self.next_interleaved_frame()
+        } else {
+            self.next_voice_frame()
+        }
+    }
+}
+
+impl<T, E> Source for EffectStage<T, E>
+where
+    T: Iterator<Item = MusicData> + Source,
+    E: Effect,
+{
+    fn channels(&self) -> u16 {
+        self.upstream_source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.upstream_source.sample_rate()
+    }
+
+    fn current_frame_len(&self) -> Option<usize> {
+        self.upstream_source.current_frame_len()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.upstream_source.total_duration()
+    }
+}
+
+/// An effect that is switched off passes the sound through untouched.
+impl<E: Effect> Effect for Option<E> {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        match self {
+            Some(effect) => effect.process(left, right),
+            None => (left, right),
+        }
+    }
+
+    fn process_mono(&mut self, input: f32) -> f32 {
+        match self {
+            Some(effect) => effect.process_mono(input),
+            None => input,
+        }
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        if let Some(effect) = self {
+            effect.handle_event(event);
+        }
+    }
+}
@@ -3,21 +3,64 @@ use std::time::Duration;
 use rodio::Source;
 
 use crate::music_data::MusicData;
+use crate::parameter::Parameter;
 
 pub enum EnvelopeState {
     Flat,
     Attack,
+    Decay,
     Hold,
     Release,
 }
+
+/// Stage times of an envelope, in seconds, and the sustain level (0.0 - 1.0).
+///
+/// Times are how long a full 0.0 - 1.0 sweep takes, so a release started
+/// from half amplitude takes half the release time.
+#[derive(Copy, Clone)]
+pub struct EnvelopeSettings {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    /// How much the velocity scales the level, 0.0 (not at all) to 1.0
+    /// (silent at velocity 0).
+    pub velocity_track: f32,
+    /// Seconds added to the attack at full velocity, negative to make hard
+    /// notes start faster.
+    pub velocity_attack: f32,
+}
+
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        EnvelopeSettings {
+            attack: 1.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 1.0,
+            velocity_track: 1.0,
+            velocity_attack: 0.0,
+        }
+    }
+}
+
 pub struct Envelope<I>
 where
     I: Iterator<Item = MusicData> + Source,
 {
     upstream_source: I,
+    /// The settings of the voice, which the parameters change.
+    settings: EnvelopeSettings,
     current_multiplier: f32,
     state: EnvelopeState,
+    sustain_level: f32,
+    attack: f32,
+    velocity_track: f32,
+    velocity_attack: f32,
+    /// Level of the current note from its velocity.
+    velocity_gain: f32,
     attack_rate: f32,
+    decay_rate: f32,
     release_rate: f32,
 }
 
@@ -25,17 +68,71 @@ impl<T> Envelope<T>
 where
     T: Iterator<Item = MusicData> + Source,
 {
-    pub(crate) fn new<I>(upstream: T) -> Envelope<T>
-    where
-        I: Iterator,
-    {
-        Envelope {
+    pub(crate) fn new(upstream: T) -> Envelope<T> {
+        Self::with_settings(upstream, EnvelopeSettings::default())
+    }
+
+    pub(crate) fn with_settings(upstream: T, settings: EnvelopeSettings) -> Envelope<T> {
+        let mut envelope = Envelope {
             upstream_source: upstream,
+            settings,
             current_multiplier: 0.0,
             state: EnvelopeState::Flat,
-            attack_rate: (1.0 / 44100.0) * 1.0, // value per sample added to multiplier. 0.2s attack
-            release_rate: -(1.0 / 44100.0) * 1.0, // value per sample added to multiplier. TODO: dynamic with sample rate. 1s release
+            sustain_level: 1.0,
+            attack: 0.0,
+            velocity_track: 0.0,
+            velocity_attack: 0.0,
+            velocity_gain: 1.0,
+            attack_rate: 0.0,
+            decay_rate: 0.0,
+            release_rate: 0.0,
+        };
+        envelope.apply_settings(settings);
+        envelope
+    }
+
+    /// Whether the envelope is still producing sound, i.e. the voice is in use.
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, EnvelopeState::Flat)
+    }
+
+    fn apply_settings(&mut self, settings: EnvelopeSettings) {
+        let sample_rate = self.upstream_source.sample_rate() as f32;
+        // value per sample added to multiplier. A zero time jumps in a single sample.
+        let rate = |seconds: f32| 1.0 / (seconds * sample_rate).max(1.0);
+
+        self.sustain_level = settings.sustain.clamp(0.0, 1.0);
+        self.attack = settings.attack;
+        self.velocity_track = settings.velocity_track.clamp(0.0, 1.0);
+        self.velocity_attack = settings.velocity_attack;
+        self.attack_rate = rate(settings.attack);
+        self.decay_rate = -rate(settings.decay);
+        self.release_rate = -rate(settings.release);
+    }
+
+    fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+        let settings = &mut self.settings;
+        match parameter {
+            Parameter::Attack => settings.attack = value.max(0.0),
+            Parameter::Decay => settings.decay = value.max(0.0),
+            Parameter::Sustain => settings.sustain = value,
+            Parameter::Release => settings.release = value.max(0.0),
+            Parameter::VelocityToLevel => settings.velocity_track = value,
+            Parameter::VelocityToAttack => settings.velocity_attack = value,
+            _ => return,
         }
+        let settings = self.settings;
+        self.apply_settings(settings);
+    }
+
+    /// Sets the level and attack time of a new note from its velocity.
+    fn apply_velocity(&mut self, velocity: u8) {
+        let velocity = velocity.min(127) as f32 / 127.0;
+        self.velocity_gain = 1.0 - self.velocity_track * (1.0 - velocity);
+
+        let sample_rate = self.upstream_source.sample_rate() as f32;
+        let attack = (self.attack + self.velocity_attack * velocity).max(0.0);
+        self.attack_rate = 1.0 / (attack * sample_rate).max(1.0);
     }
 }
 
@@ -45,23 +142,37 @@ where
 {
     type Item = MusicData;
 
+    #[allow(clippy::needless_return)]
     fn next(&mut self) -> Option<Self::Item> {
         let value = self.upstream_source.next();
 
         if let Some(music_data) = value {
-            let mut result = MusicData {
-                current_event: music_data.current_event,
-                wave_data: music_data.wave_data,
-            };
+            let mut result = music_data;
 
             if let Some(event) = music_data.current_event {
                 match event {
-                    crate::musical_keyboard::NoteEvent::Press(_) => {
+                    crate::musical_keyboard::NoteEvent::Press(note) => {
+                        if let Some(settings) = music_data.envelope {
+                            self.apply_settings(settings);
+                        }
+                        self.apply_velocity(note.velocity);
                         self.current_multiplier = 0.0;
                         self.state = EnvelopeState::Attack
                     }
                     crate::musical_keyboard::NoteEvent::Hold => (),
-                    crate::musical_keyboard::NoteEvent::Up => self.state = EnvelopeState::Release,
+                    // legato notes carry on with the envelope as it is
+                    crate::musical_keyboard::NoteEvent::Legato(_) => (),
+                    crate::musical_keyboard::NoteEvent::PitchBend(_) => (),
+                    crate::musical_keyboard::NoteEvent::Modulation(_) => (),
+                    crate::musical_keyboard::NoteEvent::Pressure(_) => (),
+                    crate::musical_keyboard::NoteEvent::Pedal(..) => (),
+                    crate::musical_keyboard::NoteEvent::Parameter(parameter, value) => {
+                        self.set_parameter(parameter, value)
+                    }
+                    crate::musical_keyboard::NoteEvent::Transport(_) => (),
+                    crate::musical_keyboard::NoteEvent::Up(_) => {
+                        self.state = EnvelopeState::Release
+                    }
                 }
             }
 
@@ -80,13 +191,22 @@ where
                     if self.current_multiplier >= 1.0 {
                         // print!("Attack: {}\r\n", self.current_multiplier);
                         self.current_multiplier = 1.0;
+                        self.state = EnvelopeState::Decay
+                    }
+                    result.wave_data *= self.current_multiplier;
+                }
+                EnvelopeState::Decay => {
+                    self.current_multiplier += self.decay_rate;
+                    if self.current_multiplier <= self.sustain_level {
+                        self.current_multiplier = self.sustain_level;
                         self.state = EnvelopeState::Hold
                     }
-                    result.wave_data = result.wave_data * self.current_multiplier;
+                    result.wave_data *= self.current_multiplier;
                 }
                 EnvelopeState::Hold => {
                     // print!("Hold\r\n");
-                    // leave the amplitude unchanged.
+                    // stay at the sustain level.
+                    result.wave_data *= self.current_multiplier;
                 }
                 EnvelopeState::Release => {
                     // print!("Release\r\n");
@@ -95,9 +215,14 @@ where
                         self.current_multiplier = 0.0;
                         self.state = EnvelopeState::Flat
                     }
-                    result.wave_data = result.wave_data * self.current_multiplier;
+                    result.wave_data *= self.current_multiplier;
                 }
             }
+            result.wave_data *= self.velocity_gain;
+            // stereo voices get the same gain on their right channel
+            result.right = music_data
+                .right
+                .map(|right| right * self.current_multiplier * self.velocity_gain);
             return Some(result);
         } else {
             return value;
@@ -0,0 +1,131 @@
+use crate::biquad::{Biquad, BiquadKind, Coefficients, SmoothedCoefficients};
+use crate::effect::Effect;
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+#[derive(Copy, Clone)]
+pub struct EqBand {
+    pub kind: BiquadKind,
+    /// Centre, corner or shelf frequency in Hz.
+    pub frequency: f32,
+    /// Width of a peak, resonance of a cut or slope of a shelf.
+    pub q: f32,
+    /// Boost or cut in dB, unused by the high and low pass.
+    pub gain: f32,
+}
+
+impl EqBand {
+    /// Parses `kind:frequency[:gain[:q]]`, e.g. `peak:2500:-3:1.4` or
+    /// `highpass:40`.
+    pub fn from_spec(spec: &str) -> Option<EqBand> {
+        let mut parts = spec.split(':');
+        let kind = BiquadKind::from_name(parts.next()?)?;
+        let frequency = parts.next()?.parse().ok()?;
+        let gain = match parts.next() {
+            Some(gain) => gain.parse().ok()?,
+            None => 0.0,
+        };
+        let q = match parts.next() {
+            Some(q) => q.parse().ok()?,
+            None => std::f32::consts::FRAC_1_SQRT_2,
+        };
+        Some(EqBand {
+            kind,
+            frequency,
+            q,
+            gain,
+        })
+    }
+
+    /// The band written the way `from_spec` reads it.
+    pub fn spec(&self) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            self.kind.name(),
+            self.frequency,
+            self.gain,
+            self.q
+        )
+    }
+
+    fn coefficients(&self, sample_rate: u32) -> Coefficients {
+        Coefficients::new(self.kind, sample_rate, self.frequency, self.q, self.gain)
+    }
+}
+
+struct Band {
+    settings: EqBand,
+    coefficients: SmoothedCoefficients,
+    left: Biquad,
+    right: Biquad,
+}
+
+/// A parametric equalizer, any number of bands in series.
+pub struct Equalizer {
+    sample_rate: u32,
+    bands: Vec<Band>,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: u32, bands: &[EqBand]) -> Equalizer {
+        Equalizer {
+            sample_rate,
+            bands: bands
+                .iter()
+                .map(|band| Band {
+                    settings: *band,
+                    coefficients: SmoothedCoefficients::new(
+                        band.coefficients(sample_rate),
+                        sample_rate,
+                    ),
+                    left: Biquad::default(),
+                    right: Biquad::default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Changes a band, which then glides to its new response.
+    fn set_band<F>(&mut self, index: u8, change: F)
+    where
+        F: FnOnce(&mut EqBand),
+    {
+        if let Some(band) = self.bands.get_mut(index as usize) {
+            change(&mut band.settings);
+            band.coefficients
+                .set_target(band.settings.coefficients(self.sample_rate));
+        }
+    }
+}
+
+impl Effect for Equalizer {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let (mut left, mut right) = (left, right);
+        for band in self.bands.iter_mut() {
+            let coefficients = band.coefficients.next();
+            left = band.left.process(coefficients, left);
+            right = band.right.process(coefficients, right);
+        }
+        (left, right)
+    }
+
+    fn process_mono(&mut self, input: f32) -> f32 {
+        let mut value = input;
+        for band in self.bands.iter_mut() {
+            value = band.left.process(band.coefficients.next(), value);
+        }
+        value
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::Parameter(Parameter::EqGain(band), gain) => {
+                self.set_band(band, |band| band.gain = gain)
+            }
+            NoteEvent::Parameter(Parameter::EqFrequency(band), frequency) => {
+                self.set_band(band, |band| band.frequency = frequency)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::biquad::{Biquad, BiquadKind, Coefficients, SmoothedCoefficients};
+use crate::music_data::MusicData;
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+#[derive(Copy, Clone)]
+pub struct FilterSettings {
+    /// Cutoff frequency in Hz for notes played at full velocity.
+    pub cutoff: f32,
+    /// 0.0 (none) to 1.0 (just below self oscillation).
+    pub resonance: f32,
+    /// How many octaves the cutoff drops for the softest notes.
+    pub velocity: f32,
+    /// How many octaves full aftertouch opens the filter.
+    pub pressure: f32,
+}
+
+impl Default for FilterSettings {
+    fn default() -> Self {
+        FilterSettings {
+            cutoff: 20000.0,
+            resonance: 0.0,
+            velocity: 0.0,
+            pressure: 2.0,
+        }
+    }
+}
+
+/// Resonance 0.0 is a Butterworth response, 1.0 a sharp peak at the cutoff.
+fn resonance_to_q(resonance: f32) -> f32 {
+    std::f32::consts::FRAC_1_SQRT_2 / (1.0 - 0.95 * resonance.clamp(0.0, 1.0))
+}
+
+/// A resonant low pass filter for a single voice, sitting between the
+/// oscillator and the envelope. The cutoff is set again on every note from
+/// its velocity, and aftertouch raises it. `Parameter::Cutoff`,
+/// `Parameter::Resonance` and the velocity and pressure routings change the
+/// settings while playing.
+pub struct Filter<I>
+where
+    I: Iterator<Item = MusicData> + Source,
+{
+    upstream_source: I,
+    settings: FilterSettings,
+    /// How many octaves below the set cutoff the current note's velocity puts it.
+    velocity_octaves: f32,
+    pressure: f32,
+    coefficients: SmoothedCoefficients,
+    left: Biquad,
+    right: Biquad,
+}
+
+impl<T> Filter<T>
+where
+    T: Iterator<Item = MusicData> + Source,
+{
+    pub fn new(upstream: T, settings: FilterSettings) -> Filter<T> {
+        let sample_rate = upstream.sample_rate();
+        let coefficients = Self::low_pass(sample_rate, settings, settings.cutoff);
+        Filter {
+            upstream_source: upstream,
+            settings,
+            velocity_octaves: 0.0,
+            pressure: 0.0,
+            coefficients: SmoothedCoefficients::new(coefficients, sample_rate),
+            left: Biquad::default(),
+            right: Biquad::default(),
+        }
+    }
+
+    fn low_pass(sample_rate: u32, settings: FilterSettings, cutoff: f32) -> Coefficients {
+        let q = resonance_to_q(settings.resonance);
+        Coefficients::new(BiquadKind::LowPass, sample_rate, cutoff, q, 0.0)
+    }
+
+    /// The coefficients for the cutoff after velocity and pressure.
+    fn current_low_pass(&self) -> Coefficients {
+        let octaves = self.pressure * self.settings.pressure - self.velocity_octaves;
+        let cutoff = self.settings.cutoff * 2.0_f32.powf(octaves);
+        let sample_rate = self.upstream_source.sample_rate();
+        Self::low_pass(sample_rate, self.settings, cutoff)
+    }
+
+    fn update_cutoff(&mut self) {
+        let coefficients = self.current_low_pass();
+        self.coefficients.set_target(coefficients);
+    }
+}
+
+impl<T> Iterator for Filter<T>
+where
+    T: Iterator<Item = MusicData> + Source,
+{
+    type Item = MusicData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut music_data = self.upstream_source.next()?;
+
+        match music_data.current_event {
+            Some(NoteEvent::Press(note)) => {
+                let velocity = note.velocity.min(127) as f32 / 127.0;
+                self.velocity_octaves = self.settings.velocity * (1.0 - velocity);
+                // a new note starts at its own cutoff rather than sweeping to it
+                let coefficients = self.current_low_pass();
+                self.coefficients.set(coefficients);
+            }
+            Some(NoteEvent::Pressure(pressure)) => {
+                self.pressure = pressure.clamp(0.0, 1.0);
+                self.update_cutoff();
+            }
+            Some(NoteEvent::Parameter(Parameter::Cutoff, cutoff)) => {
+                self.settings.cutoff = cutoff;
+                self.update_cutoff();
+            }
+            Some(NoteEvent::Parameter(Parameter::Resonance, resonance)) => {
+                self.settings.resonance = resonance;
+                self.update_cutoff();
+            }
+            // taken up from the next note
+            Some(NoteEvent::Parameter(Parameter::VelocityToCutoff, octaves)) => {
+                self.settings.velocity = octaves
+            }
+            Some(NoteEvent::Parameter(Parameter::PressureToCutoff, octaves)) => {
+                self.settings.pressure = octaves;
+                self.update_cutoff();
+            }
+            _ => {}
+        }
+
+        let coefficients = self.coefficients.next();
+        music_data.wave_data = self.left.process(coefficients, music_data.wave_data);
+        if let Some(right) = music_data.right {
+            music_data.right = Some(self.right.process(coefficients, right));
+        }
+        Some(music_data)
+    }
+}
+
+impl<T> Source for Filter<T>
+where
+    T: Iterator<Item = MusicData> + Source,
+{
+    fn channels(&self) -> u16 {
+        self.upstream_source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.upstream_source.sample_rate()
+    }
+
+    fn current_frame_len(&self) -> Option<usize> {
+        self.upstream_source.current_frame_len()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.upstream_source.total_duration()
+    }
+}
@@ -0,0 +1,336 @@
+use rodio::Source;
+use std::{
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+use crate::music_data::MusicData;
+use crate::musical_keyboard::{frequency_from_key, NoteEvent};
+use crate::pitch::{GlideSettings, Pitch};
+use crate::random::Random;
+use crate::sampler::SampleData;
+use crate::wave_table_oscilator::WavetableOscillator;
+
+const MAX_GRAINS: usize = 64;
+
+/// The buffer grains are read from.
+#[derive(Clone)]
+pub struct GrainSource {
+    data: Arc<SampleData>,
+    /// Frequency heard when the buffer plays at its own sample rate.
+    root_frequency: f32,
+}
+
+impl GrainSource {
+    /// A loaded sample that sounds at the pitch of `root_key` unshifted.
+    pub fn from_sample(data: Arc<SampleData>, root_key: u8) -> GrainSource {
+        GrainSource {
+            data,
+            root_frequency: frequency_from_key(root_key),
+        }
+    }
+
+    /// The single cycle table of a wavetable oscillator.
+    pub fn from_wave_table(oscillator: &WavetableOscillator) -> GrainSource {
+        let frames = oscillator.wave_table().to_vec();
+        // one cycle per second when played at "sample rate" = table length
+        let sample_rate = frames.len().max(1) as u32;
+        GrainSource {
+            data: Arc::new(SampleData {
+                frames,
+                sample_rate,
+            }),
+            root_frequency: 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum GrainWindow {
+    Hann,
+    Triangle,
+    Gaussian,
+    Rectangle,
+}
+
+impl GrainWindow {
+    pub fn from_name(name: &str) -> Option<GrainWindow> {
+        match name {
+            "hann" => Some(GrainWindow::Hann),
+            "triangle" => Some(GrainWindow::Triangle),
+            "gaussian" => Some(GrainWindow::Gaussian),
+            "rectangle" => Some(GrainWindow::Rectangle),
+            _ => None,
+        }
+    }
+
+    /// Window gain at `t`, the position within the grain from 0.0 to 1.0.
+    fn gain(&self, t: f32) -> f32 {
+        match self {
+            GrainWindow::Hann => 0.5 - 0.5 * (2.0 * std::f32::consts::PI * t).cos(),
+            GrainWindow::Triangle => 1.0 - (2.0 * t - 1.0).abs(),
+            GrainWindow::Gaussian => (-0.5 * ((t - 0.5) / 0.15).powi(2)).exp(),
+            GrainWindow::Rectangle => 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct GranularSettings {
+    /// Length of a grain in seconds.
+    pub grain_size: f32,
+    /// Grains started per second.
+    pub density: f32,
+    /// Where in the source grains start, 0.0 (start) to 1.0 (end).
+    pub position: f32,
+    /// Random offset of the start position, as a fraction of the source length.
+    pub position_jitter: f32,
+    /// Random detuning of each grain, in semitones.
+    pub pitch_jitter: f32,
+    pub window: GrainWindow,
+    /// How far grains are randomly panned apart, 0.0 (mono) to 1.0 (full width).
+    pub stereo_spread: f32,
+}
+
+impl Default for GranularSettings {
+    fn default() -> Self {
+        GranularSettings {
+            grain_size: 0.08,
+            density: 40.0,
+            position: 0.0,
+            position_jitter: 0.1,
+            pitch_jitter: 0.1,
+            window: GrainWindow::Hann,
+            stereo_spread: 0.5,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Grain {
+    active: bool,
+    position: f64,
+    increment: f64,
+    age: usize,
+    length: usize,
+    left_gain: f32,
+    right_gain: f32,
+}
+
+/// A stereo voice built from many short windowed grains of a source buffer,
+/// for textures and pads.
+pub struct GranularOscillator {
+    sample_rate: u32,
+    receiver: Receiver<NoteEvent>,
+    source: GrainSource,
+    settings: GranularSettings,
+    random: Random,
+    grains: [Grain; MAX_GRAINS],
+    current_frequency: f32,
+    pitch: Pitch,
+    samples_until_grain: f32,
+    current_event: Option<NoteEvent>,
+}
+
+impl GranularOscillator {
+    /// `seed` makes the scattering of the grains reproducible.
+    pub fn new(
+        sample_rate: u32,
+        source: GrainSource,
+        settings: GranularSettings,
+        seed: u64,
+        receiver: Receiver<NoteEvent>,
+    ) -> GranularOscillator {
+        GranularOscillator {
+            sample_rate,
+            receiver,
+            source,
+            settings,
+            random: Random::new(seed),
+            grains: [Grain {
+                active: false,
+                position: 0.0,
+                increment: 0.0,
+                age: 0,
+                length: 0,
+                left_gain: 0.0,
+                right_gain: 0.0,
+            }; MAX_GRAINS],
+            current_frequency: 0.0,
+            pitch: Pitch::new(sample_rate, GlideSettings::default()),
+            samples_until_grain: 0.0,
+            current_event: None,
+        }
+    }
+
+    pub fn set_glide(&mut self, glide: GlideSettings) {
+        self.pitch.set_glide(glide);
+    }
+
+    fn spawn_grain(&mut self) {
+        let slot = match self.grains.iter().position(|grain| !grain.active) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let frame_count = self.source.data.frames.len() as f64;
+        let settings = self.settings;
+
+        let start = settings.position + settings.position_jitter * self.random.next_bipolar();
+        let detune = settings.pitch_jitter * self.random.next_bipolar();
+        let pan = (settings.stereo_spread * self.random.next_bipolar()).clamp(-1.0, 1.0);
+
+        let increment = (self.current_frequency / self.source.root_frequency) as f64
+            * 2.0_f64.powf(detune as f64 / 12.0)
+            * self.source.data.sample_rate as f64
+            / self.sample_rate as f64;
+
+        self.grains[slot] = Grain {
+            active: true,
+            position: (start as f64 * frame_count).rem_euclid(frame_count),
+            increment,
+            age: 0,
+            length: ((settings.grain_size * self.sample_rate as f32) as usize).max(1),
+            left_gain: (1.0 - pan).min(1.0),
+            right_gain: (1.0 + pan).min(1.0),
+        };
+    }
+
+    fn get_sample(&mut self) -> (f32, f32) {
+        self.current_event = None;
+        if let Ok(event) = self.receiver.try_recv() {
+            match event {
+                NoteEvent::Press(note) | NoteEvent::Legato(note) => {
+                    self.pitch.set_frequency(note.frequency)
+                }
+                NoteEvent::PitchBend(semitones) => self.pitch.set_bend(semitones),
+                NoteEvent::Modulation(amount) => self.pitch.set_modulation(amount),
+                NoteEvent::Parameter(parameter, value) => {
+                    self.pitch.set_parameter(parameter, value)
+                }
+                NoteEvent::Hold
+                | NoteEvent::Up(_)
+                | NoteEvent::Pressure(_)
+                | NoteEvent::Pedal(..)
+                | NoteEvent::Transport(_) => {}
+            }
+            self.current_event = Some(event);
+        }
+        // grains keep the pitch they started with, so slides are heard as new grains start
+        self.current_frequency = self.pitch.next_frequency();
+
+        let frames = &self.source.data.frames;
+        if frames.is_empty() || self.current_frequency <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        self.samples_until_grain -= 1.0;
+        if self.samples_until_grain <= 0.0 {
+            self.samples_until_grain += self.sample_rate as f32 / self.settings.density.max(0.1);
+            self.spawn_grain();
+        }
+
+        let frames = &self.source.data.frames;
+        let frame_count = frames.len();
+        let window = self.settings.window;
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for grain in self.grains.iter_mut().filter(|grain| grain.active) {
+            // the source wraps around, which is what single cycle wavetables need
+            let index = grain.position as usize % frame_count;
+            let fraction = (grain.position - grain.position.floor()) as f32;
+            let value =
+                frames[index] * (1.0 - fraction) + frames[(index + 1) % frame_count] * fraction;
+            let value = value * window.gain(grain.age as f32 / grain.length as f32);
+
+            left += value * grain.left_gain;
+            right += value * grain.right_gain;
+
+            grain.position = (grain.position + grain.increment) % frame_count as f64;
+            grain.age += 1;
+            grain.active = grain.age < grain.length;
+        }
+
+        // keep the level steady however many grains overlap
+        let overlap = (self.settings.density * self.settings.grain_size).max(1.0);
+        let gain = 1.0 / overlap.sqrt();
+        (left * gain, right * gain)
+    }
+}
+
+impl Source for GranularOscillator {
+    // both channels travel in one MusicData, see `MusicData::right`
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Iterator for GranularOscillator {
+    type Item = MusicData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (left, right) = self.get_sample();
+        Some(MusicData {
+            current_event: self.current_event,
+            wave_data: left,
+            envelope: None,
+            pan: 0.0,
+            right: Some(right),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musical_keyboard::Note;
+    use std::sync::mpsc;
+
+    /// The first samples of a note played on an oscillator seeded with
+    /// `seed`, reading a ramp.
+    fn play(seed: u64) -> Vec<(f32, f32)> {
+        let source = GrainSource::from_sample(
+            Arc::new(SampleData {
+                frames: (0..1000).map(|i| (i as f32 / 500.0) - 1.0).collect(),
+                sample_rate: 44100,
+            }),
+            60,
+        );
+        let (sender, receiver) = mpsc::channel();
+        let mut oscillator =
+            GranularOscillator::new(44100, source, GranularSettings::default(), seed, receiver);
+        sender
+            .send(NoteEvent::Press(Note {
+                key: 64,
+                frequency: frequency_from_key(64),
+                velocity: 100,
+            }))
+            .unwrap();
+        oscillator
+            .by_ref()
+            .take(10000)
+            .map(|data| (data.wave_data, data.right.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_seed_plays_the_same() {
+        let first = play(7);
+        assert!(first
+            .iter()
+            .any(|&(left, right)| left != 0.0 && right != left));
+        assert!(first == play(7));
+        assert!(first != play(8));
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crossterm::event::KeyCode;
+use serde::Deserialize;
+
+/// The rows of a US keyboard, number row first. Layouts are written for
+/// these keys and moved to the same physical keys on other keyboards.
+const QWERTY_ROWS: [&str; 4] = ["1234567890-=", "qwertyuiop[]", "asdfghjkl;'", "zxcvbnm,./"];
+const AZERTY_ROWS: [&str; 4] = ["&é\"'(-è_çà)=", "azertyuiop^$", "qsdfghjklmù", "wxcvbn,;:!"];
+const QWERTZ_ROWS: [&str; 4] = ["1234567890ß´", "qwertzuiopü+", "asdfghjklöä", "yxcvbnm,.-"];
+
+/// One octave on the home row with the sharps above it.
+const PIANO: &[(&str, i32)] = &[("awsedftgyhujk", 0)];
+/// As in the trackers, the two bottom rows from C and the two top rows an
+/// octave higher, with the sharps on the upper row of each pair.
+const TRACKER: &[(&str, i32)] = &[("zsxdcvgbhnjm,l.;/", 0), ("q2w3er5t6y7ui9o0p[=]", 12)];
+
+/// Key `c` of a US keyboard as the same physical key on a keyboard with `rows`.
+fn translate(c: char, rows: &[&str; 4]) -> char {
+    for (qwerty, other) in QWERTY_ROWS.iter().zip(rows.iter()) {
+        if let Some(position) = qwerty.chars().position(|q| q == c) {
+            return other.chars().nth(position).unwrap_or(c);
+        }
+    }
+    c
+}
+
+/// Reads a key name: a single character, `space`, `tab`, `escape`, `f1` to
+/// `f12`, `pageup`, `pagedown`, `home`, `end`, `insert` or `delete`.
+fn parse_key(name: &str) -> Option<KeyCode> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(c));
+    }
+    match name {
+        "space" => Some(KeyCode::Char(' ')),
+        "tab" => Some(KeyCode::Tab),
+        "escape" => Some(KeyCode::Esc),
+        "pageup" => Some(KeyCode::PageUp),
+        "pagedown" => Some(KeyCode::PageDown),
+        "home" => Some(KeyCode::Home),
+        "end" => Some(KeyCode::End),
+        "insert" => Some(KeyCode::Insert),
+        "delete" => Some(KeyCode::Delete),
+        _ => match name.strip_prefix('f')?.parse() {
+            Ok(number @ 1..=12) => Some(KeyCode::F(number)),
+            _ => None,
+        },
+    }
+}
+
+/// A keymap file. Everything is optional, a file with only `layout` picks
+/// a built-in layout and the `[notes]` table adds to or changes its keys:
+///
+/// ```toml
+/// layout = "tracker-qwertz"
+/// octave-down = "f1"
+/// octave-up = "f2"
+///
+/// [notes]
+/// # semitones above the C of the current octave
+/// "ü" = 29
+/// ```
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct KeymapFile {
+    layout: Option<String>,
+    octave_down: Option<String>,
+    octave_up: Option<String>,
+    transpose_down: Option<String>,
+    transpose_up: Option<String>,
+    sustain: Option<String>,
+    quit: Option<String>,
+    #[serde(default)]
+    notes: HashMap<String, i32>,
+}
+
+/// Which keys of the computer keyboard play which notes, and which ones
+/// control the playing.
+pub struct Keymap {
+    /// Semitones above the C of the current octave.
+    notes: HashMap<KeyCode, i32>,
+    pub octave_down: KeyCode,
+    pub octave_up: KeyCode,
+    /// Move everything a semitone down or up.
+    pub transpose_down: KeyCode,
+    pub transpose_up: KeyCode,
+    pub sustain: KeyCode,
+    pub quit: KeyCode,
+}
+
+impl Keymap {
+    /// The built-in layouts: `qwerty`, `azerty` and `qwertz` play one octave
+    /// on the home row, `tracker`, `tracker-azerty` and `tracker-qwertz` two
+    /// and a half octaves on the bottom and top rows.
+    pub fn from_name(name: &str) -> Option<Keymap> {
+        let (rows, tracker) = match name {
+            "qwerty" => (&QWERTY_ROWS, false),
+            "azerty" => (&AZERTY_ROWS, false),
+            "qwertz" => (&QWERTZ_ROWS, false),
+            "tracker" => (&QWERTY_ROWS, true),
+            "tracker-azerty" => (&AZERTY_ROWS, true),
+            "tracker-qwertz" => (&QWERTZ_ROWS, true),
+            _ => return None,
+        };
+        let layout = if tracker { TRACKER } else { PIANO };
+        let mut notes = HashMap::new();
+        for (keys, first) in layout.iter() {
+            for (offset, key) in keys.chars().enumerate() {
+                notes.insert(KeyCode::Char(translate(key, rows)), first + offset as i32);
+            }
+        }
+        // the tracker layout plays on the number row and q, so the controls move elsewhere
+        let [octave_down, octave_up, transpose_down, transpose_up, quit] = if tracker {
+            [
+                KeyCode::F(1),
+                KeyCode::F(2),
+                KeyCode::F(3),
+                KeyCode::F(4),
+                KeyCode::Esc,
+            ]
+        } else {
+            ['8', '9', '-', '=', 'q'].map(|key| KeyCode::Char(translate(key, rows)))
+        };
+        Some(Keymap {
+            notes,
+            octave_down,
+            octave_up,
+            transpose_down,
+            transpose_up,
+            sustain: KeyCode::Char(' '),
+            quit,
+        })
+    }
+
+    /// Reads a keymap file, see `KeymapFile`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keymap, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file: KeymapFile =
+            toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let layout = file.layout.as_deref().unwrap_or("qwerty");
+        let mut keymap = Keymap::from_name(layout)
+            .ok_or_else(|| format!("{}: unknown layout {}", path.display(), layout))?;
+        let key = |name: &str| {
+            parse_key(name).ok_or_else(|| format!("{}: unknown key {}", path.display(), name))
+        };
+        if let Some(name) = &file.octave_down {
+            keymap.octave_down = key(name)?;
+        }
+        if let Some(name) = &file.octave_up {
+            keymap.octave_up = key(name)?;
+        }
+        if let Some(name) = &file.transpose_down {
+            keymap.transpose_down = key(name)?;
+        }
+        if let Some(name) = &file.transpose_up {
+            keymap.transpose_up = key(name)?;
+        }
+        if let Some(name) = &file.sustain {
+            keymap.sustain = key(name)?;
+        }
+        if let Some(name) = &file.quit {
+            keymap.quit = key(name)?;
+        }
+        for (name, semitones) in file.notes.iter() {
+            keymap.notes.insert(key(name)?, *semitones);
+        }
+        Ok(keymap)
+    }
+
+    /// The MIDI key a key plays, if it plays one. Octave 4 starts at middle
+    /// C and `transpose` moves the key by semitones.
+    pub fn key(&self, code: KeyCode, octave: i32, transpose: i32) -> Option<u8> {
+        let key = 12 * (octave + 1) + transpose + self.notes.get(&code)?;
+        if !(0..=127).contains(&key) {
+            return None;
+        }
+        Some(key as u8)
+    }
+}
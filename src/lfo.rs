@@ -0,0 +1,37 @@
+/// Controls shared by the LFO driven effects.
+#[derive(Copy, Clone)]
+pub struct ModulationSettings {
+    /// LFO speed in Hz.
+    pub rate: f32,
+    /// How far the LFO sweeps, 0.0 to 1.0.
+    pub depth: f32,
+    /// How much of the output is fed back in, -1.0 to 1.0. Negative values
+    /// flip the phase of the feedback, moving the peaks of the comb.
+    pub feedback: f32,
+    /// 0.0 (dry only) to 1.0 (effect only).
+    pub mix: f32,
+}
+
+/// A sine low frequency oscillator. Stereo effects read it twice, the right
+/// channel a quarter cycle ahead of the left so the two never sweep together.
+pub struct Lfo {
+    phase: f32,
+    increment: f32,
+}
+
+impl Lfo {
+    pub fn new(sample_rate: u32, rate: f32) -> Lfo {
+        Lfo {
+            phase: 0.0,
+            increment: rate.max(0.0) / sample_rate as f32,
+        }
+    }
+
+    /// Advances one sample and returns the left and right values, -1.0 to 1.0.
+    pub fn next_stereo(&mut self) -> (f32, f32) {
+        let tau = 2.0 * std::f32::consts::PI;
+        let values = ((self.phase * tau).sin(), ((self.phase + 0.25) * tau).sin());
+        self.phase = (self.phase + self.increment).fract();
+        values
+    }
+}
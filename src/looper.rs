@@ -0,0 +1,188 @@
+use crate::musical_keyboard::NoteEvent;
+
+/// How many presses and releases a loop holds. The space is taken up front
+/// so recording never allocates on the audio thread.
+const MAX_EVENTS: usize = 4096;
+const BEATS_PER_BAR: f32 = 4.0;
+
+#[derive(Copy, Clone, PartialEq)]
+enum LoopState {
+    Empty,
+    /// Waiting for the next bar to start recording.
+    Armed,
+    /// The first time through, which sets the loop going afterwards.
+    Recording {
+        overdub: bool,
+    },
+    Playing {
+        overdub: bool,
+    },
+}
+
+/// Records a phrase of some bars against the clock and plays it over and
+/// over, recording what is played on top of it while overdubbing. The bars
+/// are the sequencer's while it plays, otherwise they are counted at the
+/// tempo from when recording is started.
+pub struct Looper {
+    sample_rate: f32,
+    tempo: f32,
+    bars: u32,
+    state: LoopState,
+    /// Bars since the loop started.
+    bar: u32,
+    /// Frames into the bar, when the looper counts the bars itself.
+    bar_position: f32,
+    /// Frames since the loop started.
+    position: usize,
+    /// Presses and releases and the frame they happen at, in order.
+    events: Vec<(usize, NoteEvent)>,
+    /// The next event to play.
+    next: usize,
+    /// Keys the loop itself is holding down.
+    sounding: [bool; 128],
+}
+
+impl Looper {
+    pub fn new(sample_rate: u32, tempo: f32, bars: u32) -> Looper {
+        Looper {
+            sample_rate: sample_rate as f32,
+            tempo,
+            bars: bars.max(1),
+            state: LoopState::Empty,
+            bar: 0,
+            bar_position: 0.0,
+            position: 0,
+            events: Vec::with_capacity(MAX_EVENTS),
+            next: 0,
+            sounding: [false; 128],
+        }
+    }
+
+    /// Beats per minute the bars are counted at without the sequencer.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.clamp(20.0, 400.0);
+    }
+
+    /// Starts recording a loop on the next bar, or turns overdubbing on and
+    /// off once there is one. Recording that has not started yet is called
+    /// off.
+    pub fn record(&mut self) {
+        self.state = match self.state {
+            LoopState::Empty => LoopState::Armed,
+            LoopState::Armed => LoopState::Empty,
+            LoopState::Recording { overdub } => LoopState::Recording { overdub: !overdub },
+            LoopState::Playing { overdub } => LoopState::Playing { overdub: !overdub },
+        };
+    }
+
+    /// Forgets the loop.
+    pub fn clear<F: FnMut(NoteEvent)>(&mut self, play: F) {
+        self.release(play);
+        self.events.clear();
+        self.state = LoopState::Empty;
+    }
+
+    /// Takes an event played live, keeping the presses and releases while
+    /// recording.
+    pub fn handle_event(&mut self, event: NoteEvent) {
+        let recording = match self.state {
+            LoopState::Empty | LoopState::Armed => false,
+            LoopState::Recording { .. } => true,
+            LoopState::Playing { overdub } => overdub,
+        };
+        if !recording
+            || !matches!(event, NoteEvent::Press(_) | NoteEvent::Up(_))
+            || self.events.len() == self.events.capacity()
+        {
+            return;
+        }
+        match self.state {
+            // nothing after the next event is due before this frame, so it
+            // goes there and counts as played, as it is already sounding
+            LoopState::Playing { .. } => {
+                self.events.insert(self.next, (self.position, event));
+                self.next += 1;
+            }
+            _ => self.events.push((self.position, event)),
+        }
+    }
+
+    /// Advances by one sample, playing what is due. `sequencer_bar` is
+    /// whether a bar of the sequencer starts at this frame, None when it is
+    /// not playing.
+    pub fn tick<F: FnMut(NoteEvent)>(&mut self, sequencer_bar: Option<bool>, mut play: F) {
+        if self.state == LoopState::Empty {
+            return;
+        }
+        let bar_length = BEATS_PER_BAR * 60.0 / self.tempo * self.sample_rate;
+        let bar_start = match sequencer_bar {
+            Some(bar_start) => {
+                // counting carries on in step if the sequencer stops
+                if bar_start {
+                    self.bar_position = 0.0;
+                }
+                bar_start
+            }
+            // without a clock to wait for, the first bar starts right away
+            None if self.state == LoopState::Armed => {
+                self.bar_position = 0.0;
+                true
+            }
+            None if self.bar_position >= bar_length => {
+                self.bar_position -= bar_length;
+                true
+            }
+            None => false,
+        };
+
+        match self.state {
+            LoopState::Armed if !bar_start => return,
+            LoopState::Armed => {
+                self.state = LoopState::Recording { overdub: true };
+                self.bar = 0;
+                self.position = 0;
+                self.next = 0;
+            }
+            _ if bar_start => {
+                self.bar += 1;
+                if self.bar >= self.bars {
+                    // notes held over the end would otherwise never be let go
+                    self.release(&mut play);
+                    self.bar = 0;
+                    self.position = 0;
+                    self.next = 0;
+                    if let LoopState::Recording { overdub } = self.state {
+                        self.state = LoopState::Playing { overdub };
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if let LoopState::Playing { .. } = self.state {
+            while let Some(&(at, event)) = self.events.get(self.next) {
+                if at > self.position {
+                    break;
+                }
+                match event {
+                    NoteEvent::Press(note) => self.sounding[note.key as usize & 127] = true,
+                    NoteEvent::Up(key) => self.sounding[key as usize & 127] = false,
+                    _ => {}
+                }
+                play(event);
+                self.next += 1;
+            }
+        }
+        self.position += 1;
+        self.bar_position += 1.0;
+    }
+
+    fn release<F: FnMut(NoteEvent)>(&mut self, mut play: F) {
+        for key in 0..128 {
+            if self.sounding[key] {
+                self.sounding[key] = false;
+                play(NoteEvent::Up(key as u8));
+            }
+        }
+    }
+}
@@ -1,46 +1,1344 @@
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+use arpeggiator::{ArpMode, ArpSettings, Arpeggiator};
+use chorus::{Chorus, Flanger};
 use crossterm::event::{read, Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-use envvelope::Envelope;
-use musical_keyboard::{frequency_from_keycode, NoteEvent};
-use rodio::{OutputStream, Source};
-use saw_wave_oscilator_band_limited::SawWaveOscilatorBandLimited;
+use delay::{Delay, DelaySettings, DelayTime};
+use drums::{DrumKit, DrumVoice};
+use dynamics::{Compressor, CompressorSettings, Limiter, LimiterSettings};
+use effect::EffectStage;
+use envvelope::{Envelope, EnvelopeSettings};
+use equalizer::{EqBand, Equalizer};
+use filter::{Filter, FilterSettings};
+use granular_oscillator::{GrainSource, GrainWindow, GranularOscillator, GranularSettings};
+use keymap::Keymap;
+use lfo::ModulationSettings;
+use looper::Looper;
+use meter::Meter;
+use midi_file::TimedMessage;
+use midi_input::MidiSettings;
+use music_data::MusicData;
+use musical_keyboard::{NoteEvent, Pedal};
+use parameter::{Parameter, Parameters};
+use patch::{Patch, PatchWatcher, PresetBrowser};
+use phaser::Phaser;
+use pitch::{GlideMode, GlideSettings};
+use playback::Playback;
+use polyphony::{NotePriority, Polyphony, VoiceMode};
+use recorder::{Input, Recorder};
+use render::Renderer;
+use reverb::{Reverb, ReverbSettings};
+use rodio::{OutputStream, OutputStreamHandle, Source};
+use sampler::{SampleData, SampleMap, Sampler};
+use saw_wave_oscilator_band_limited::{OscillatorSettings, SawWaveOscilatorBandLimited};
+use sequencer::{Sequence, Sequencer, Transport};
+use tuning::{KeyboardMapping, Scale, Tuning};
+use velocity::VelocityCurve;
+use wave_table_oscilator::WavetableOscillator;
+use waveshaper::{ShapeMode, Waveshaper, WaveshaperSettings};
+mod arpeggiator;
+mod atomic_f32;
+mod biquad;
+mod chorus;
+// kept as the first version wrote it
+#[allow(
+    dead_code,
+    clippy::extra_unused_type_parameters,
+    clippy::unnecessary_unwrap
+)]
 mod dataconverter;
+mod delay;
+mod drums;
+mod dynamics;
+mod effect;
 pub mod envvelope;
+mod equalizer;
+mod filter;
+mod granular_oscillator;
+mod keymap;
+mod lfo;
+mod looper;
+mod meter;
+mod midi_file;
+mod midi_input;
+mod mml;
 mod music_data;
 mod musical_keyboard;
+mod osc;
+mod parameter;
+mod patch;
+mod phaser;
+mod pitch;
+mod playback;
+mod polyphony;
+mod random;
+mod recorder;
+mod render;
+mod reverb;
+mod sampler;
+// kept as the first version wrote it
+#[allow(
+    dead_code,
+    clippy::needless_return,
+    clippy::single_match,
+    clippy::assign_op_pattern
+)]
 mod saw_wave_oscilator;
 mod saw_wave_oscilator_band_limited;
+mod sequencer;
+mod sf2;
+mod sfz;
+mod tuning;
+mod velocity;
+// kept as the first version wrote it
+#[allow(clippy::needless_return, clippy::single_match)]
 mod wave_table_oscilator;
+mod waveshaper;
+
+const SAMPLE_RATE: u32 = 44100;
+const VOICES: usize = 8;
+/// Octaves the computer keyboard can be moved to, 4 being the one from middle C.
+const OCTAVES: RangeInclusive<i32> = -1..=8;
+/// Semitones the computer keyboard can be transposed either way.
+const MAX_TRANSPOSE: i32 = 12;
+/// Bars the looper records unless `--loop-bars` says otherwise.
+const LOOP_BARS: u32 = 4;
+/// How often the gain reduction meters are looked at.
+const METER_INTERVAL: Duration = Duration::from_millis(250);
+/// How long after its file changes the patch is read again.
+const RELOAD_DELAY: Duration = Duration::from_millis(50);
 
 fn main() {
-    let (tx, rx) = mpsc::channel();
+    let args: Vec<String> = std::env::args().collect();
 
-    //let oscillator = WavetableOscillator::new(44100, wave_table, rx);
-    let oscillator = SawWaveOscilatorBandLimited::new(44100, rx);
+    if args.get(1).map(String::as_str) == Some("render") {
+        render_midi_file(&args);
+        return;
+    }
 
-    let envelope = Envelope::new::<SawWaveOscilatorBandLimited>(oscillator);
-    //let data_converter = DataConverter::new::<SawWaveOscilatorBandLimited>(oscillator);
+    let instrument = match load_instrument(&args) {
+        Ok(instrument) => instrument,
+        Err(message) => {
+            eprintln!("{}", message);
+            return;
+        }
+    };
+    let (
+        patch,
+        options,
+        effects,
+        midi,
+        osc_port,
+        keymap,
+        tuning,
+        sequence,
+        arpeggio,
+        song,
+        loop_bars,
+    ) = match patch_option(&args).and_then(|patch| {
+        let options = voice_options(&args, &patch)?;
+        let effects = effect_options(&args, &patch)?;
+        let midi = midi_options(&args)?;
+        let osc_port = parse_arg::<u16>(&args, "--osc")?;
+        let keymap = keymap_option(&args)?;
+        let tuning = tuning_options(&args)?;
+        let sequence = arg_value(&args, "--sequence")
+            .map(Sequence::load)
+            .transpose()?;
+        let arpeggio = arp_options(&args)?;
+        let song = arg_value(&args, "--play").map(load_song).transpose()?;
+        let loop_bars = parse_arg::<u32>(&args, "--loop-bars")?.unwrap_or(LOOP_BARS);
+        Ok((
+            patch, options, effects, midi, osc_port, keymap, tuning, sequence, arpeggio, song,
+            loop_bars,
+        ))
+    }) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            return;
+        }
+    };
+
+    // everything played goes to the voices, and to the recorder on the side
+    let (synth_tx, rx) = mpsc::channel();
+    let (recorder_tx, recorder_rx) = mpsc::channel();
+    let tx = Input::new(synth_tx, recorder_tx);
+    let bend_range = midi
+        .as_ref()
+        .map_or(MidiSettings::default().bend_range, |settings| {
+            settings.bend_range
+        });
+    let recording = Recording {
+        recorder: Arc::new(Mutex::new(Recorder::new(bend_range))),
+        path: arg_value(&args, "--record")
+            .cloned()
+            .unwrap_or_else(|| "recording.mid".to_string()),
+    };
+    let recorder = recording.recorder.clone();
+    thread::spawn(move || {
+        for (time, event) in recorder_rx {
+            recorder.lock().unwrap().record(time, &event);
+        }
+    });
+    // the connection has to be kept for as long as notes should come in
+    let _midi_connection = match midi
+        .map(|settings| midi_input::connect(&settings, tuning.clone(), tx.clone()))
+        .transpose()
+    {
+        Ok(connection) => connection,
+        Err(message) => {
+            eprintln!("{}", message);
+            return;
+        }
+    };
+    let parameters = parameters(&options, &effects, sequence.as_ref());
+    let pattern_count = sequence
+        .as_ref()
+        .map_or(0, |sequence| sequence.patterns.len());
+    let sequencer =
+        sequence.map(|sequence| Sequencer::new(options.sample_rate, sequence, tuning.clone()));
+    let tempo = parameters
+        .get(Parameter::Tempo)
+        .unwrap_or(sequencer::DEFAULT_TEMPO);
+    let arpeggiator = arpeggio
+        .map(|settings| Arpeggiator::new(options.sample_rate, settings, tempo, tuning.clone()));
+    let playback = song.map(|song| Playback::new(options.sample_rate, song, tuning.clone()));
+    let looper = Looper::new(options.sample_rate, tempo, loop_bars);
+    if let Some(port) = osc_port {
+        if let Err(message) = osc::listen(port, tx.clone(), parameters.clone(), tuning.clone()) {
+            eprintln!("{}", message);
+            return;
+        }
+    }
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+    let meters = Meters::default();
+
+    let players = Players {
+        sequencer,
+        arpeggiator,
+        playback,
+        looper,
+    };
+    let output = Output {
+        stream_handle: &stream_handle,
+        effects: &effects,
+        meters: &meters,
+    };
+    match instrument {
+        Instrument::Saw => play(rx, &options, players, output, |voice_rx| {
+            //let oscillator = WavetableOscillator::new(44100, wave_table, rx);
+            let mut oscillator = SawWaveOscilatorBandLimited::new(options.sample_rate, voice_rx);
+            oscillator.set_glide(options.glide);
+            oscillator
+        }),
+        Instrument::Sampler(sample_map) => play(rx, &options, players, output, |voice_rx| {
+            let mut sampler = Sampler::new(options.sample_rate, sample_map.clone(), voice_rx);
+            sampler.set_glide(options.glide);
+            sampler
+        }),
+        Instrument::Granular(source, settings) => {
+            let mut seed = 0;
+            play(rx, &options, players, output, |voice_rx| {
+                seed += 1;
+                let mut oscillator = GranularOscillator::new(
+                    options.sample_rate,
+                    source.clone(),
+                    settings,
+                    seed,
+                    voice_rx,
+                );
+                oscillator.set_glide(options.glide);
+                oscillator
+            })
+        }
+        Instrument::Drums(kit) => {
+            let mut seed = 0;
+            play(rx, &options, players, output, |voice_rx| {
+                seed += 1;
+                DrumVoice::new(options.sample_rate, kit, seed, voice_rx)
+            })
+        }
+    }
+
+    meters.show();
+
+    // the voices start out with their own defaults, the patch sets the rest
+    for (parameter, value) in parameters.iter() {
+        if parameter.is_voice() {
+            tx.send(NoteEvent::Parameter(parameter, value)).unwrap();
+        }
+    }
+
+    let browser = PresetBrowser::new(
+        arg_value(&args, "--presets").map_or("presets", String::as_str),
+        arg_value(&args, "--patch").map(Path::new),
+    );
+    let (changes_tx, changes) = mpsc::channel();
+    let watcher = match PatchWatcher::new(changes_tx) {
+        Ok(watcher) => Some(watcher),
+        Err(message) => {
+            eprintln!("{}", message);
+            None
+        }
+    };
+    let mut presets = Presets {
+        browser,
+        patch,
+        watcher,
+        loaded: true,
+    };
+    presets.watch();
+    let presets = Arc::new(Mutex::new(presets));
+    reload_on_change(presets.clone(), changes, tx.clone(), parameters.clone());
+    let mut files = Files { recording, presets };
+    listen_for_keyboard(tx, &keymap, &tuning, &parameters, pattern_count, &mut files);
+    // what is being recorded is kept on quitting
+    if files.recording.recorder.lock().unwrap().is_recording() {
+        files.recording.toggle(&parameters);
+    }
+}
+
+/// What plays along with the keyboard on the audio thread.
+struct Players {
+    sequencer: Option<Sequencer>,
+    arpeggiator: Option<Arpeggiator>,
+    playback: Option<Playback>,
+    looper: Looper,
+}
+
+/// Where the voices are heard, through the effects.
+struct Output<'a> {
+    stream_handle: &'a OutputStreamHandle,
+    effects: &'a EffectOptions,
+    meters: &'a Meters,
+}
+
+/// Starts the voices playing the events from `rx`, each one an oscillator
+/// made by `new_oscillator` with the filter and envelope after it.
+fn play<S, F>(
+    rx: mpsc::Receiver<NoteEvent>,
+    options: &VoiceOptions,
+    players: Players,
+    output: Output,
+    mut new_oscillator: F,
+) where
+    S: Iterator<Item = MusicData> + Source + Send + 'static,
+    F: FnMut(mpsc::Receiver<NoteEvent>) -> S,
+{
+    let mut voices = Polyphony::new(options.sample_rate, VOICES, rx, |voice_rx| {
+        options.voice(new_oscillator(voice_rx))
+    });
+    options.apply(&mut voices);
+    voices.set_sequencer(players.sequencer);
+    voices.set_arpeggiator(players.arpeggiator);
+    voices.set_playback(players.playback);
+    voices.set_looper(Some(players.looper));
+    //let data_converter = DataConverter::new(voices);
+
+    let voices = output.effects.chain(voices, output.meters);
+    let _result = output.stream_handle.play_raw(voices.convert_samples());
+}
+
+/// What is saved and loaded while playing.
+struct Files {
+    recording: Recording,
+    /// Shared with the thread reloading the patch when its file changes.
+    presets: Arc<Mutex<Presets>>,
+}
+
+/// What is played from every input, saved as a MIDI file.
+struct Recording {
+    recorder: Arc<Mutex<Recorder>>,
+    path: String,
+}
+
+impl Recording {
+    /// Starts recording, or stops and saves what was recorded.
+    fn toggle(&self, parameters: &Parameters) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if !recorder.is_recording() {
+            recorder.start();
+            print!("Recording\r\n");
+            return;
+        }
+        let messages = recorder.stop();
+        let tempo = parameters
+            .get(Parameter::Tempo)
+            .unwrap_or(sequencer::DEFAULT_TEMPO);
+        match midi_file::save(&self.path, &messages, tempo) {
+            Ok(()) => print!("Saved {}\r\n", self.path),
+            Err(e) => print!("{}: {}\r\n", self.path, e),
+        }
+    }
+}
+
+/// The patch playing and the presets it can be changed to.
+struct Presets {
+    browser: PresetBrowser,
+    patch: Patch,
+    /// Watches the file of the preset playing, None when it can not.
+    watcher: Option<PatchWatcher>,
+    /// Whether the file of the preset playing loaded. One that did not has
+    /// edits that are not playing, so it is not saved over.
+    loaded: bool,
+}
+
+impl Presets {
+    /// Loads the preset `step` places on and plays with it.
+    fn step(&mut self, step: isize, tx: &Input, parameters: &Parameters) {
+        let patch = self.browser.step(step);
+        // a preset that does not load is watched too, so it plays once fixed
+        self.watch();
+        let patch = match patch {
+            Some(Ok(patch)) => patch,
+            Some(Err(message)) => {
+                self.loaded = false;
+                print!("{}\r\n", message);
+                return;
+            }
+            None => {
+                print!("No presets\r\n");
+                return;
+            }
+        };
+        if let Err(message) = apply_patch(&patch, tx, parameters) {
+            self.loaded = false;
+            print!("{}\r\n", message);
+            return;
+        }
+        let (position, count) = self.browser.position();
+        print!("Preset {}/{}: {}\r\n", position, count, patch.name);
+        self.patch = patch;
+        self.loaded = true;
+    }
+
+    /// Saves the patch as it is playing now.
+    fn save(&mut self, parameters: &Parameters) {
+        if !self.loaded {
+            if let Some(path) = self.browser.path() {
+                print!("{} did not load, not saving over it\r\n", path.display());
+            }
+            return;
+        }
+        self.patch.update(parameters);
+        match self.browser.save(&self.patch) {
+            Ok(path) => print!("Saved {}\r\n", path.display()),
+            Err(message) => print!("{}\r\n", message),
+        }
+        self.watch();
+    }
+
+    /// Reads the file of the preset playing again and plays with what it
+    /// says now. A file that does not load is reported and the sound kept.
+    fn reload(&mut self, tx: &Input, parameters: &Parameters) {
+        let path = match self.browser.path() {
+            Some(path) => path,
+            None => return,
+        };
+        let patch = match Patch::load(path) {
+            Ok(patch) => patch,
+            Err(message) => {
+                self.loaded = false;
+                print!("{}\r\n", message);
+                return;
+            }
+        };
+        self.loaded = true;
+        // saving writes the file too
+        if patch == self.patch {
+            return;
+        }
+        if let Err(message) = apply_patch(&patch, tx, parameters) {
+            self.loaded = false;
+            print!("{}\r\n", message);
+            return;
+        }
+        print!("Reloaded {}\r\n", patch.name);
+        if self.patch.differs_at_startup(&patch, parameters) {
+            print!("Some of the changes are only heard after a restart\r\n");
+        }
+        self.patch = patch;
+    }
+
+    /// Watches the file of the preset playing.
+    fn watch(&mut self) {
+        if let (Some(watcher), Some(path)) = (self.watcher.as_mut(), self.browser.path()) {
+            if let Err(message) = watcher.watch(path) {
+                print!("{}\r\n", message);
+            }
+        }
+    }
+
+    fn is_watching(&self, path: &Path) -> bool {
+        self.watcher
+            .as_ref()
+            .is_some_and(|watcher| watcher.is_watching(path))
+    }
+}
+
+/// Reloads the patch playing whenever its file changes. The new patch is
+/// read and compared here and only the parameters that changed are sent,
+/// so the audio thread takes them like any other parameter change.
+fn reload_on_change(
+    presets: Arc<Mutex<Presets>>,
+    changes: mpsc::Receiver<PathBuf>,
+    tx: Input,
+    parameters: Parameters,
+) {
+    thread::spawn(move || {
+        while let Ok(path) = changes.recv() {
+            // editors save in a few writes, so wait for the last of them
+            thread::sleep(RELOAD_DELAY);
+            let mut changed = vec![path];
+            changed.extend(changes.try_iter());
+            let mut presets = presets.lock().unwrap();
+            if changed.iter().any(|path| presets.is_watching(path)) {
+                presets.reload(&tx, &parameters);
+            }
+        }
+    });
+}
+
+/// Changes every parameter that can be changed while playing to the
+/// patch's value, sending only the ones that differ. The rest of the patch
+/// is only read at startup.
+fn apply_patch(patch: &Patch, tx: &Input, parameters: &Parameters) -> Result<(), String> {
+    let options = voice_options(&[], patch)?;
+    let mut effects = effect_options(&[], patch)?;
+    // note length delay times are timed against the tempo playing
+    effects.delay.tempo = parameters
+        .get(Parameter::Tempo)
+        .unwrap_or(sequencer::DEFAULT_TEMPO);
+    for (parameter, value) in self::parameters(&options, &effects, None).iter() {
+        // the tempo and swing belong to the sequence, and parameters of
+        // stages that are not playing are left out
+        // `DelayNote` comes after `DelayTime`, so a note length replaces the
+        // time in milliseconds sent for it
+        let unchanged = parameters.get(parameter) == Some(value);
+        if !unchanged && !matches!(parameter, Parameter::Tempo | Parameter::Swing) {
+            parameters.send(tx, parameter, value);
+        }
+    }
+    Ok(())
+}
+
+/// `--patch <file.toml>` starts out with a patch, see `Patch`, which the
+/// other options change.
+fn patch_option(args: &[String]) -> Result<Patch, String> {
+    match arg_value(args, "--patch") {
+        Some(path) => Patch::load(path),
+        None => Ok(Patch::default()),
+    }
+}
+
+enum Instrument {
+    Saw,
+    Sampler(Arc<SampleMap>),
+    Granular(GrainSource, GranularSettings),
+    Drums(DrumKit),
+}
+
+/// Picks the instrument to play from the command line.
+fn load_instrument(args: &[String]) -> Result<Instrument, String> {
+    // `--drums` plays the drum machine, `--drum <drum>:<tune>:<decay>:<tone>`
+    // changes one of its drums
+    if args.iter().any(|arg| arg == "--drums") {
+        let mut kit = DrumKit::default();
+        for spec in arg_values(args, "--drum") {
+            kit.set_from_spec(spec)
+                .ok_or_else(|| format!("invalid drum {}", spec))?;
+        }
+        return Ok(Instrument::Drums(kit));
+    }
+
+    if let Some(path) = arg_value(args, "--sfz") {
+        let sample_map = sfz::load(path).map_err(|e| format!("{}: {}", path, e))?;
+        return Ok(Instrument::Sampler(Arc::new(sample_map)));
+    }
+
+    if let Some(path) = arg_value(args, "--sf2") {
+        let program = arg_value(args, "--program")
+            .and_then(|program| program.parse().ok())
+            .unwrap_or(0);
+        let sound_font = sf2::load(path).map_err(|e| format!("{}: {}", path, e))?;
+        let preset = sound_font
+            .preset(0, program)
+            .ok_or_else(|| format!("{}: no presets", path))?;
+        println!("Playing preset {}\r", preset.name);
+        return Ok(Instrument::Sampler(preset.sample_map.clone()));
+    }
+
+    // `--granular wavetable` scatters grains of the wavetable oscillator's
+    // table, anything else is the path of a sample recorded at middle C
+    if let Some(source) = arg_value(args, "--granular") {
+        let mut settings = GranularSettings::default();
+        if let Some(name) = arg_value(args, "--grain-window") {
+            settings.window = GrainWindow::from_name(name)
+                .ok_or_else(|| format!("unknown grain window {}", name))?;
+        }
+        if source == "wavetable" {
+            let (_unused_tx, unused_rx) = mpsc::channel();
+            let oscillator = WavetableOscillator::new_sinwave(SAMPLE_RATE, unused_rx);
+            return Ok(Instrument::Granular(
+                GrainSource::from_wave_table(&oscillator),
+                settings,
+            ));
+        }
+        let sample = SampleData::load_wav(source).map_err(|e| format!("{}: {}", source, e))?;
+        return Ok(Instrument::Granular(
+            GrainSource::from_sample(Arc::new(sample), 60),
+            settings,
+        ));
+    }
+
+    Ok(Instrument::Saw)
+}
+
+/// How the voices of the live instrument play, from the command line.
+struct VoiceOptions {
+    sample_rate: u32,
+    mode: VoiceMode,
+    glide: GlideSettings,
+    velocity_curve: VelocityCurve,
+    /// Distortion on every voice rather than on the mix.
+    drive: Option<WaveshaperSettings>,
+    oscillator: OscillatorSettings,
+    filter: FilterSettings,
+    envelope: EnvelopeSettings,
+    /// Vibrato depth in semitones and rate in Hz.
+    vibrato: (f32, f32),
+}
+
+impl VoiceOptions {
+    /// Puts the distortion, filter and envelope after a voice's oscillator.
+    fn voice<S>(&self, oscillator: S) -> Envelope<Filter<EffectStage<S, Option<Waveshaper>>>>
+    where
+        S: Iterator<Item = MusicData> + Source,
+    {
+        let sample_rate = oscillator.sample_rate();
+        let drive = self
+            .drive
+            .map(|settings| Waveshaper::new(sample_rate, settings));
+        let drive = EffectStage::new(oscillator, drive);
+        Envelope::with_settings(Filter::new(drive, self.filter), self.envelope)
+    }
+
+    fn apply<V>(&self, voices: &mut Polyphony<V>)
+    where
+        V: Iterator<Item = MusicData> + Source,
+    {
+        voices.set_mode(self.mode);
+        voices.set_velocity_curve(self.velocity_curve);
+    }
+}
+
+/// Reads `--mono [priority]` or `--legato [priority]`, `--glide <seconds>`
+/// with an optional `--glide-mode time|rate`, the velocity curve and what
+/// else velocity changes, and the filter, over the patch's settings.
+fn voice_options(args: &[String], patch: &Patch) -> Result<VoiceOptions, String> {
+    let priority = |flag: &str| -> Result<NotePriority, String> {
+        match arg_value(args, flag) {
+            Some(name) if !name.starts_with("--") => NotePriority::from_name(name)
+                .ok_or_else(|| format!("unknown note priority {}", name)),
+            _ => Ok(NotePriority::Last),
+        }
+    };
+    let mode = if args.iter().any(|arg| arg == "--legato") {
+        VoiceMode::Legato(priority("--legato")?)
+    } else if args.iter().any(|arg| arg == "--mono") {
+        VoiceMode::Mono(priority("--mono")?)
+    } else {
+        VoiceMode::Poly
+    };
+
+    let mut glide = patch.glide()?;
+    if let Some(time) = parse_arg(args, "--glide")? {
+        glide.time = time;
+        glide.mode = GlideMode::ConstantTime;
+    }
+    if let Some(name) = arg_value(args, "--glide-mode") {
+        glide.mode =
+            GlideMode::from_name(name).ok_or_else(|| format!("unknown glide mode {}", name))?;
+    }
+
+    let velocity_curve = match arg_value(args, "--velocity-curve") {
+        Some(name) => VelocityCurve::from_name(name)
+            .ok_or_else(|| format!("unknown velocity curve {}", name))?,
+        None => patch.velocity_curve()?,
+    };
+
+    let mut filter = patch.filter();
+    filter.cutoff = parse_arg(args, "--cutoff")?.unwrap_or(filter.cutoff);
+    filter.resonance = parse_arg(args, "--resonance")?.unwrap_or(filter.resonance);
+    filter.velocity = parse_arg(args, "--velocity-to-cutoff")?.unwrap_or(filter.velocity);
+
+    let mut envelope = patch.envelope();
+    envelope.velocity_attack =
+        parse_arg(args, "--velocity-to-attack")?.unwrap_or(envelope.velocity_attack);
+
+    Ok(VoiceOptions {
+        sample_rate: parse_arg(args, "--sample-rate")?.unwrap_or(SAMPLE_RATE),
+        mode,
+        glide,
+        velocity_curve,
+        drive: drive_options(args, patch)?
+            .filter(|(_, per_voice)| *per_voice)
+            .map(|(drive, _)| drive),
+        oscillator: patch.oscillator()?,
+        filter,
+        envelope,
+        vibrato: (patch.modulation.vibrato, patch.modulation.vibrato_rate),
+    })
+}
+
+/// The effects the voices are played through.
+struct EffectOptions {
+    drive: Option<WaveshaperSettings>,
+    chorus: ModulationSettings,
+    flanger: ModulationSettings,
+    phaser: ModulationSettings,
+    delay: DelaySettings,
+    reverb: ReverbSettings,
+    eq: Vec<EqBand>,
+    compressor: Option<CompressorSettings>,
+    limiter: LimiterSettings,
+}
+
+/// Gain reduction of the master dynamics, in dB, for showing while playing.
+#[derive(Clone, Default)]
+struct Meters {
+    compressor: Meter,
+    limiter: Meter,
+}
+
+impl Meters {
+    /// Prints the gain reduction whenever it has moved by a decibel or more,
+    /// looking a few times a second.
+    fn show(&self) {
+        let meters = self.clone();
+        thread::spawn(move || {
+            let mut shown = (0.0, 0.0);
+            loop {
+                thread::sleep(METER_INTERVAL);
+                let reduction = (meters.compressor.get(), meters.limiter.get());
+                if (reduction.0 - shown.0).abs() >= 1.0 || (reduction.1 - shown.1).abs() >= 1.0 {
+                    print!(
+                        "Gain reduction: compressor {:.1} dB, limiter {:.1} dB\r\n",
+                        reduction.0, reduction.1
+                    );
+                    shown = reduction;
+                }
+            }
+        });
+    }
+}
+
+impl EffectOptions {
+    fn chain<I>(&self, voices: I, meters: &Meters) -> impl Source<Item = MusicData>
+    where
+        I: Iterator<Item = MusicData> + Source,
+    {
+        let sample_rate = voices.sample_rate();
+        let drive = self
+            .drive
+            .map(|settings| Waveshaper::new(sample_rate, settings));
+        let voices = EffectStage::new(voices, drive);
+        let voices = EffectStage::new(voices, Chorus::new(sample_rate, self.chorus));
+        let voices = EffectStage::new(voices, Flanger::new(sample_rate, self.flanger));
+        let voices = EffectStage::new(voices, Phaser::new(sample_rate, self.phaser));
+        let voices = EffectStage::new(voices, Delay::new(sample_rate, self.delay));
+        let voices = EffectStage::new(voices, Reverb::new(sample_rate, self.reverb));
+        let voices = EffectStage::new(voices, Equalizer::new(sample_rate, &self.eq));
+        let compressor = self
+            .compressor
+            .map(|settings| Compressor::new(sample_rate, settings, meters.compressor.clone()));
+        let voices = EffectStage::new(voices, compressor);
+        let limiter = Limiter::new(sample_rate, self.limiter, meters.limiter.clone());
+        EffectStage::new(voices, limiter)
+    }
+}
+
+/// Every parameter that can be changed while playing, starting out at the
+/// values given on the command line or in the patch.
+fn parameters(
+    options: &VoiceOptions,
+    effects: &EffectOptions,
+    sequence: Option<&Sequence>,
+) -> Parameters {
+    let mut values = vec![
+        (Parameter::Cutoff, options.filter.cutoff),
+        (Parameter::Resonance, options.filter.resonance),
+        (Parameter::Shape, options.oscillator.shape.index()),
+        (Parameter::Harmonics, options.oscillator.harmonics as f32),
+        (Parameter::OscillatorDecay, options.oscillator.decay),
+        (Parameter::Attack, options.envelope.attack),
+        (Parameter::Decay, options.envelope.decay),
+        (Parameter::Sustain, options.envelope.sustain),
+        (Parameter::Release, options.envelope.release),
+        (Parameter::VelocityToLevel, options.envelope.velocity_track),
+        (
+            Parameter::VelocityToAttack,
+            options.envelope.velocity_attack,
+        ),
+        (Parameter::VelocityToCutoff, options.filter.velocity),
+        (Parameter::PressureToCutoff, options.filter.pressure),
+        (Parameter::Glide, options.glide.time),
+        (Parameter::Vibrato, options.vibrato.0),
+        (Parameter::VibratoRate, options.vibrato.1),
+        (Parameter::ChorusMix, effects.chorus.mix),
+        (Parameter::FlangerMix, effects.flanger.mix),
+        (Parameter::PhaserMix, effects.phaser.mix),
+        (
+            Parameter::DelayTime,
+            effects.delay.time.seconds(effects.delay.tempo) * 1000.0,
+        ),
+        (
+            Parameter::DelayNote,
+            match effects.delay.time {
+                DelayTime::Division(fraction) => fraction,
+                DelayTime::Milliseconds(_) => 0.0,
+            },
+        ),
+        (Parameter::DelayFeedback, effects.delay.feedback),
+        (Parameter::DelayMix, effects.delay.mix),
+        (Parameter::ReverbMix, effects.reverb.mix),
+        (Parameter::RoomSize, effects.reverb.room_size),
+        (Parameter::Damping, effects.reverb.damping),
+    ];
+    for (index, band) in effects.eq.iter().enumerate() {
+        values.push((Parameter::EqGain(index as u8), band.gain));
+        values.push((Parameter::EqFrequency(index as u8), band.frequency));
+    }
+    if let Some(compressor) = effects.compressor {
+        values.push((Parameter::CompressorThreshold, compressor.threshold));
+    }
+    values.push((Parameter::Ceiling, effects.limiter.ceiling));
+    values.push((
+        Parameter::Tempo,
+        sequence.map_or(effects.delay.tempo, |sequence| sequence.tempo),
+    ));
+    values.push((
+        Parameter::Swing,
+        sequence.map_or(sequencer::DEFAULT_SWING, |sequence| sequence.swing),
+    ));
+    Parameters::new(&values)
+}
+
+/// Reads the effect settings over the patch's. `--chorus <mix>`,
+/// `--flanger <mix>`, `--phaser <mix>` and `--reverb <mix>` turn those on,
+/// `--delay <ms|1/8|1/8d|1/8t>` the delay and `--compressor <threshold>` the
+/// compressor. `--eq <band>`, see `EqBand::from_spec`, adds an EQ band and
+/// can be repeated. The limiter is always on.
+fn effect_options(args: &[String], patch: &Patch) -> Result<EffectOptions, String> {
+    let effects = &patch.effects;
+    let chorus = modulation_options(args, "chorus", effects.chorus.settings())?;
+    let flanger = modulation_options(args, "flanger", effects.flanger.settings())?;
+    let phaser = modulation_options(args, "phaser", effects.phaser.settings())?;
+
+    let tempo = parse_arg(args, "--bpm")?.unwrap_or(sequencer::DEFAULT_TEMPO);
+    let mut delay = effects.delay.settings(tempo)?;
+    if let Some(time) = arg_value(args, "--delay") {
+        delay.time =
+            DelayTime::from_name(time).ok_or_else(|| format!("invalid delay time {}", time))?;
+        delay.mix = 0.3;
+    }
+    delay.feedback = parse_arg(args, "--delay-feedback")?.unwrap_or(delay.feedback);
+    delay.low_cut = parse_arg(args, "--delay-low-cut")?.unwrap_or(delay.low_cut);
+    delay.high_cut = parse_arg(args, "--delay-high-cut")?.unwrap_or(delay.high_cut);
+    delay.mix = parse_arg(args, "--delay-mix")?.unwrap_or(delay.mix);
+    delay.ping_pong |= args.iter().any(|arg| arg == "--ping-pong");
+
+    let mut reverb = effects.reverb.settings();
+    reverb.mix = parse_arg(args, "--reverb")?.unwrap_or(reverb.mix);
+    reverb.room_size = parse_arg(args, "--room-size")?.unwrap_or(reverb.room_size);
+    reverb.damping = parse_arg(args, "--damping")?.unwrap_or(reverb.damping);
+    reverb.pre_delay = parse_arg::<f32>(args, "--pre-delay")?
+        .map(|ms| ms / 1000.0)
+        .unwrap_or(reverb.pre_delay);
+    reverb.width = parse_arg(args, "--reverb-width")?.unwrap_or(reverb.width);
+
+    let compressor = match parse_arg(args, "--compressor")? {
+        Some(threshold) => {
+            let defaults = effects
+                .compressor
+                .as_ref()
+                .map_or_else(CompressorSettings::default, |compressor| {
+                    compressor.settings()
+                });
+            Some(CompressorSettings {
+                threshold,
+                ratio: parse_arg(args, "--ratio")?.unwrap_or(defaults.ratio),
+                attack: parse_arg::<f32>(args, "--attack")?
+                    .map(|ms| ms / 1000.0)
+                    .unwrap_or(defaults.attack),
+                release: parse_arg::<f32>(args, "--release")?
+                    .map(|ms| ms / 1000.0)
+                    .unwrap_or(defaults.release),
+                knee: parse_arg(args, "--knee")?.unwrap_or(defaults.knee),
+                makeup: parse_arg(args, "--makeup")?.unwrap_or(defaults.makeup),
+            })
+        }
+        None => effects
+            .compressor
+            .as_ref()
+            .map(|compressor| compressor.settings()),
+    };
+    // the bands on the command line come after the patch's
+    let mut eq = effects.eq()?;
+    for spec in arg_values(args, "--eq") {
+        eq.push(EqBand::from_spec(spec).ok_or_else(|| format!("invalid EQ band {}", spec))?);
+    }
+    let limiter = LimiterSettings {
+        ceiling: parse_arg(args, "--ceiling")?.unwrap_or(effects.ceiling),
+        ..LimiterSettings::default()
+    };
+
+    Ok(EffectOptions {
+        drive: drive_options(args, patch)?
+            .filter(|(_, per_voice)| !*per_voice)
+            .map(|(drive, _)| drive),
+        chorus,
+        flanger,
+        phaser,
+        delay,
+        reverb,
+        eq,
+        compressor,
+        limiter,
+    })
+}
+
+/// `--arp up|down|up-down|random|played|chord` arpeggiates the held notes,
+/// with `--arp-octaves <1-4>`, `--arp-rate <1/16|1/8t|...>`, `--arp-gate
+/// <0-1>` and `--arp-latch` to keep playing after the keys are let go.
+fn arp_options(args: &[String]) -> Result<Option<ArpSettings>, String> {
+    let mode = match arg_value(args, "--arp") {
+        Some(name) => {
+            ArpMode::from_name(name).ok_or_else(|| format!("unknown arpeggio mode {}", name))?
+        }
+        None => return Ok(None),
+    };
+    let defaults = ArpSettings::default();
+    let division = match arg_value(args, "--arp-rate") {
+        Some(rate) => match DelayTime::from_name(rate) {
+            Some(DelayTime::Division(division)) if division > 0.0 => division,
+            _ => {
+                return Err(format!(
+                    "arpeggio rate {} is not a note length such as 1/16",
+                    rate
+                ))
+            }
+        },
+        None => defaults.division,
+    };
+    let octaves = match parse_arg::<u8>(args, "--arp-octaves")? {
+        Some(octaves @ 1..=4) => octaves,
+        Some(octaves) => return Err(format!("arpeggio octaves {} is not 1-4", octaves)),
+        None => defaults.octaves,
+    };
+    let gate = parse_arg::<f32>(args, "--arp-gate")?.unwrap_or(defaults.gate);
+    if gate <= 0.0 || gate > 1.0 {
+        return Err(format!("arpeggio gate {} is not above 0 and up to 1", gate));
+    }
+    Ok(Some(ArpSettings {
+        mode,
+        octaves,
+        division,
+        gate,
+        latch: args.iter().any(|arg| arg == "--arp-latch"),
+    }))
+}
+
+/// `--keymap <layout>` picks a built-in layout, see `Keymap::from_name`,
+/// and `--keymap <file.toml>` reads one from a file.
+fn keymap_option(args: &[String]) -> Result<Keymap, String> {
+    match arg_value(args, "--keymap") {
+        Some(name) => match Keymap::from_name(name) {
+            Some(keymap) => Ok(keymap),
+            None => Keymap::load(name),
+        },
+        None => Ok(Keymap::from_name("qwerty").unwrap()),
+    }
+}
+
+/// `--tuning just|pythagorean|<n>edo` or `--tuning <file.scl>` picks the
+/// scale, `--kbm <file.kbm>` maps it to the keys and `--reference <hz>`
+/// tunes the reference key, A4 unless the mapping says otherwise.
+fn tuning_options(args: &[String]) -> Result<Tuning, String> {
+    let scale = match arg_value(args, "--tuning") {
+        Some(name) => match Scale::from_name(name) {
+            Some(scale) => scale,
+            None => Scale::load(name)?,
+        },
+        None => Scale::equal(12),
+    };
+    let mut mapping = match arg_value(args, "--kbm") {
+        Some(path) => KeyboardMapping::load(path)?,
+        None => KeyboardMapping::default(),
+    };
+    if let Some(reference) = parse_arg::<f32>(args, "--reference")? {
+        if reference <= 0.0 {
+            return Err(format!("reference pitch {} Hz", reference));
+        }
+        mapping.reference_frequency = reference;
+    }
+    Ok(Tuning::new(&scale, &mapping))
+}
+
+/// `--midi` listens on a virtual sequencer port, `--midi-port <name>`
+/// connects to an existing one. `--midi-channel <1-16>` and
+/// `--bend-range <semitones>` apply to both.
+fn midi_options(args: &[String]) -> Result<Option<MidiSettings>, String> {
+    let port = arg_value(args, "--midi-port").cloned();
+    if port.is_none() && !args.iter().any(|arg| arg == "--midi") {
+        return Ok(None);
+    }
+    let channel = match parse_arg::<u8>(args, "--midi-channel")? {
+        Some(channel @ 1..=16) => Some(channel - 1),
+        Some(channel) => return Err(format!("MIDI channel {} is not 1-16", channel)),
+        None => None,
+    };
+    Ok(Some(MidiSettings {
+        port,
+        channel,
+        bend_range: parse_arg(args, "--bend-range")?.unwrap_or(MidiSettings::default().bend_range),
+    }))
+}
+
+/// `--drive soft|hard|tube|fold|crush` distorts the mix, or every voice
+/// with `--drive-per-voice`, in place of the patch's drive. Returns the
+/// settings and whether they are for every voice.
+fn drive_options(
+    args: &[String],
+    patch: &Patch,
+) -> Result<Option<(WaveshaperSettings, bool)>, String> {
+    let mode = match arg_value(args, "--drive") {
+        Some(name) => {
+            ShapeMode::from_name(name).ok_or_else(|| format!("unknown drive mode {}", name))?
+        }
+        None => return patch.drive(),
+    };
+    let defaults = WaveshaperSettings::default();
+    let drive = WaveshaperSettings {
+        mode,
+        pre_gain: parse_arg(args, "--drive-pre")?.unwrap_or(defaults.pre_gain),
+        post_gain: parse_arg(args, "--drive-post")?.unwrap_or(defaults.post_gain),
+        oversampling: parse_arg(args, "--oversample")?.unwrap_or(defaults.oversampling),
+        bits: parse_arg(args, "--bits")?.unwrap_or(defaults.bits),
+        downsample: parse_arg(args, "--downsample")?.unwrap_or(defaults.downsample),
+    };
+    let per_voice = args.iter().any(|arg| arg == "--drive-per-voice");
+    Ok(Some((drive, per_voice)))
+}
+
+/// `--<name> <mix>` with `--<name>-rate`, `--<name>-depth` and `--<name>-feedback`.
+fn modulation_options(
+    args: &[String],
+    name: &str,
+    defaults: ModulationSettings,
+) -> Result<ModulationSettings, String> {
+    let flag = |setting: &str| format!("--{}-{}", name, setting);
+    Ok(ModulationSettings {
+        rate: parse_arg(args, &flag("rate"))?.unwrap_or(defaults.rate),
+        depth: parse_arg(args, &flag("depth"))?.unwrap_or(defaults.depth),
+        feedback: parse_arg(args, &flag("feedback"))?.unwrap_or(defaults.feedback),
+        mix: parse_arg(args, &format!("--{}", name))?.unwrap_or(defaults.mix),
+    })
+}
 
-    let _result = stream_handle.play_raw(envelope.convert_samples());
+/// Parses the number following `name` on the command line, if it is there.
+fn parse_arg<T: FromStr>(args: &[String], name: &str) -> Result<Option<T>, String> {
+    match arg_value(args, name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid value {} for {}", value, name)),
+        None => Ok(None),
+    }
+}
+
+/// The value following `name` on the command line, e.g. the path in `--sfz path`.
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+}
+
+/// Every value following `name`, for options that can be given more than once.
+fn arg_values<'a>(args: &'a [String], name: &'a str) -> impl Iterator<Item = &'a String> {
+    args.windows(2)
+        .filter(move |pair| pair[0] == name)
+        .map(|pair| &pair[1])
+}
+
+/// Reads a song from an MML file, see `mml::compile`, or a MIDI file.
+fn load_song(path: &String) -> Result<Vec<TimedMessage>, String> {
+    if Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension == "mml")
+    {
+        return mml::load(path);
+    }
+    midi_file::load(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// `render <song.mid> --sf2 <bank.sf2> [-o <out.wav>] [--sample-rate <hz>]`
+fn render_midi_file(args: &[String]) {
+    let (midi_path, sf2_path) = match (args.get(2), arg_value(args, "--sf2")) {
+        (Some(midi_path), Some(sf2_path)) => (midi_path, sf2_path),
+        _ => {
+            eprintln!(
+                "usage: {} render <song.mid|song.mml> --sf2 <bank.sf2> [-o <out.wav>]",
+                args[0]
+            );
+            return;
+        }
+    };
+    let output_path = match arg_value(args, "-o") {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => Path::new(midi_path).with_extension("wav"),
+    };
+
+    let messages = match load_song(midi_path) {
+        Ok(messages) => messages,
+        Err(message) => {
+            eprintln!("{}", message);
+            return;
+        }
+    };
+    let sound_font = match sf2::load(sf2_path) {
+        Ok(sound_font) => sound_font,
+        Err(e) => {
+            eprintln!("{}: {}", sf2_path, e);
+            return;
+        }
+    };
+
+    let sample_rate = match parse_arg(args, "--sample-rate") {
+        Ok(sample_rate) => sample_rate.unwrap_or(SAMPLE_RATE),
+        Err(message) => {
+            eprintln!("{}", message);
+            return;
+        }
+    };
+
+    let tuning = match tuning_options(args) {
+        Ok(tuning) => tuning,
+        Err(message) => {
+            eprintln!("{}", message);
+            return;
+        }
+    };
+
+    let mut renderer = Renderer::new(&sound_font, sample_rate, tuning);
+    match renderer.render(&messages, &output_path) {
+        Ok(()) => println!("Rendered {}", output_path.display()),
+        Err(e) => eprintln!("{}: {}", output_path.display(), e),
+    }
+}
 
-    listen_for_keyboard(tx);
+fn print_status(octave: i32, transpose: i32) {
+    print!("Octave {} transpose {:+}\r\n", octave, transpose);
 }
 
-fn listen_for_keyboard(mut tx: mpsc::Sender<NoteEvent>) {
+fn listen_for_keyboard(
+    tx: Input,
+    keymap: &Keymap,
+    tuning: &Tuning,
+    parameters: &Parameters,
+    pattern_count: usize,
+    files: &mut Files,
+) {
     enable_raw_mode().unwrap();
-    let mut current_octave = 1.0;
+    let mut octave = 2;
+    let mut transpose = 0;
+    // keys have no velocity of their own, so every note is played with this
+    let mut velocity: u8 = 127;
+    let mut sustain = false;
+    // the key every held computer key plays, so it is released even after
+    // the octave or transpose has changed
+    let mut held: HashMap<KeyCode, u8> = HashMap::new();
+    let mut playing = false;
+    // the pattern cued last, None for the chain
+    let mut cued: Option<usize> = None;
+    print_status(octave, transpose);
     loop {
         match read().unwrap() {
             Event::Key(KeyEvent {
-                code: KeyCode::Char('q'),
+                code,
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) if code == keymap.quit => break,
+            // velocity, up and down arrows
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::Up | KeyCode::Down),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                state: KeyEventState::NONE,
+            }) => {
+                velocity = if code == KeyCode::Up {
+                    velocity.saturating_add(8).min(127)
+                } else {
+                    velocity.saturating_sub(8).max(1)
+                };
+                print!("Velocity {}\r\n", velocity);
+            }
+            // delay time, left and right arrows
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::Left | KeyCode::Right),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                state: KeyEventState::NONE,
+            }) => {
+                let step = if code == KeyCode::Right { 10.0 } else { -10.0 };
+                let delay_time = parameters.get(Parameter::DelayTime).unwrap_or(0.0);
+                let delay_time = (delay_time + step).clamp(10.0, 4000.0);
+                parameters.send(&tx, Parameter::DelayTime, delay_time);
+                print!("Delay {} ms\r\n", delay_time);
+            }
+            // sequencer start and stop, enter
+            Event::Key(KeyEvent {
+                code: KeyCode::Enter,
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) if pattern_count > 0 => {
+                playing = !playing;
+                let transport = if playing {
+                    Transport::Start
+                } else {
+                    Transport::Stop
+                };
+                tx.send(NoteEvent::Transport(transport)).unwrap();
+                print!(
+                    "Sequencer {}\r\n",
+                    if playing { "playing" } else { "stopped" }
+                );
+            }
+            // the pattern to play next, tab steps through the chain and every pattern
+            Event::Key(KeyEvent {
+                code: KeyCode::Tab,
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) if pattern_count > 0 => {
+                cued = match cued {
+                    None => Some(0),
+                    Some(pattern) if pattern + 1 < pattern_count => Some(pattern + 1),
+                    Some(_) => None,
+                };
+                tx.send(NoteEvent::Transport(Transport::Cue(cued))).unwrap();
+                match cued {
+                    Some(pattern) => print!("Next: pattern {}\r\n", pattern + 1),
+                    None => print!("Next: chain\r\n"),
+                }
+            }
+            // tempo, page up and page down, and swing, home and end
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press | KeyEventKind::Repeat,
+                state: KeyEventState::NONE,
+            }) if pattern_count > 0 => {
+                let (parameter, step, range) = match code {
+                    KeyCode::PageUp => (Parameter::Tempo, 1.0, 20.0..=400.0),
+                    KeyCode::PageDown => (Parameter::Tempo, -1.0, 20.0..=400.0),
+                    KeyCode::Home => (Parameter::Swing, -1.0, 50.0..=75.0),
+                    _ => (Parameter::Swing, 1.0, 50.0..=75.0),
+                };
+                let value = parameters.get(parameter).unwrap_or(0.0) + step;
+                let value = value.clamp(*range.start(), *range.end());
+                parameters.send(&tx, parameter, value);
+                if parameter == Parameter::Tempo {
+                    print!("Tempo {} BPM\r\n", value);
+                } else {
+                    print!("Swing {}%\r\n", value);
+                }
+            }
+            // recording to a MIDI file, F5
+            Event::Key(KeyEvent {
+                code: KeyCode::F(5),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) => files.recording.toggle(parameters),
+            // presets, F8 and F9 step back and on, F10 saves
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::F(8) | KeyCode::F(9)),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) => {
+                let step = if code == KeyCode::F(9) { 1 } else { -1 };
+                files.presets.lock().unwrap().step(step, &tx, parameters);
+            }
+            Event::Key(KeyEvent {
+                code: KeyCode::F(10),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) => files.presets.lock().unwrap().save(parameters),
+            // looper, F6 records and then turns overdubbing on and off, F7 clears
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::F(6) | KeyCode::F(7)),
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) => {
+                let (transport, message) = if code == KeyCode::F(6) {
+                    (Transport::Loop, "Loop: record or overdub")
+                } else {
+                    (Transport::ClearLoop, "Loop cleared")
+                };
+                tx.send(NoteEvent::Transport(transport)).unwrap();
+                print!("{}\r\n", message);
+            }
+            // sustain pedal, toggled by a key
+            Event::Key(KeyEvent {
+                code,
                 modifiers: KeyModifiers::NONE,
                 kind: KeyEventKind::Press,
                 state: KeyEventState::NONE,
-            }) => break,
+            }) if code == keymap.sustain => {
+                sustain = !sustain;
+                tx.send(NoteEvent::Pedal(Pedal::Sustain, sustain)).unwrap();
+                print!("Sustain {}\r\n", if sustain { "on" } else { "off" });
+            }
+            // octave and transpose, on press only so every key press is one step
+            Event::Key(KeyEvent {
+                code,
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }) if [
+                keymap.octave_down,
+                keymap.octave_up,
+                keymap.transpose_down,
+                keymap.transpose_up,
+            ]
+            .contains(&code) =>
+            {
+                if code == keymap.octave_down {
+                    octave = (octave - 1).max(*OCTAVES.start());
+                } else if code == keymap.octave_up {
+                    octave = (octave + 1).min(*OCTAVES.end());
+                } else if code == keymap.transpose_down {
+                    transpose = (transpose - 1).max(-MAX_TRANSPOSE);
+                } else {
+                    transpose = (transpose + 1).min(MAX_TRANSPOSE);
+                }
+                print_status(octave, transpose);
+            }
             // PRESS
             Event::Key(KeyEvent {
                 code: c,
@@ -48,17 +1346,13 @@ fn listen_for_keyboard(mut tx: mpsc::Sender<NoteEvent>) {
                 kind: KeyEventKind::Press,
                 state: KeyEventState::NONE,
             }) => {
-                if c == KeyCode::Char('9') {
-                    current_octave += 1.0;
-                } else if c == KeyCode::Char('8') {
-                    current_octave -= 1.0;
-                } else {
-                    print!("Press\r\n");
-                    let f = frequency_from_keycode(c, current_octave);
-                    match f {
-                        Some(f) => tx.send(NoteEvent::Press(f)).unwrap(),
-                        None => (),
-                    }
+                print!("Press\r\n");
+                let note = keymap
+                    .key(c, octave, transpose)
+                    .and_then(|key| tuning.note(key, velocity));
+                if let Some(note) = note {
+                    held.insert(c, note.key);
+                    tx.send(NoteEvent::Press(note)).unwrap();
                 }
             }
             // REPEAT
@@ -67,19 +1361,9 @@ fn listen_for_keyboard(mut tx: mpsc::Sender<NoteEvent>) {
                 modifiers: KeyModifiers::NONE,
                 kind: KeyEventKind::Repeat,
                 state: KeyEventState::NONE,
-            }) => {
-                if c == KeyCode::Char('9') {
-                    current_octave += 1.0;
-                } else if c == KeyCode::Char('8') {
-                    current_octave -= 1.0;
-                } else {
-                    print!("Repeat\r\n");
-                    let f = frequency_from_keycode(c, current_octave);
-                    match f {
-                        Some(f) => tx.send(NoteEvent::Hold).unwrap(),
-                        None => (),
-                    }
-                }
+            }) if held.contains_key(&c) => {
+                print!("Repeat\r\n");
+                tx.send(NoteEvent::Hold).unwrap();
             }
             // RELEASE
             Event::Key(KeyEvent {
@@ -88,16 +1372,11 @@ fn listen_for_keyboard(mut tx: mpsc::Sender<NoteEvent>) {
                 kind: KeyEventKind::Release,
                 state: KeyEventState::NONE,
             }) => {
-                if c == KeyCode::Char('9') {
-                    current_octave += 1.0;
-                } else if c == KeyCode::Char('8') {
-                    current_octave -= 1.0;
-                } else {
-                    println!("Release");
-                    let f = frequency_from_keycode(c, current_octave);
-                    match f {
-                        Some(f) => tx.send(NoteEvent::Up).unwrap(),
-                        None => (),
+                if let Some(key) = held.remove(&c) {
+                    print!("Release\r\n");
+                    // another held computer key may play the same note
+                    if !held.values().any(|&other| other == key) {
+                        tx.send(NoteEvent::Up(key)).unwrap();
                     }
                 }
             }
@@ -0,0 +1,19 @@
+use std::sync::Arc;
+
+use crate::atomic_f32::AtomicF32;
+
+/// A level measured on the audio thread and read by the user interface.
+#[derive(Clone, Default)]
+pub struct Meter {
+    value: Arc<AtomicF32>,
+}
+
+impl Meter {
+    pub fn set(&self, value: f32) {
+        self.value.set(value);
+    }
+
+    pub fn get(&self) -> f32 {
+        self.value.get()
+    }
+}
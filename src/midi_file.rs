@@ -0,0 +1,475 @@
+//! Reader for Standard MIDI Files (format 0 and 1), and a writer for format 0.
+use std::{fmt, fs, io, path::Path};
+
+pub enum MidiFileError {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for MidiFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiFileError::Io(e) => write!(f, "could not read midi file: {}", e),
+            MidiFileError::Format(message) => write!(f, "invalid midi file: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for MidiFileError {
+    fn from(e: io::Error) -> Self {
+        MidiFileError::Io(e)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        key: u8,
+    },
+    Controller {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// Bend wheel position from -1.0 to 1.0.
+    PitchBend {
+        channel: u8,
+        value: f32,
+    },
+    /// Aftertouch from 0.0 to 1.0.
+    Pressure {
+        channel: u8,
+        value: f32,
+    },
+}
+
+/// Number of data bytes following a channel message status byte.
+pub fn data_length(status: u8) -> usize {
+    match status & 0xf0 {
+        0xc0 | 0xd0 => 1,
+        _ => 2,
+    }
+}
+
+impl MidiMessage {
+    /// Decodes a channel message from its status byte and the
+    /// `data_length(status)` data bytes after it.
+    pub fn from_bytes(status: u8, data: &[u8]) -> Option<MidiMessage> {
+        let channel = status & 0x0f;
+        let first = *data.first()? & 0x7f;
+        let second = data.get(1).map(|byte| byte & 0x7f);
+        match status & 0xf0 {
+            0x80 => Some(MidiMessage::NoteOff {
+                channel,
+                key: first,
+            }),
+            0x90 => match second? {
+                0 => Some(MidiMessage::NoteOff {
+                    channel,
+                    key: first,
+                }),
+                velocity => Some(MidiMessage::NoteOn {
+                    channel,
+                    key: first,
+                    velocity,
+                }),
+            },
+            // polyphonic aftertouch, the voices only know one pressure per channel
+            0xa0 => Some(MidiMessage::Pressure {
+                channel,
+                value: second? as f32 / 127.0,
+            }),
+            0xb0 => Some(MidiMessage::Controller {
+                channel,
+                controller: first,
+                value: second?,
+            }),
+            0xc0 => Some(MidiMessage::ProgramChange {
+                channel,
+                program: first,
+            }),
+            0xd0 => Some(MidiMessage::Pressure {
+                channel,
+                value: first as f32 / 127.0,
+            }),
+            0xe0 => {
+                let value = ((second? as i32) << 7 | first as i32) - 8192;
+                Some(MidiMessage::PitchBend {
+                    channel,
+                    value: value as f32 / 8192.0,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MidiMessage {
+    /// The status and data bytes of the message.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let data_byte = |value: f32| (value.clamp(0.0, 1.0) * 127.0).round() as u8;
+        match self {
+            MidiMessage::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => vec![0x90 | channel, key, velocity],
+            MidiMessage::NoteOff { channel, key } => vec![0x80 | channel, key, 0],
+            MidiMessage::Controller {
+                channel,
+                controller,
+                value,
+            } => vec![0xb0 | channel, controller, value],
+            MidiMessage::ProgramChange { channel, program } => vec![0xc0 | channel, program],
+            MidiMessage::PitchBend { channel, value } => {
+                let value = ((value.clamp(-1.0, 1.0) * 8192.0) as i32 + 8192).min(16383);
+                vec![0xe0 | channel, (value & 0x7f) as u8, (value >> 7) as u8]
+            }
+            MidiMessage::Pressure { channel, value } => vec![0xd0 | channel, data_byte(value)],
+        }
+    }
+}
+
+/// A message and the time it happens at, in seconds from the start.
+#[derive(Copy, Clone)]
+pub struct TimedMessage {
+    pub time: f64,
+    pub message: MidiMessage,
+}
+
+/// Reads all channel messages of a file, merged across tracks and sorted by time.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<TimedMessage>, MidiFileError> {
+    parse(&fs::read(path)?)
+}
+
+/// Ticks per quarter note in written files.
+const WRITE_DIVISION: u16 = 480;
+
+fn write_variable_length(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Writes messages sorted by time to a single track file at `tempo` beats per
+/// minute, so the bars line up when the file is opened elsewhere.
+pub fn save<P: AsRef<Path>>(path: P, messages: &[TimedMessage], tempo: f32) -> io::Result<()> {
+    let microseconds_per_quarter = (60_000_000.0 / tempo.max(1.0)) as u32;
+    let ticks_per_second = WRITE_DIVISION as f64 * 1_000_000.0 / microseconds_per_quarter as f64;
+
+    let mut track = vec![0x00, 0xff, 0x51, 0x03];
+    track.extend_from_slice(&microseconds_per_quarter.to_be_bytes()[1..]);
+    let mut last_tick = 0;
+    for timed in messages.iter() {
+        let tick = (timed.time.max(0.0) * ticks_per_second).round() as u32;
+        write_variable_length(&mut track, tick.saturating_sub(last_tick));
+        last_tick = tick.max(last_tick);
+        track.extend(timed.message.to_bytes());
+    }
+    track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+    let mut file = b"MThd".to_vec();
+    file.extend_from_slice(&6u32.to_be_bytes());
+    // format 0, one track
+    file.extend_from_slice(&[0, 0, 0, 1]);
+    file.extend_from_slice(&WRITE_DIVISION.to_be_bytes());
+    file.extend_from_slice(b"MTrk");
+    file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    file.extend(track);
+    fs::write(path, file)
+}
+
+pub fn parse(data: &[u8]) -> Result<Vec<TimedMessage>, MidiFileError> {
+    let mut reader = Reader { data, position: 0 };
+
+    if reader.bytes(4)? != b"MThd" {
+        return Err(format_error("missing MThd header"));
+    }
+    let header_length = reader.u32()? as usize;
+    let header = Reader {
+        data: reader.bytes(header_length)?,
+        position: 0,
+    };
+    if header.data.len() < 6 {
+        return Err(format_error("short MThd header"));
+    }
+    let track_count = u16::from_be_bytes([header.data[2], header.data[3]]);
+    let division = u16::from_be_bytes([header.data[4], header.data[5]]);
+
+    // (tick, message) for channel messages, (tick, microseconds per quarter) for tempo changes
+    let mut messages: Vec<(u64, MidiMessage)> = Vec::new();
+    let mut tempo_changes: Vec<(u64, u32)> = Vec::new();
+
+    for _ in 0..track_count {
+        if reader.position >= reader.data.len() {
+            break;
+        }
+        let chunk_type = reader.bytes(4)?;
+        let chunk_length = reader.u32()? as usize;
+        let chunk = reader.bytes(chunk_length)?;
+        if chunk_type == b"MTrk" {
+            read_track(chunk, &mut messages, &mut tempo_changes)?;
+        }
+    }
+
+    // stable sorts keep the order of simultaneous events within a track
+    messages.sort_by_key(|(tick, _)| *tick);
+    tempo_changes.sort_by_key(|(tick, _)| *tick);
+
+    let seconds_per_tick = |microseconds_per_quarter: u32| -> f64 {
+        if division & 0x8000 != 0 {
+            // SMPTE timing: frames per second and ticks per frame
+            let frames_per_second = -((division >> 8) as i8) as f64;
+            let ticks_per_frame = (division & 0xff) as f64;
+            1.0 / (frames_per_second * ticks_per_frame)
+        } else {
+            microseconds_per_quarter as f64 / 1_000_000.0 / division.max(1) as f64
+        }
+    };
+
+    let mut result = Vec::with_capacity(messages.len());
+    let mut tempo_index = 0;
+    let mut tempo = 500_000; // 120 bpm until told otherwise
+    let mut last_tick = 0;
+    let mut time = 0.0;
+    for (tick, message) in messages {
+        // walk through the tempo changes between the previous message and this one
+        while tempo_index < tempo_changes.len() && tempo_changes[tempo_index].0 <= tick {
+            let (change_tick, new_tempo) = tempo_changes[tempo_index];
+            time += (change_tick - last_tick) as f64 * seconds_per_tick(tempo);
+            last_tick = change_tick;
+            tempo = new_tempo;
+            tempo_index += 1;
+        }
+        time += (tick - last_tick) as f64 * seconds_per_tick(tempo);
+        last_tick = tick;
+        result.push(TimedMessage { time, message });
+    }
+    Ok(result)
+}
+
+fn read_track(
+    data: &[u8],
+    messages: &mut Vec<(u64, MidiMessage)>,
+    tempo_changes: &mut Vec<(u64, u32)>,
+) -> Result<(), MidiFileError> {
+    let mut reader = Reader { data, position: 0 };
+    let mut tick = 0;
+    let mut running_status = 0;
+
+    while reader.position < data.len() {
+        tick += reader.variable_length()? as u64;
+
+        let mut status = reader.u8()?;
+        if status < 0x80 {
+            // running status, the byte we read is the first data byte
+            status = running_status;
+            reader.position -= 1;
+        }
+
+        match status {
+            0xff => {
+                let meta_type = reader.u8()?;
+                let length = reader.variable_length()? as usize;
+                let meta = reader.bytes(length)?;
+                if meta_type == 0x51 && length == 3 {
+                    let tempo = u32::from_be_bytes([0, meta[0], meta[1], meta[2]]);
+                    tempo_changes.push((tick, tempo));
+                } else if meta_type == 0x2f {
+                    break;
+                }
+            }
+            0xf0 | 0xf7 => {
+                let length = reader.variable_length()? as usize;
+                reader.bytes(length)?;
+            }
+            0x80..=0xef => {
+                running_status = status;
+                let data = reader.bytes(data_length(status))?;
+                if let Some(message) = MidiMessage::from_bytes(status, data) {
+                    messages.push((tick, message));
+                }
+            }
+            _ => {
+                return Err(format_error(&format!(
+                    "unexpected status byte {:#x}",
+                    status
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn format_error(message: &str) -> MidiFileError {
+    MidiFileError::Format(message.to_string())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], MidiFileError> {
+        let end = self.position + count;
+        if end > self.data.len() {
+            return Err(format_error("unexpected end of data"));
+        }
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiFileError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiFileError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn variable_length(&mut self) -> Result<u32, MidiFileError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(format_error("variable length quantity too long"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timed(time: f64, message: MidiMessage) -> TimedMessage {
+        TimedMessage { time, message }
+    }
+
+    /// A file with a single track of `track` at 96 ticks per quarter note.
+    fn file(track: &[u8]) -> Vec<u8> {
+        let mut data = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+        data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        data.extend_from_slice(track);
+        data
+    }
+
+    #[test]
+    fn saved_files_load_the_same() {
+        let messages = [
+            timed(
+                0.0,
+                MidiMessage::ProgramChange {
+                    channel: 0,
+                    program: 5,
+                },
+            ),
+            timed(
+                0.0,
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    key: 60,
+                    velocity: 100,
+                },
+            ),
+            timed(
+                0.25,
+                MidiMessage::Controller {
+                    channel: 0,
+                    controller: 64,
+                    value: 127,
+                },
+            ),
+            timed(
+                0.5,
+                MidiMessage::PitchBend {
+                    channel: 0,
+                    value: -0.5,
+                },
+            ),
+            timed(
+                0.75,
+                MidiMessage::Pressure {
+                    channel: 0,
+                    value: 1.0,
+                },
+            ),
+            timed(
+                1.0,
+                MidiMessage::NoteOff {
+                    channel: 0,
+                    key: 60,
+                },
+            ),
+            // long enough to need more than one byte of delta time
+            timed(
+                61.0,
+                MidiMessage::NoteOn {
+                    channel: 0,
+                    key: 62,
+                    velocity: 1,
+                },
+            ),
+        ];
+        let path = std::env::temp_dir().join("rust_synth_midi_file_round_trip.mid");
+        save(&path, &messages, 90.0).unwrap();
+        let loaded = load(&path).ok().unwrap();
+
+        assert_eq!(loaded.len(), messages.len());
+        for (saved, loaded) in messages.iter().zip(loaded.iter()) {
+            // times are rounded to ticks, 1/720 s at 90 bpm
+            assert!((saved.time - loaded.time).abs() < 0.001);
+            assert_eq!(saved.message.to_bytes(), loaded.message.to_bytes());
+        }
+    }
+
+    #[test]
+    fn running_status_and_tempo_changes() {
+        let data = file(&[
+            0x00, 0x90, 60, 100, // note on at tick 0
+            0x60, 62, 100, // running status, a quarter later
+            0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 60 bpm from here
+            0x60, 0x90, 60, 0, // velocity 0 is a note off
+            0x00, 0xff, 0x2f, 0x00,
+        ]);
+        let messages = parse(&data).ok().unwrap();
+        let times: Vec<f64> = messages.iter().map(|timed| timed.time).collect();
+        assert_eq!(times, [0.0, 0.5, 1.5]);
+        match messages[1].message {
+            MidiMessage::NoteOn { key: 62, .. } => {}
+            _ => panic!("running status not followed"),
+        }
+        match messages[2].message {
+            MidiMessage::NoteOff { key: 60, .. } => {}
+            _ => panic!("velocity 0 not read as a note off"),
+        }
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        assert!(parse(b"MTrk").is_err());
+        let mut data = file(&[0x00, 0x90, 60, 100, 0x00, 0xff, 0x2f, 0x00]);
+        data.truncate(data.len() - 5);
+        assert!(parse(&data).is_err());
+        assert!(parse(&file(&[0x00, 0xf4])).is_err());
+    }
+}
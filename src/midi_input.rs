@@ -0,0 +1,163 @@
+use midir::{MidiInput, MidiInputConnection};
+
+use crate::midi_file::{data_length, MidiMessage};
+use crate::musical_keyboard::{NoteEvent, Pedal};
+use crate::recorder::Input;
+use crate::tuning::Tuning;
+
+/// Name of the synth on the ALSA sequencer, as shown by `aconnect -l`.
+const CLIENT_NAME: &str = "rust_synth";
+
+#[derive(Clone)]
+pub struct MidiSettings {
+    /// Connect to the first input port with this in its name. Without it a
+    /// virtual port is created for other programs to connect to.
+    pub port: Option<String>,
+    /// Only listen to this channel, 0 to 15. Every channel when None.
+    pub channel: Option<u8>,
+    /// Semitones the pitch bend wheel reaches at either end.
+    pub bend_range: f32,
+}
+
+impl Default for MidiSettings {
+    fn default() -> Self {
+        MidiSettings {
+            port: None,
+            channel: None,
+            bend_range: 2.0,
+        }
+    }
+}
+
+/// Turns incoming MIDI messages into note events.
+struct Translator {
+    sender: Input,
+    settings: MidiSettings,
+    tuning: Tuning,
+}
+
+impl Translator {
+    fn handle_bytes(&mut self, bytes: &[u8]) {
+        let (&status, data) = match bytes.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        if status == 0xf0 {
+            let message = data.strip_suffix(&[0xf7]).unwrap_or(data);
+            if let Some(name) = self.tuning.apply_mts(message) {
+                if !name.is_empty() {
+                    print!("Tuning {}\r\n", name);
+                }
+            }
+            return;
+        }
+        // system messages such as clock and active sensing are of no interest
+        if !(0x80..=0xef).contains(&status) || data.len() < data_length(status) {
+            return;
+        }
+        if let Some(message) = MidiMessage::from_bytes(status, data) {
+            if let Some(event) = self.event(message) {
+                let _ = self.sender.send(event);
+            }
+        }
+    }
+
+    fn event(&self, message: MidiMessage) -> Option<NoteEvent> {
+        let channel = match message {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::Controller { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::PitchBend { channel, .. }
+            | MidiMessage::Pressure { channel, .. } => channel,
+        };
+        if self.settings.channel.is_some_and(|only| only != channel) {
+            return None;
+        }
+
+        match message {
+            MidiMessage::NoteOn { key, velocity, .. } => {
+                self.tuning.note(key, velocity).map(NoteEvent::Press)
+            }
+            MidiMessage::NoteOff { key, .. } => Some(NoteEvent::Up(key)),
+            MidiMessage::PitchBend { value, .. } => {
+                Some(NoteEvent::PitchBend(value * self.settings.bend_range))
+            }
+            MidiMessage::Pressure { value, .. } => Some(NoteEvent::Pressure(value)),
+            MidiMessage::Controller {
+                controller, value, ..
+            } => match controller {
+                1 => Some(NoteEvent::Modulation(value as f32 / 127.0)),
+                64 => Some(NoteEvent::Pedal(Pedal::Sustain, value >= 64)),
+                66 => Some(NoteEvent::Pedal(Pedal::Sostenuto, value >= 64)),
+                _ => None,
+            },
+            MidiMessage::ProgramChange { .. } => None,
+        }
+    }
+}
+
+/// Starts listening for MIDI, sending what is played to `sender` from the
+/// MIDI thread. Input stops when the returned connection is dropped.
+pub fn connect(
+    settings: &MidiSettings,
+    tuning: Tuning,
+    sender: Input,
+) -> Result<MidiInputConnection<()>, String> {
+    let input = MidiInput::new(CLIENT_NAME).map_err(|e| format!("MIDI input: {}", e))?;
+    let mut translator = Translator {
+        sender,
+        settings: settings.clone(),
+        tuning,
+    };
+    let callback = move |_time: u64, bytes: &[u8], _: &mut ()| translator.handle_bytes(bytes);
+
+    match &settings.port {
+        Some(name) => {
+            let ports = input.ports();
+            let port = ports
+                .iter()
+                .find(|port| {
+                    input
+                        .port_name(port)
+                        .is_ok_and(|port_name| port_name.contains(name.as_str()))
+                })
+                .ok_or_else(|| {
+                    let names: Vec<String> = ports
+                        .iter()
+                        .filter_map(|port| input.port_name(port).ok())
+                        .collect();
+                    format!(
+                        "no MIDI input port matching {}, found: {}",
+                        name,
+                        names.join(", ")
+                    )
+                })?
+                .clone();
+            input
+                .connect(&port, "input", callback, ())
+                .map_err(|e| format!("MIDI input {}: {}", name, e))
+        }
+        None => create_virtual(input, callback),
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual<F>(input: MidiInput, callback: F) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+
+    input
+        .create_virtual("input", callback, ())
+        .map_err(|e| format!("MIDI input: {}", e))
+}
+
+#[cfg(not(unix))]
+fn create_virtual<F>(_input: MidiInput, _callback: F) -> Result<MidiInputConnection<()>, String>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    Err("virtual MIDI ports are not supported here, pick a port with --midi-port".to_string())
+}
@@ -0,0 +1,481 @@
+//! Compiler for Music Macro Language, melodies written as text such as
+//! `t120 o4 l8 cdefgab>c`.
+use std::{fs, path::Path};
+
+use crate::midi_file::{MidiMessage, TimedMessage};
+
+const DEFAULT_TEMPO: f64 = 120.0;
+const DEFAULT_OCTAVE: i32 = 4;
+const DEFAULT_VELOCITY: u8 = 100;
+/// Notes are held for 7/8 of their length unless `q` says otherwise.
+const DEFAULT_GATE: u32 = 7;
+const MAX_CHANNELS: usize = 16;
+
+/// A note as written, in beats from the start of the piece.
+struct Written {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    start: f64,
+    end: f64,
+}
+
+/// The notes of the last note or chord, which a tie lengthens.
+struct Tied {
+    /// Indices into the written notes.
+    first: usize,
+    start: f64,
+    /// Beats as written, before the gate shortens them.
+    length: f64,
+    keys: Vec<u8>,
+}
+
+/// Everything written in a piece, in beats.
+#[derive(Default)]
+struct Piece {
+    notes: Vec<Written>,
+    /// Beat, channel and program.
+    programs: Vec<(f64, u8, u8)>,
+    /// Beat and beats per minute.
+    tempos: Vec<(f64, f64)>,
+}
+
+/// Reads one channel, up to the next `;`.
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+    channel: u8,
+    octave: i32,
+    /// Default note length in beats.
+    length: f64,
+    velocity: u8,
+    /// Eighths of the length that notes are held.
+    gate: u32,
+    beat: f64,
+    tied: Option<Tied>,
+    /// A `&` was read and the next note continues the last one.
+    tie: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.position).map(u8::to_ascii_lowercase)
+    }
+
+    fn error(&self, message: &str) -> String {
+        let before = &self.text[..self.position.min(self.text.len())];
+        let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+        let column = before.iter().rev().take_while(|&&c| c != b'\n').count() + 1;
+        format!("line {} column {}: {}", line, column, message)
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.position])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// A value such as `o4` or `v90` that has to be within `range`.
+    fn value(&mut self, range: std::ops::RangeInclusive<u32>) -> Result<u32, String> {
+        match self.number() {
+            Some(value) if range.contains(&value) => Ok(value),
+            _ => Err(self.error(&format!(
+                "expected a number from {} to {}",
+                range.start(),
+                range.end()
+            ))),
+        }
+    }
+
+    /// A length such as `8` or `4.` in beats, the default length when none
+    /// is written.
+    fn note_length(&mut self) -> Result<f64, String> {
+        let mut length = match self.number() {
+            Some(0) => return Err(self.error("note length 0")),
+            Some(division) => 4.0 / division as f64,
+            None => self.length,
+        };
+        let mut dot = length / 2.0;
+        while self.peek() == Some(b'.') {
+            self.position += 1;
+            length += dot;
+            dot /= 2.0;
+        }
+        Ok(length)
+    }
+
+    /// A note name with its sharps and flats, as a key in the current octave.
+    fn key(&mut self, octave: i32) -> Result<u8, String> {
+        let semitone = match self.peek() {
+            Some(b'c') => 0,
+            Some(b'd') => 2,
+            Some(b'e') => 4,
+            Some(b'f') => 5,
+            Some(b'g') => 7,
+            Some(b'a') => 9,
+            Some(b'b') => 11,
+            _ => return Err(self.error("expected a note")),
+        };
+        self.position += 1;
+        let mut key = 12 * (octave + 1) + semitone;
+        loop {
+            match self.peek() {
+                Some(b'+') | Some(b'#') => key += 1,
+                Some(b'-') => key -= 1,
+                _ => break,
+            }
+            self.position += 1;
+        }
+        if !(0..=127).contains(&key) {
+            return Err(self.error("note out of range"));
+        }
+        Ok(key as u8)
+    }
+
+    /// Writes a note or chord at the current beat and moves past it.
+    fn play(&mut self, keys: Vec<u8>, length: f64, piece: &mut Piece) {
+        let gate = self.gate as f64 / 8.0;
+        let tie = self.tie;
+        match self.tied.as_mut() {
+            // a tie to the same notes lengthens them
+            Some(tied) if tie && tied.keys == keys => {
+                tied.length += length;
+                for note in piece.notes[tied.first..].iter_mut() {
+                    note.end = tied.start + tied.length * gate;
+                }
+            }
+            tied => {
+                // a tie to other notes plays them without a gap
+                if let Some(tied) = tied.filter(|_| tie) {
+                    for note in piece.notes[tied.first..].iter_mut() {
+                        note.end = tied.start + tied.length;
+                    }
+                }
+                let first = piece.notes.len();
+                for &key in keys.iter() {
+                    piece.notes.push(Written {
+                        channel: self.channel,
+                        key,
+                        velocity: self.velocity,
+                        start: self.beat,
+                        end: self.beat + length * gate,
+                    });
+                }
+                self.tied = Some(Tied {
+                    first,
+                    start: self.beat,
+                    length,
+                    keys,
+                });
+            }
+        }
+        self.tie = false;
+        self.beat += length;
+    }
+
+    /// Reads up to the end of the channel.
+    fn parse(&mut self, piece: &mut Piece) -> Result<(), String> {
+        while let Some(c) = self.peek() {
+            if c == b';' {
+                break;
+            }
+            if c.is_ascii_whitespace() || c == b'|' {
+                self.position += 1;
+                continue;
+            }
+            if (b'a'..=b'g').contains(&c) {
+                let key = self.key(self.octave)?;
+                let length = self.note_length()?;
+                self.play(vec![key], length, piece);
+                continue;
+            }
+            self.position += 1;
+            match c {
+                b'r' => {
+                    self.beat += self.note_length()?;
+                    self.tied = None;
+                    self.tie = false;
+                }
+                b'[' => {
+                    // octave changes inside a chord only last until its end
+                    let mut octave = self.octave;
+                    let mut keys = Vec::new();
+                    loop {
+                        match self.peek() {
+                            Some(b']') => break,
+                            Some(b'<') => octave -= 1,
+                            Some(b'>') => octave += 1,
+                            Some(c) if c.is_ascii_whitespace() => {}
+                            None => return Err(self.error("chord without ]")),
+                            Some(_) => {
+                                keys.push(self.key(octave)?);
+                                continue;
+                            }
+                        }
+                        self.position += 1;
+                    }
+                    self.position += 1;
+                    if keys.is_empty() {
+                        return Err(self.error("empty chord"));
+                    }
+                    let length = self.note_length()?;
+                    self.play(keys, length, piece);
+                }
+                b'&' => self.tie = true,
+                b'^' => {
+                    let length = self.note_length()?;
+                    match self.tied.as_ref() {
+                        Some(tied) => {
+                            let keys = tied.keys.clone();
+                            self.tie = true;
+                            self.play(keys, length, piece);
+                        }
+                        None => return Err(self.error("^ without a note before it")),
+                    }
+                }
+                b'o' => self.octave = self.value(0..=9)? as i32,
+                b'<' => self.octave -= 1,
+                b'>' => self.octave += 1,
+                b'l' => self.length = self.note_length()?,
+                b't' => piece.tempos.push((self.beat, self.value(20..=400)? as f64)),
+                b'v' => self.velocity = self.value(1..=127)? as u8,
+                b'q' => self.gate = self.value(1..=8)?,
+                b'@' => {
+                    let program = self.value(0..=127)? as u8;
+                    piece.programs.push((self.beat, self.channel, program));
+                }
+                _ => {
+                    self.position -= 1;
+                    return Err(self.error(&format!("unknown command {}", c as char)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Seconds from the start at a beat, following the tempo changes.
+fn seconds(tempos: &[(f64, f64)], beat: f64) -> f64 {
+    let mut seconds = 0.0;
+    let mut last_beat = 0.0;
+    let mut tempo = DEFAULT_TEMPO;
+    for &(at, new_tempo) in tempos.iter().take_while(|(at, _)| *at < beat) {
+        seconds += (at - last_beat) * 60.0 / tempo;
+        last_beat = at;
+        tempo = new_tempo;
+    }
+    seconds + (beat - last_beat) * 60.0 / tempo
+}
+
+/// Compiles MML into MIDI messages, the same ones a MIDI file is read into.
+///
+/// - `c d e f g a b` are notes, followed by `+` or `#` for sharp and `-`
+///   for flat, then a length (`4` is a quarter note) and dots
+/// - `r` is a rest, with a length like a note
+/// - `[ceg]` is a chord, with a length after the `]`
+/// - `&` ties a note to the next one, `^8` lengthens the last note
+/// - `o4` sets the octave, `<` and `>` go down and up one; o4 starts at
+///   middle C
+/// - `l8` sets the length of notes written without one
+/// - `t120` sets the tempo of every channel, `v100` the velocity, `q7`
+///   how many eighths of its length a note is held and `@0` the program
+/// - `;` starts the next channel, up to 16
+pub fn compile(text: &str) -> Result<Vec<TimedMessage>, String> {
+    let mut piece = Piece::default();
+    let mut position = 0;
+    for channel in 0..MAX_CHANNELS {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            position,
+            channel: channel as u8,
+            octave: DEFAULT_OCTAVE,
+            length: 1.0,
+            velocity: DEFAULT_VELOCITY,
+            gate: DEFAULT_GATE,
+            beat: 0.0,
+            tied: None,
+            tie: false,
+        };
+        parser.parse(&mut piece)?;
+        // past the `;`
+        position = parser.position + 1;
+        if position > text.len() {
+            break;
+        }
+    }
+    // a `;` after the last channel is fine as long as nothing follows it
+    if text
+        .get(position..)
+        .is_some_and(|rest| !rest.trim().is_empty())
+    {
+        return Err(format!("more than {} channels", MAX_CHANNELS));
+    }
+
+    piece
+        .tempos
+        .sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let time = |beat| seconds(&piece.tempos, beat);
+    // (time, order, message), releases first so a repeated note is played again
+    let mut messages = Vec::new();
+    for &(beat, channel, program) in piece.programs.iter() {
+        messages.push((
+            time(beat),
+            1,
+            MidiMessage::ProgramChange { channel, program },
+        ));
+    }
+    for note in piece.notes.iter() {
+        let (channel, key) = (note.channel, note.key);
+        messages.push((
+            time(note.start),
+            2,
+            MidiMessage::NoteOn {
+                channel,
+                key,
+                velocity: note.velocity,
+            },
+        ));
+        messages.push((time(note.end), 0, MidiMessage::NoteOff { channel, key }));
+    }
+    messages.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.1.cmp(&b.1))
+    });
+    Ok(messages
+        .into_iter()
+        .map(|(time, _, message)| TimedMessage { time, message })
+        .collect())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<TimedMessage>, String> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    compile(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (time, channel, key, true for note on) of every note message.
+    fn notes(text: &str) -> Vec<(f64, u8, u8, bool)> {
+        compile(text)
+            .unwrap()
+            .into_iter()
+            .filter_map(|timed| match timed.message {
+                MidiMessage::NoteOn { channel, key, .. } => Some((timed.time, channel, key, true)),
+                MidiMessage::NoteOff { channel, key } => Some((timed.time, channel, key, false)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn notes_are_held_for_their_gate() {
+        // a quarter note is half a second at 120 bpm, held for 7/8 of it
+        assert_eq!(
+            notes("c d8 r8 >c"),
+            [
+                (0.0, 0, 60, true),
+                (0.4375, 0, 60, false),
+                (0.5, 0, 62, true),
+                (0.71875, 0, 62, false),
+                (1.0, 0, 72, true),
+                (1.4375, 0, 72, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_join_notes() {
+        // a tie to the same note lengthens it, to another note leaves no gap
+        assert_eq!(
+            notes("c&c d&e ^4"),
+            [
+                (0.0, 0, 60, true),
+                (0.875, 0, 60, false),
+                (1.0, 0, 62, true),
+                (1.5, 0, 62, false),
+                (1.5, 0, 64, true),
+                (2.375, 0, 64, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn chords_start_together() {
+        assert_eq!(
+            notes("o3 [c>e-g]2 c"),
+            [
+                (0.0, 0, 48, true),
+                (0.0, 0, 63, true),
+                (0.0, 0, 67, true),
+                (0.875, 0, 48, false),
+                (0.875, 0, 63, false),
+                (0.875, 0, 67, false),
+                // the octave change ended with the chord
+                (1.0, 0, 48, true),
+                (1.4375, 0, 48, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn channels_play_at_once() {
+        assert_eq!(
+            notes("q8 c; q8 e; q8 @3 g"),
+            [
+                (0.0, 0, 60, true),
+                (0.0, 1, 64, true),
+                (0.0, 2, 67, true),
+                (0.5, 0, 60, false),
+                (0.5, 1, 64, false),
+                (0.5, 2, 67, false),
+            ]
+        );
+        let programs: Vec<(u8, u8)> = compile("c; @3 g")
+            .unwrap()
+            .into_iter()
+            .filter_map(|timed| match timed.message {
+                MidiMessage::ProgramChange { channel, program } => Some((channel, program)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(programs, [(1, 3)]);
+    }
+
+    #[test]
+    fn tempo_changes_apply_to_every_channel() {
+        // one beat at 60 bpm, then 240 bpm on both channels
+        assert_eq!(
+            notes("q8 t60 c t240 c; q8 r c"),
+            [
+                (0.0, 0, 60, true),
+                (1.0, 0, 60, false),
+                (1.0, 0, 60, true),
+                (1.0, 1, 60, true),
+                (1.25, 0, 60, false),
+                (1.25, 1, 60, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_give_where_they_are() {
+        assert_eq!(
+            compile("cde\n  x").err().unwrap(),
+            "line 2 column 3: unknown command x"
+        );
+        assert!(compile("[ceg").err().unwrap().contains("chord without ]"));
+        assert!(compile("^4").is_err());
+        assert!(compile("t10 c").is_err());
+        assert!(compile(&format!("{}c", "c;".repeat(16))).is_err());
+        assert!(compile(&format!("{}\n", "c;".repeat(16))).is_ok());
+    }
+}
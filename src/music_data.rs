@@ -1,5 +1,4 @@
-use std::sync::Arc;
-
+use crate::envvelope::EnvelopeSettings;
 use crate::musical_keyboard::NoteEvent;
 use rodio::Sample;
 
@@ -7,6 +6,14 @@ use rodio::Sample;
 pub struct MusicData {
     pub current_event: Option<NoteEvent>,
     pub wave_data: f32,
+    /// Envelope to use for the note started by `current_event`, for sources
+    /// that carry their own per-note envelope (e.g. sampler regions).
+    pub envelope: Option<EnvelopeSettings>,
+    /// Stereo position of the voice, -1.0 (left) to 1.0 (right).
+    pub pan: f32,
+    /// Right channel of voices that are stereo by themselves. When set,
+    /// `wave_data` is the left channel and `pan` is not used.
+    pub right: Option<f32>,
 }
 
 unsafe impl rodio::cpal::Sample for MusicData {
@@ -24,7 +31,7 @@ unsafe impl rodio::cpal::Sample for MusicData {
         self.wave_data as u16
     }
 
-    fn from<S>(s: &S) -> Self
+    fn from<S>(_s: &S) -> Self
     where
         S: rodio::cpal::Sample,
     {
@@ -39,6 +46,9 @@ impl Sample for MusicData {
         MusicData {
             current_event: first.current_event,
             wave_data,
+            envelope: first.envelope,
+            pan: first.pan,
+            right: first.right,
         }
     }
 
@@ -46,6 +56,9 @@ impl Sample for MusicData {
         MusicData {
             current_event: self.current_event,
             wave_data: self.wave_data * value,
+            envelope: self.envelope,
+            pan: self.pan,
+            right: self.right.map(|right| right * value),
         }
     }
 
@@ -53,6 +66,14 @@ impl Sample for MusicData {
         MusicData {
             current_event: self.current_event,
             wave_data: self.wave_data + other.wave_data,
+            envelope: self.envelope,
+            pan: self.pan,
+            right: match (self.right, other.right) {
+                (None, None) => None,
+                (right, other_right) => {
+                    Some(right.unwrap_or(self.wave_data) + other_right.unwrap_or(other.wave_data))
+                }
+            },
         }
     }
 
@@ -60,6 +81,9 @@ impl Sample for MusicData {
         MusicData {
             current_event: None,
             wave_data: 0.0,
+            envelope: None,
+            pan: 0.0,
+            right: None,
         }
     }
 }
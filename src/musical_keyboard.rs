@@ -1,24 +1,6 @@
-use crossterm::event::KeyCode;
+use crate::parameter::Parameter;
+use crate::sequencer::Transport;
 
-pub fn frequency_from_keycode(c: KeyCode, octave: f32) -> Option<f32> {
-    match c {
-        KeyCode::Char('a') => Some(calc_frequency(octave, 3.0)), // C
-        KeyCode::Char('w') => Some(calc_frequency(octave, 4.0)), // C#
-        KeyCode::Char('s') => Some(calc_frequency(octave, 5.0)), // D
-        KeyCode::Char('e') => Some(calc_frequency(octave, 6.0)), // D#
-        KeyCode::Char('d') => Some(calc_frequency(octave, 7.0)), // E
-        KeyCode::Char('f') => Some(calc_frequency(octave, 8.0)), // F
-        KeyCode::Char('t') => Some(calc_frequency(octave, 9.0)), // F#
-        KeyCode::Char('g') => Some(calc_frequency(octave, 10.0)), // G
-        KeyCode::Char('y') => Some(calc_frequency(octave, 11.0)), // G#
-        KeyCode::Char('h') => Some(calc_frequency(octave + 1.0, 0.0)), // A
-        KeyCode::Char('u') => Some(calc_frequency(octave + 1.0, 1.0)), // A#
-        KeyCode::Char('j') => Some(calc_frequency(octave + 1.0, 2.0)), // B
-        KeyCode::Char('k') => Some(calc_frequency(octave + 1.0, 3.0)), // C
-
-        _ => None,
-    }
-}
 ///    Calculate the frequency of any note!
 /// frequency = 440×(2^(n/12))
 ///
@@ -39,13 +21,87 @@ pub fn frequency_from_keycode(c: KeyCode, octave: f32) -> Option<f32> {
 /// 9  = F#
 /// 10 = G
 /// 11 = G#
+#[allow(clippy::needless_return)]
 pub fn calc_frequency(octave: f32, note: f32) -> f32 {
     return 440.0 * 2.0_f32.powf(((octave - 4.0) * 12.0 + note) / 12.0);
 }
 
+/// Frequency of a MIDI key number, where key 69 is A4.
+pub fn frequency_from_key(key: u8) -> f32 {
+    calc_frequency(4.0, key as f32 - 69.0)
+}
+
+/// The MIDI key of a note name such as `c4`, `f#2` or `bb-1`, where `c4` is
+/// middle C.
+pub fn key_from_name(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let semitone = match chars.next()?.to_ascii_lowercase() {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (1, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (-1, octave)
+    } else {
+        (0, rest)
+    };
+    let key = 12 * (octave.parse::<i32>().ok()? + 1) + semitone + accidental;
+    if (0..=127).contains(&key) {
+        Some(key as u8)
+    } else {
+        None
+    }
+}
+
+/// A single played note.
+///
+/// `key` is the MIDI key number (60 = middle C) and is what key-mapped
+/// sources such as the sampler use to pick what to play. `frequency` is the
+/// pitch the note should sound at, and `velocity` is the MIDI-style strike
+/// velocity in the range 0..=127.
+#[derive(Copy, Clone)]
+pub struct Note {
+    pub key: u8,
+    pub frequency: f32,
+    pub velocity: u8,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Pedal {
+    /// Keys released while the pedal is down keep sounding until it lifts.
+    Sustain,
+    /// Like sustain, but only for the keys held at the moment the pedal
+    /// went down.
+    Sostenuto,
+}
+
 #[derive(Copy, Clone)]
 pub enum NoteEvent {
-    Press(f32),
+    Press(Note),
     Hold,
-    Up,
+    /// Release of the note with the given key.
+    Up(u8),
+    /// Change the pitch of the sounding note without restarting it, used
+    /// when notes overlap in legato mode.
+    Legato(Note),
+    /// Pitch bend in semitones, applied on top of every note.
+    PitchBend(f32),
+    /// Mod wheel position from 0.0 to 1.0, adds vibrato.
+    Modulation(f32),
+    /// Aftertouch from 0.0 to 1.0, opens the filter.
+    Pressure(f32),
+    /// A pedal going down (true) or up.
+    Pedal(Pedal, bool),
+    /// A new value for a parameter of the voices or effects.
+    Parameter(Parameter, f32),
+    /// Starts, stops or cues the step sequencer, or records a loop.
+    Transport(Transport),
 }
@@ -0,0 +1,254 @@
+use std::convert::TryFrom;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+
+use crate::musical_keyboard::{Note, NoteEvent};
+use crate::parameter::{Parameter, Parameters};
+use crate::recorder::Input;
+use crate::tuning::Tuning;
+
+/// Velocity of `/note/on` messages that do not give one.
+const DEFAULT_VELOCITY: u8 = 100;
+
+enum Argument {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+impl Argument {
+    fn number(&self) -> Option<f32> {
+        match self {
+            Argument::Int(value) => Some(*value as f32),
+            Argument::Float(value) => Some(*value),
+            Argument::String(_) => None,
+        }
+    }
+}
+
+struct Message {
+    address: String,
+    arguments: Vec<Argument>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position.checked_add(count)?)?;
+        self.position += count;
+        Some(bytes)
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        let bytes = self.bytes(4)?;
+        Some(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A null terminated string, padded to a multiple of four bytes.
+    fn string(&mut self) -> Option<String> {
+        let rest = self.data.get(self.position..)?;
+        let length = rest.iter().position(|&byte| byte == 0)?;
+        let string = String::from_utf8(rest[..length].to_vec()).ok()?;
+        self.position += (length + 4) & !3;
+        Some(string)
+    }
+}
+
+/// Reads the messages of a packet, looking inside bundles. Bundles are
+/// played as soon as they arrive, their time tags are ignored.
+fn parse_packet(data: &[u8], messages: &mut Vec<Message>) -> Option<()> {
+    let mut reader = Reader { data, position: 0 };
+    if data.starts_with(b"#bundle\0") {
+        // the name and the time tag
+        reader.bytes(16)?;
+        while reader.position < data.len() {
+            // a negative length is a broken packet
+            let length = usize::try_from(reader.i32()?).ok()?;
+            parse_packet(reader.bytes(length)?, messages)?;
+        }
+        return Some(());
+    }
+
+    let address = reader.string()?;
+    let type_tags = if reader.position < data.len() {
+        reader.string()?
+    } else {
+        String::new()
+    };
+    let mut arguments = Vec::new();
+    for tag in type_tags.chars().skip_while(|&c| c == ',') {
+        let argument = match tag {
+            'i' => Argument::Int(reader.i32()?),
+            'f' => Argument::Float(f32::from_bits(reader.i32()? as u32)),
+            's' => Argument::String(reader.string()?),
+            'T' => Argument::Int(1),
+            'F' => Argument::Int(0),
+            _ => return None,
+        };
+        arguments.push(argument);
+    }
+    messages.push(Message { address, arguments });
+    Some(())
+}
+
+fn write_string(packet: &mut Vec<u8>, string: &str) {
+    packet.extend_from_slice(string.as_bytes());
+    let padding = 4 - string.len() % 4;
+    packet.extend(std::iter::repeat_n(0, padding));
+}
+
+fn encode(message: &Message) -> Vec<u8> {
+    let mut packet = Vec::new();
+    write_string(&mut packet, &message.address);
+    let type_tags: String = std::iter::once(',')
+        .chain(message.arguments.iter().map(|argument| match argument {
+            Argument::Int(_) => 'i',
+            Argument::Float(_) => 'f',
+            Argument::String(_) => 's',
+        }))
+        .collect();
+    write_string(&mut packet, &type_tags);
+    for argument in message.arguments.iter() {
+        match argument {
+            Argument::Int(value) => packet.extend_from_slice(&value.to_be_bytes()),
+            Argument::Float(value) => packet.extend_from_slice(&value.to_be_bytes()),
+            Argument::String(value) => write_string(&mut packet, value),
+        }
+    }
+    packet
+}
+
+/// Plays notes and changes parameters on messages from other programs:
+///
+/// - `/note/on <key> [velocity]`, where a fractional key plays between the
+///   keys and velocity 0 releases the key
+/// - `/note/off <key>`
+/// - `/param/<name> <value>` sets a parameter, see `Parameter::name`
+/// - `/param/<name>` answers with the current value in the same form
+/// - `/param/list` answers with the name and value of every parameter
+struct Server {
+    socket: UdpSocket,
+    sender: Input,
+    parameters: Parameters,
+    tuning: Tuning,
+}
+
+impl Server {
+    fn run(&self) {
+        let mut buffer = [0; 65536];
+        let mut messages = Vec::new();
+        loop {
+            let (length, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprint!("OSC: {}\r\n", e);
+                    continue;
+                }
+            };
+            messages.clear();
+            if parse_packet(&buffer[..length], &mut messages).is_none() {
+                eprint!("OSC: could not read packet from {}\r\n", from);
+            }
+            for message in messages.iter() {
+                if !self.handle_message(message, from) {
+                    eprint!("OSC: could not handle {}\r\n", message.address);
+                }
+            }
+        }
+    }
+
+    /// Returns false for messages that are not understood.
+    fn handle_message(&self, message: &Message, from: SocketAddr) -> bool {
+        let numbers: Vec<f32> = message
+            .arguments
+            .iter()
+            .filter_map(Argument::number)
+            .collect();
+        match (message.address.as_str(), numbers.as_slice()) {
+            ("/note/on", [key, rest @ ..]) => {
+                let velocity = rest
+                    .first()
+                    .map_or(DEFAULT_VELOCITY, |v| v.clamp(0.0, 127.0) as u8);
+                if velocity == 0 {
+                    let _ = self.sender.send(NoteEvent::Up(key.round() as u8));
+                } else if let Some(frequency) = self.tuning.frequency_between(*key) {
+                    let _ = self.sender.send(NoteEvent::Press(Note {
+                        key: key.round().clamp(0.0, 127.0) as u8,
+                        frequency,
+                        velocity,
+                    }));
+                }
+                true
+            }
+            ("/note/off", [key, ..]) => {
+                let _ = self.sender.send(NoteEvent::Up(key.round() as u8));
+                true
+            }
+            ("/param/list", []) => {
+                let arguments = self
+                    .parameters
+                    .iter()
+                    .flat_map(|(parameter, value)| {
+                        [Argument::String(parameter.name()), Argument::Float(value)]
+                    })
+                    .collect();
+                self.reply("/param/list", arguments, from);
+                true
+            }
+            (address, numbers) => {
+                let parameter = match address
+                    .strip_prefix("/param/")
+                    .and_then(Parameter::from_name)
+                {
+                    Some(parameter) => parameter,
+                    None => return false,
+                };
+                match numbers.first() {
+                    Some(&value) => self.parameters.send(&self.sender, parameter, value),
+                    None => match self.parameters.get(parameter) {
+                        Some(value) => {
+                            self.reply(address, vec![Argument::Float(value)], from);
+                            true
+                        }
+                        None => false,
+                    },
+                }
+            }
+        }
+    }
+
+    fn reply(&self, address: &str, arguments: Vec<Argument>, to: SocketAddr) {
+        let message = Message {
+            address: address.to_string(),
+            arguments,
+        };
+        if let Err(e) = self.socket.send_to(&encode(&message), to) {
+            eprint!("OSC: could not answer {}: {}\r\n", to, e);
+        }
+    }
+}
+
+/// Starts listening for OSC on a UDP port, on a thread of its own.
+pub fn listen(
+    port: u16,
+    sender: Input,
+    parameters: Parameters,
+    tuning: Tuning,
+) -> Result<(), String> {
+    let socket =
+        UdpSocket::bind(("0.0.0.0", port)).map_err(|e| format!("OSC port {}: {}", port, e))?;
+    let server = Server {
+        socket,
+        sender,
+        parameters,
+        tuning,
+    };
+    thread::spawn(move || server.run());
+    Ok(())
+}
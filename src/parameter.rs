@@ -0,0 +1,257 @@
+use std::sync::Arc;
+
+use crate::atomic_f32::AtomicF32;
+use crate::delay::DelayTime;
+use crate::musical_keyboard::NoteEvent;
+use crate::recorder::Input;
+use crate::sequencer::DEFAULT_TEMPO;
+
+/// A setting that can be changed while playing, by sending
+/// `NoteEvent::Parameter` down the stream. Every stage picks out the
+/// parameters it owns as the event passes through it.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Parameter {
+    /// Filter cutoff in Hz for notes played at full velocity.
+    Cutoff,
+    /// Filter resonance, 0.0 to 1.0.
+    Resonance,
+    /// Waveform of the oscillator, see `OscillatorShape::index`.
+    Shape,
+    /// Most harmonics the oscillator adds, 0 for one per octave below
+    /// half the sample rate.
+    Harmonics,
+    /// Level the oscillator loses per second while a note plays.
+    OscillatorDecay,
+    /// Envelope attack, decay and release in seconds.
+    Attack,
+    Decay,
+    /// Envelope sustain level, 0.0 to 1.0.
+    Sustain,
+    Release,
+    /// How much velocity sets the level, 0.0 to 1.0.
+    VelocityToLevel,
+    /// Seconds added to the attack at full velocity.
+    VelocityToAttack,
+    /// Octaves the cutoff drops for the softest notes.
+    VelocityToCutoff,
+    /// Octaves full aftertouch opens the filter.
+    PressureToCutoff,
+    /// Glide time in seconds.
+    Glide,
+    /// Vibrato depth in semitones with the mod wheel all the way up.
+    Vibrato,
+    /// Vibrato speed in Hz.
+    VibratoRate,
+    ChorusMix,
+    FlangerMix,
+    PhaserMix,
+    /// Delay time in milliseconds.
+    DelayTime,
+    /// Delay time as a fraction of a whole note, which follows the tempo. 0
+    /// while the delay time is in milliseconds.
+    DelayNote,
+    DelayFeedback,
+    DelayMix,
+    ReverbMix,
+    RoomSize,
+    Damping,
+    /// Gain in dB of an EQ band, counting from 0.
+    EqGain(u8),
+    /// Frequency in Hz of an EQ band, counting from 0.
+    EqFrequency(u8),
+    /// Compressor threshold in dBFS.
+    CompressorThreshold,
+    /// Limiter ceiling in dBFS.
+    Ceiling,
+    /// Sequencer tempo in beats per minute.
+    Tempo,
+    /// Sequencer swing in percent, see `Sequencer::set_swing`.
+    Swing,
+}
+
+impl Parameter {
+    /// Every parameter except the ones of the EQ bands, which depend on how
+    /// many bands there are.
+    pub const FIXED: [Parameter; 30] = [
+        Parameter::Cutoff,
+        Parameter::Resonance,
+        Parameter::Shape,
+        Parameter::Harmonics,
+        Parameter::OscillatorDecay,
+        Parameter::Attack,
+        Parameter::Decay,
+        Parameter::Sustain,
+        Parameter::Release,
+        Parameter::VelocityToLevel,
+        Parameter::VelocityToAttack,
+        Parameter::VelocityToCutoff,
+        Parameter::PressureToCutoff,
+        Parameter::Glide,
+        Parameter::Vibrato,
+        Parameter::VibratoRate,
+        Parameter::ChorusMix,
+        Parameter::FlangerMix,
+        Parameter::PhaserMix,
+        Parameter::DelayTime,
+        Parameter::DelayNote,
+        Parameter::DelayFeedback,
+        Parameter::DelayMix,
+        Parameter::ReverbMix,
+        Parameter::RoomSize,
+        Parameter::Damping,
+        Parameter::CompressorThreshold,
+        Parameter::Ceiling,
+        Parameter::Tempo,
+        Parameter::Swing,
+    ];
+
+    /// The name used on the command line and over OSC. EQ bands are
+    /// numbered from 1 there, e.g. `eq1-gain`.
+    pub fn name(&self) -> String {
+        let name = match self {
+            Parameter::Cutoff => "cutoff",
+            Parameter::Resonance => "resonance",
+            Parameter::Shape => "shape",
+            Parameter::Harmonics => "harmonics",
+            Parameter::OscillatorDecay => "oscillator-decay",
+            Parameter::Attack => "attack",
+            Parameter::Decay => "decay",
+            Parameter::Sustain => "sustain",
+            Parameter::Release => "release",
+            Parameter::VelocityToLevel => "velocity-to-level",
+            Parameter::VelocityToAttack => "velocity-to-attack",
+            Parameter::VelocityToCutoff => "velocity-to-cutoff",
+            Parameter::PressureToCutoff => "pressure-to-cutoff",
+            Parameter::Glide => "glide",
+            Parameter::Vibrato => "vibrato",
+            Parameter::VibratoRate => "vibrato-rate",
+            Parameter::ChorusMix => "chorus-mix",
+            Parameter::FlangerMix => "flanger-mix",
+            Parameter::PhaserMix => "phaser-mix",
+            Parameter::DelayTime => "delay-time",
+            Parameter::DelayNote => "delay-note",
+            Parameter::DelayFeedback => "delay-feedback",
+            Parameter::DelayMix => "delay-mix",
+            Parameter::ReverbMix => "reverb-mix",
+            Parameter::RoomSize => "room-size",
+            Parameter::Damping => "damping",
+            Parameter::EqGain(band) => return format!("eq{}-gain", band + 1),
+            Parameter::EqFrequency(band) => return format!("eq{}-frequency", band + 1),
+            Parameter::CompressorThreshold => "compressor-threshold",
+            Parameter::Ceiling => "ceiling",
+            Parameter::Tempo => "tempo",
+            Parameter::Swing => "swing",
+        };
+        name.to_string()
+    }
+
+    pub fn from_name(name: &str) -> Option<Parameter> {
+        if let Some(parameter) = Parameter::FIXED.iter().find(|p| p.name() == name) {
+            return Some(*parameter);
+        }
+        let (band, setting) = name.strip_prefix("eq")?.split_once('-')?;
+        let band = band.parse::<u8>().ok()?.checked_sub(1)?;
+        match setting {
+            "gain" => Some(Parameter::EqGain(band)),
+            "frequency" => Some(Parameter::EqFrequency(band)),
+            _ => None,
+        }
+    }
+
+    /// Parameters of the voices, which the polyphony passes on to every voice
+    /// instead of only down the stream.
+    pub fn is_voice(&self) -> bool {
+        matches!(
+            self,
+            Parameter::Cutoff
+                | Parameter::Resonance
+                | Parameter::Shape
+                | Parameter::Harmonics
+                | Parameter::OscillatorDecay
+                | Parameter::Attack
+                | Parameter::Decay
+                | Parameter::Sustain
+                | Parameter::Release
+                | Parameter::VelocityToLevel
+                | Parameter::VelocityToAttack
+                | Parameter::VelocityToCutoff
+                | Parameter::PressureToCutoff
+                | Parameter::Glide
+                | Parameter::Vibrato
+                | Parameter::VibratoRate
+        )
+    }
+}
+
+/// The current value of every parameter, shared by everything that changes
+/// them so the values can be listed.
+#[derive(Clone)]
+pub struct Parameters {
+    values: Arc<Vec<(Parameter, AtomicF32)>>,
+}
+
+impl Parameters {
+    pub fn new(values: &[(Parameter, f32)]) -> Parameters {
+        Parameters {
+            values: Arc::new(
+                values
+                    .iter()
+                    .map(|&(parameter, value)| (parameter, AtomicF32::new(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn get(&self, parameter: Parameter) -> Option<f32> {
+        self.values
+            .iter()
+            .find(|(p, _)| *p == parameter)
+            .map(|(_, value)| value.get())
+    }
+
+    /// Records the new value and sends it down the stream. Returns false for
+    /// a parameter that is not in the list.
+    pub fn send(&self, sender: &Input, parameter: Parameter, value: f32) -> bool {
+        match self.values.iter().find(|(p, _)| *p == parameter) {
+            Some((_, current)) => {
+                current.set(value);
+                self.follow(parameter, value);
+                let _ = sender.send(NoteEvent::Parameter(parameter, value));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keeps the two delay times agreeing the way the delay does: a time in
+    /// milliseconds replaces a note length, and a note length sets the time
+    /// in milliseconds at the tempo playing.
+    fn follow(&self, parameter: Parameter, value: f32) {
+        let note = self.get(Parameter::DelayNote).unwrap_or(0.0);
+        let tempo = self.get(Parameter::Tempo).unwrap_or(DEFAULT_TEMPO);
+        match parameter {
+            Parameter::DelayTime => self.set(Parameter::DelayNote, 0.0),
+            Parameter::DelayNote if value > 0.0 => {
+                let ms = DelayTime::Division(value).seconds(tempo) * 1000.0;
+                self.set(Parameter::DelayTime, ms)
+            }
+            Parameter::Tempo if note > 0.0 => {
+                let ms = DelayTime::Division(note).seconds(value) * 1000.0;
+                self.set(Parameter::DelayTime, ms)
+            }
+            _ => {}
+        }
+    }
+
+    fn set(&self, parameter: Parameter, value: f32) {
+        if let Some((_, current)) = self.values.iter().find(|(p, _)| *p == parameter) {
+            current.set(value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Parameter, f32)> + '_ {
+        self.values
+            .iter()
+            .map(|(parameter, value)| (*parameter, value.get()))
+    }
+}
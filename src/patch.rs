@@ -0,0 +1,912 @@
+//! Patches, everything about how the instrument sounds in a TOML file that
+//! can be edited by hand, and a browser for a directory of them.
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use crate::delay::{DelaySettings, DelayTime};
+use crate::dynamics::{CompressorSettings, LimiterSettings};
+use crate::envvelope::EnvelopeSettings;
+use crate::equalizer::EqBand;
+use crate::filter::FilterSettings;
+use crate::lfo::ModulationSettings;
+use crate::parameter::{Parameter, Parameters};
+use crate::pitch::{GlideMode, GlideSettings, VIBRATO_DEPTH, VIBRATO_RATE};
+use crate::reverb::ReverbSettings;
+use crate::saw_wave_oscilator_band_limited::{OscillatorSettings, OscillatorShape};
+use crate::sequencer::DEFAULT_TEMPO;
+use crate::velocity::VelocityCurve;
+use crate::waveshaper::{ShapeMode, WaveshaperSettings};
+
+/// A sound: the oscillator, envelope, filter, glide, modulation routings,
+/// drive and effects. Everything can be left out of the file and then has
+/// the same value as without a patch:
+///
+/// ```toml
+/// name = "Soft square"
+///
+/// [oscillator]
+/// shape = "square"
+///
+/// [envelope]
+/// attack = 0.05
+/// release = 0.4
+///
+/// [effects.reverb]
+/// mix = 0.3
+/// ```
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Patch {
+    pub name: String,
+    /// `linear`, `exponential` or `fixed`, see `VelocityCurve`.
+    pub velocity_curve: String,
+    pub oscillator: OscillatorPatch,
+    pub envelope: EnvelopePatch,
+    pub filter: FilterPatch,
+    pub glide: GlidePatch,
+    pub modulation: ModulationPatch,
+    pub drive: Option<DrivePatch>,
+    pub effects: EffectsPatch,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct OscillatorPatch {
+    /// `saw`, `square`, `triangle` or `sine`.
+    pub shape: String,
+    pub harmonics: u32,
+    /// Level lost per second while a note plays.
+    pub decay: f32,
+}
+
+/// Stage times in seconds and the sustain level.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct EnvelopePatch {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct FilterPatch {
+    pub cutoff: f32,
+    pub resonance: f32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct GlidePatch {
+    /// `off`, `time` or `rate`, see `GlideMode`.
+    pub mode: String,
+    pub time: f32,
+}
+
+/// Where the mod wheel, aftertouch and velocity go.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct ModulationPatch {
+    /// Semitones of vibrato with the mod wheel all the way up.
+    pub vibrato: f32,
+    pub vibrato_rate: f32,
+    /// Octaves full aftertouch opens the filter.
+    pub pressure_to_cutoff: f32,
+    /// Octaves the filter closes for the softest notes.
+    pub velocity_to_cutoff: f32,
+    /// 0.0 (every note as loud) to 1.0 (silent at velocity 0).
+    pub velocity_to_level: f32,
+    /// Seconds added to the attack at full velocity.
+    pub velocity_to_attack: f32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct DrivePatch {
+    /// `soft`, `hard`, `tube`, `fold` or `crush`, see `ShapeMode`.
+    pub mode: String,
+    pub pre_gain: f32,
+    pub post_gain: f32,
+    pub oversampling: usize,
+    pub bits: u32,
+    pub downsample: usize,
+    /// On every voice rather than on the mix.
+    pub per_voice: bool,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct EffectsPatch {
+    /// Limiter ceiling in dBFS.
+    pub ceiling: f32,
+    /// EQ bands as `kind:frequency[:gain[:q]]`, see `EqBand::from_spec`.
+    pub eq: Vec<String>,
+    pub chorus: LfoEffectPatch<ChorusDefaults>,
+    pub flanger: LfoEffectPatch<FlangerDefaults>,
+    pub phaser: LfoEffectPatch<PhaserDefaults>,
+    pub delay: DelayPatch,
+    pub reverb: ReverbPatch,
+    pub compressor: Option<CompressorPatch>,
+}
+
+/// The chorus, flanger and phaser, see `ModulationSettings`. `E` is which
+/// of them, for what is left out of the file.
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default, bound = "")]
+pub struct LfoEffectPatch<E: LfoEffectDefaults> {
+    pub mix: f32,
+    pub rate: f32,
+    pub depth: f32,
+    pub feedback: f32,
+    #[serde(skip)]
+    effect: PhantomData<E>,
+}
+
+/// The settings of an LFO driven effect with nothing changed, all off.
+pub trait LfoEffectDefaults {
+    const DEFAULTS: ModulationSettings;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct ChorusDefaults;
+
+impl LfoEffectDefaults for ChorusDefaults {
+    const DEFAULTS: ModulationSettings = ModulationSettings {
+        rate: 0.8,
+        depth: 0.5,
+        feedback: 0.0,
+        mix: 0.0,
+    };
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct FlangerDefaults;
+
+impl LfoEffectDefaults for FlangerDefaults {
+    const DEFAULTS: ModulationSettings = ModulationSettings {
+        rate: 0.2,
+        depth: 1.0,
+        feedback: 0.6,
+        mix: 0.0,
+    };
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct PhaserDefaults;
+
+impl LfoEffectDefaults for PhaserDefaults {
+    const DEFAULTS: ModulationSettings = ModulationSettings {
+        rate: 0.4,
+        depth: 0.8,
+        feedback: 0.5,
+        mix: 0.0,
+    };
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct DelayPatch {
+    /// Milliseconds (`350`) or a note length (`1/8`, `1/8d`, `1/8t`).
+    pub time: String,
+    pub feedback: f32,
+    pub low_cut: f32,
+    pub high_cut: f32,
+    pub ping_pong: bool,
+    pub mix: f32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct ReverbPatch {
+    pub mix: f32,
+    pub room_size: f32,
+    pub damping: f32,
+    /// Milliseconds.
+    pub pre_delay: f32,
+    pub width: f32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct CompressorPatch {
+    pub threshold: f32,
+    pub ratio: f32,
+    /// Milliseconds.
+    pub attack: f32,
+    /// Milliseconds.
+    pub release: f32,
+    pub knee: f32,
+    pub makeup: f32,
+}
+
+impl Default for Patch {
+    fn default() -> Self {
+        Patch {
+            name: "Init".to_string(),
+            velocity_curve: "linear".to_string(),
+            oscillator: OscillatorPatch::default(),
+            envelope: EnvelopePatch::default(),
+            filter: FilterPatch::default(),
+            glide: GlidePatch::default(),
+            modulation: ModulationPatch::default(),
+            drive: None,
+            effects: EffectsPatch::default(),
+        }
+    }
+}
+
+impl Default for OscillatorPatch {
+    fn default() -> Self {
+        let defaults = OscillatorSettings::default();
+        OscillatorPatch {
+            shape: defaults.shape.name().to_string(),
+            harmonics: defaults.harmonics,
+            decay: defaults.decay,
+        }
+    }
+}
+
+impl Default for EnvelopePatch {
+    fn default() -> Self {
+        let defaults = EnvelopeSettings::default();
+        EnvelopePatch {
+            attack: defaults.attack,
+            decay: defaults.decay,
+            sustain: defaults.sustain,
+            release: defaults.release,
+        }
+    }
+}
+
+impl Default for FilterPatch {
+    fn default() -> Self {
+        let defaults = FilterSettings::default();
+        FilterPatch {
+            cutoff: defaults.cutoff,
+            resonance: defaults.resonance,
+        }
+    }
+}
+
+impl Default for GlidePatch {
+    fn default() -> Self {
+        GlidePatch {
+            mode: "off".to_string(),
+            time: GlideSettings::default().time,
+        }
+    }
+}
+
+impl Default for ModulationPatch {
+    fn default() -> Self {
+        let filter = FilterSettings::default();
+        let envelope = EnvelopeSettings::default();
+        ModulationPatch {
+            vibrato: VIBRATO_DEPTH,
+            vibrato_rate: VIBRATO_RATE,
+            pressure_to_cutoff: filter.pressure,
+            velocity_to_cutoff: filter.velocity,
+            velocity_to_level: envelope.velocity_track,
+            velocity_to_attack: envelope.velocity_attack,
+        }
+    }
+}
+
+impl Default for DrivePatch {
+    fn default() -> Self {
+        let defaults = WaveshaperSettings::default();
+        DrivePatch {
+            mode: "soft".to_string(),
+            pre_gain: defaults.pre_gain,
+            post_gain: defaults.post_gain,
+            oversampling: defaults.oversampling,
+            bits: defaults.bits,
+            downsample: defaults.downsample,
+            per_voice: false,
+        }
+    }
+}
+
+impl Default for EffectsPatch {
+    fn default() -> Self {
+        EffectsPatch {
+            ceiling: LimiterSettings::default().ceiling,
+            eq: Vec::new(),
+            chorus: LfoEffectPatch::default(),
+            flanger: LfoEffectPatch::default(),
+            phaser: LfoEffectPatch::default(),
+            delay: DelayPatch::default(),
+            reverb: ReverbPatch::default(),
+            compressor: None,
+        }
+    }
+}
+
+impl Default for DelayPatch {
+    fn default() -> Self {
+        let defaults = DelaySettings::default();
+        DelayPatch {
+            time: format!("{}", defaults.time.seconds(defaults.tempo) * 1000.0),
+            feedback: defaults.feedback,
+            low_cut: defaults.low_cut,
+            high_cut: defaults.high_cut,
+            ping_pong: defaults.ping_pong,
+            mix: defaults.mix,
+        }
+    }
+}
+
+impl Default for ReverbPatch {
+    fn default() -> Self {
+        let defaults = ReverbSettings::default();
+        ReverbPatch {
+            mix: defaults.mix,
+            room_size: defaults.room_size,
+            damping: defaults.damping,
+            pre_delay: defaults.pre_delay * 1000.0,
+            width: defaults.width,
+        }
+    }
+}
+
+impl Default for CompressorPatch {
+    fn default() -> Self {
+        let defaults = CompressorSettings::default();
+        CompressorPatch {
+            threshold: defaults.threshold,
+            ratio: defaults.ratio,
+            attack: defaults.attack * 1000.0,
+            release: defaults.release * 1000.0,
+            knee: defaults.knee,
+            makeup: defaults.makeup,
+        }
+    }
+}
+
+impl Patch {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Patch, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Patch::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Reads a patch and checks every name in it.
+    pub fn parse(text: &str) -> Result<Patch, String> {
+        let patch: Patch = toml::from_str(text).map_err(|e| e.to_string())?;
+        patch.velocity_curve()?;
+        patch.oscillator()?;
+        patch.glide()?;
+        patch.drive()?;
+        patch.effects.delay.settings(DEFAULT_TEMPO)?;
+        patch.effects.eq()?;
+        Ok(patch)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let text = toml::to_string(self).map_err(|e| format!("{}: {}", path.display(), e))?;
+        fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn velocity_curve(&self) -> Result<VelocityCurve, String> {
+        VelocityCurve::from_name(&self.velocity_curve)
+            .ok_or_else(|| format!("unknown velocity curve {}", self.velocity_curve))
+    }
+
+    pub fn oscillator(&self) -> Result<OscillatorSettings, String> {
+        let oscillator = &self.oscillator;
+        Ok(OscillatorSettings {
+            shape: OscillatorShape::from_name(&oscillator.shape)
+                .ok_or_else(|| format!("unknown oscillator shape {}", oscillator.shape))?,
+            harmonics: oscillator.harmonics,
+            decay: oscillator.decay.max(0.0),
+        })
+    }
+
+    pub fn envelope(&self) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack: self.envelope.attack,
+            decay: self.envelope.decay,
+            sustain: self.envelope.sustain,
+            release: self.envelope.release,
+            velocity_track: self.modulation.velocity_to_level,
+            velocity_attack: self.modulation.velocity_to_attack,
+        }
+    }
+
+    pub fn filter(&self) -> FilterSettings {
+        FilterSettings {
+            cutoff: self.filter.cutoff,
+            resonance: self.filter.resonance,
+            velocity: self.modulation.velocity_to_cutoff,
+            pressure: self.modulation.pressure_to_cutoff,
+        }
+    }
+
+    pub fn glide(&self) -> Result<GlideSettings, String> {
+        Ok(GlideSettings {
+            mode: GlideMode::from_name(&self.glide.mode)
+                .ok_or_else(|| format!("unknown glide mode {}", self.glide.mode))?,
+            time: self.glide.time,
+        })
+    }
+
+    /// The drive settings, and whether it is on every voice.
+    pub fn drive(&self) -> Result<Option<(WaveshaperSettings, bool)>, String> {
+        let drive = match self.drive.as_ref() {
+            Some(drive) => drive,
+            None => return Ok(None),
+        };
+        let settings = WaveshaperSettings {
+            mode: ShapeMode::from_name(&drive.mode)
+                .ok_or_else(|| format!("unknown drive mode {}", drive.mode))?,
+            pre_gain: drive.pre_gain,
+            post_gain: drive.post_gain,
+            oversampling: drive.oversampling,
+            bits: drive.bits,
+            downsample: drive.downsample,
+        };
+        Ok(Some((settings, drive.per_voice)))
+    }
+
+    /// Takes the current value of every parameter into the patch, so it
+    /// saves what is being played.
+    pub fn update(&mut self, parameters: &Parameters) {
+        let note = parameters.get(Parameter::DelayNote).unwrap_or(0.0);
+        let written = DelayTime::from_name(&self.effects.delay.time);
+        let mut bands: Vec<Option<EqBand>> = self
+            .effects
+            .eq
+            .iter()
+            .map(|spec| EqBand::from_spec(spec))
+            .collect();
+        for (parameter, value) in parameters.iter() {
+            let effects = &mut self.effects;
+            match parameter {
+                Parameter::Cutoff => self.filter.cutoff = value,
+                Parameter::Resonance => self.filter.resonance = value,
+                Parameter::Shape => {
+                    if let Some(shape) = OscillatorShape::from_index(value) {
+                        self.oscillator.shape = shape.name().to_string();
+                    }
+                }
+                Parameter::Harmonics => self.oscillator.harmonics = value.max(0.0) as u32,
+                Parameter::OscillatorDecay => self.oscillator.decay = value,
+                Parameter::Attack => self.envelope.attack = value,
+                Parameter::Decay => self.envelope.decay = value,
+                Parameter::Sustain => self.envelope.sustain = value,
+                Parameter::Release => self.envelope.release = value,
+                Parameter::VelocityToLevel => self.modulation.velocity_to_level = value,
+                Parameter::VelocityToAttack => self.modulation.velocity_to_attack = value,
+                Parameter::VelocityToCutoff => self.modulation.velocity_to_cutoff = value,
+                Parameter::PressureToCutoff => self.modulation.pressure_to_cutoff = value,
+                Parameter::Glide => self.glide.time = value,
+                Parameter::Vibrato => self.modulation.vibrato = value,
+                Parameter::VibratoRate => self.modulation.vibrato_rate = value,
+                Parameter::ChorusMix => effects.chorus.mix = value,
+                Parameter::FlangerMix => effects.flanger.mix = value,
+                Parameter::PhaserMix => effects.phaser.mix = value,
+                // a note length is what plays for as long as `DelayNote` is set
+                Parameter::DelayTime => {
+                    if note <= 0.0 && written != Some(DelayTime::Milliseconds(value)) {
+                        effects.delay.time = format!("{}", value.round());
+                    }
+                }
+                Parameter::DelayNote => {
+                    if value > 0.0 && written != Some(DelayTime::Division(value)) {
+                        effects.delay.time = DelayTime::Division(value).name();
+                    }
+                }
+                Parameter::DelayFeedback => effects.delay.feedback = value,
+                Parameter::DelayMix => effects.delay.mix = value,
+                Parameter::ReverbMix => effects.reverb.mix = value,
+                Parameter::RoomSize => effects.reverb.room_size = value,
+                Parameter::Damping => effects.reverb.damping = value,
+                Parameter::EqGain(band) => {
+                    if let Some(Some(band)) = bands.get_mut(band as usize) {
+                        band.gain = value;
+                    }
+                }
+                Parameter::EqFrequency(band) => {
+                    if let Some(Some(band)) = bands.get_mut(band as usize) {
+                        band.frequency = value;
+                    }
+                }
+                Parameter::CompressorThreshold => {
+                    if let Some(compressor) = effects.compressor.as_mut() {
+                        compressor.threshold = value;
+                    }
+                }
+                Parameter::Ceiling => effects.ceiling = value,
+                // the tempo and swing belong to the sequence
+                Parameter::Tempo | Parameter::Swing => {}
+            }
+        }
+        for (spec, band) in self.effects.eq.iter_mut().zip(bands) {
+            if let Some(band) = band {
+                *spec = band.spec();
+            }
+        }
+    }
+
+    /// Whether `other` differs in anything only read at startup, leaving
+    /// out the name and whatever `parameters` can change while playing.
+    pub fn differs_at_startup(&self, other: &Patch, parameters: &Parameters) -> bool {
+        // both take the playing values, so only what they cannot change is left
+        let mut this = self.clone();
+        let mut other = other.clone();
+        this.update(parameters);
+        other.update(parameters);
+        this.name = other.name.clone();
+        this.effects.delay.time = other.effects.delay.time.clone();
+        this != other
+    }
+}
+
+impl<E: LfoEffectDefaults> Default for LfoEffectPatch<E> {
+    fn default() -> Self {
+        LfoEffectPatch {
+            mix: E::DEFAULTS.mix,
+            rate: E::DEFAULTS.rate,
+            depth: E::DEFAULTS.depth,
+            feedback: E::DEFAULTS.feedback,
+            effect: PhantomData,
+        }
+    }
+}
+
+impl<E: LfoEffectDefaults> LfoEffectPatch<E> {
+    pub fn settings(&self) -> ModulationSettings {
+        ModulationSettings {
+            rate: self.rate,
+            depth: self.depth,
+            feedback: self.feedback,
+            mix: self.mix,
+        }
+    }
+}
+
+impl DelayPatch {
+    /// The delay timed against `tempo` when the time is a note length.
+    pub fn settings(&self, tempo: f32) -> Result<DelaySettings, String> {
+        Ok(DelaySettings {
+            time: DelayTime::from_name(&self.time)
+                .ok_or_else(|| format!("invalid delay time {}", self.time))?,
+            tempo,
+            feedback: self.feedback,
+            low_cut: self.low_cut,
+            high_cut: self.high_cut,
+            ping_pong: self.ping_pong,
+            mix: self.mix,
+        })
+    }
+}
+
+impl ReverbPatch {
+    pub fn settings(&self) -> ReverbSettings {
+        ReverbSettings {
+            room_size: self.room_size,
+            damping: self.damping,
+            pre_delay: self.pre_delay / 1000.0,
+            width: self.width,
+            mix: self.mix,
+        }
+    }
+}
+
+impl CompressorPatch {
+    pub fn settings(&self) -> CompressorSettings {
+        CompressorSettings {
+            threshold: self.threshold,
+            ratio: self.ratio,
+            attack: self.attack / 1000.0,
+            release: self.release / 1000.0,
+            knee: self.knee,
+            makeup: self.makeup,
+        }
+    }
+}
+
+impl EffectsPatch {
+    pub fn eq(&self) -> Result<Vec<EqBand>, String> {
+        self.eq
+            .iter()
+            .map(|spec| EqBand::from_spec(spec).ok_or_else(|| format!("invalid EQ band {}", spec)))
+            .collect()
+    }
+}
+
+/// The patches in a directory, in the order of their file names, stepped
+/// through while playing.
+pub struct PresetBrowser {
+    directory: PathBuf,
+    paths: Vec<PathBuf>,
+    /// The preset playing, None when it is not one of the files.
+    current: Option<usize>,
+}
+
+impl PresetBrowser {
+    /// Lists the `.toml` files in `directory`, which need not exist until
+    /// something is saved. `current` is the patch playing, if it came from
+    /// a file.
+    pub fn new<P: AsRef<Path>>(directory: P, current: Option<&Path>) -> PresetBrowser {
+        let mut browser = PresetBrowser {
+            directory: directory.as_ref().to_path_buf(),
+            paths: Vec::new(),
+            current: None,
+        };
+        browser.scan();
+        if let Some(current) = current {
+            match browser.paths.iter().position(|path| path == current) {
+                Some(index) => browser.current = Some(index),
+                None => {
+                    browser.paths.push(current.to_path_buf());
+                    browser.current = Some(browser.paths.len() - 1);
+                }
+            }
+        }
+        browser
+    }
+
+    fn scan(&mut self) {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "toml")
+            })
+            .collect();
+        paths.sort();
+        for path in paths {
+            if !self.paths.contains(&path) {
+                self.paths.push(path);
+            }
+        }
+    }
+
+    /// The file of the preset playing.
+    pub fn path(&self) -> Option<&Path> {
+        self.current.map(|index| self.paths[index].as_path())
+    }
+
+    /// Where the preset playing is in the list, counting from 1, and how
+    /// many presets there are.
+    pub fn position(&self) -> (usize, usize) {
+        (self.current.map_or(0, |index| index + 1), self.paths.len())
+    }
+
+    /// Loads the preset `step` places on from the current one, wrapping
+    /// around. A preset that does not load is still stepped onto, so the
+    /// next step moves past it.
+    pub fn step(&mut self, step: isize) -> Option<Result<Patch, String>> {
+        self.scan();
+        if self.paths.is_empty() {
+            return None;
+        }
+        let count = self.paths.len() as isize;
+        let index = match self.current {
+            Some(index) => (index as isize + step).rem_euclid(count),
+            None if step < 0 => count - 1,
+            None => 0,
+        } as usize;
+        self.current = Some(index);
+        Some(Patch::load(&self.paths[index]))
+    }
+
+    /// Saves over the preset playing, or as a new file in the directory
+    /// named after the patch. Returns the path it was saved to.
+    pub fn save(&mut self, patch: &Patch) -> Result<PathBuf, String> {
+        let path = match self.path() {
+            Some(path) => path.to_path_buf(),
+            None => {
+                fs::create_dir_all(&self.directory)
+                    .map_err(|e| format!("{}: {}", self.directory.display(), e))?;
+                self.new_path(&patch.name)
+            }
+        };
+        patch.save(&path)?;
+        if self.path().is_none() {
+            self.paths.push(path.clone());
+            self.current = Some(self.paths.len() - 1);
+        }
+        Ok(path)
+    }
+
+    /// A file name from the patch name that no preset has yet.
+    fn new_path(&self, name: &str) -> PathBuf {
+        let stem: String = name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        let stem = if stem.is_empty() { "patch" } else { &stem };
+        let mut path = self.directory.join(format!("{}.toml", stem));
+        let mut number = 2;
+        while path.exists() {
+            path = self.directory.join(format!("{}-{}.toml", stem, number));
+            number += 1;
+        }
+        path
+    }
+}
+
+/// Tells when the file of the patch playing is written to, so edits made
+/// in another program are heard straight away.
+pub struct PatchWatcher {
+    watcher: RecommendedWatcher,
+    /// The directory watched and the file in it, both without symbolic
+    /// links so they compare with the paths of the changes.
+    directory: Option<PathBuf>,
+    file: Option<PathBuf>,
+}
+
+impl PatchWatcher {
+    /// Sends the path of every file created or written to in the directory
+    /// watched, from a thread of its own.
+    pub fn new(sender: Sender<PathBuf>) -> Result<PatchWatcher, String> {
+        let watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+            if let Ok(event) = result {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        let _ = sender.send(path);
+                    }
+                }
+            }
+        })
+        .map_err(|e| format!("watching patches: {}", e))?;
+        Ok(PatchWatcher {
+            watcher,
+            directory: None,
+            file: None,
+        })
+    }
+
+    /// Watches the patch file at `path` in place of the one before. It is
+    /// the directory that is watched, as editors often save by writing a
+    /// new file and renaming it over the old one.
+    pub fn watch(&mut self, path: &Path) -> Result<(), String> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| format!("{}: not a file", path.display()))?;
+        let directory = match path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new("."),
+        };
+        let directory =
+            fs::canonicalize(directory).map_err(|e| format!("{}: {}", directory.display(), e))?;
+        if self.directory.as_ref() != Some(&directory) {
+            if let Some(watched) = self.directory.take() {
+                let _ = self.watcher.unwatch(&watched);
+            }
+            self.watcher
+                .watch(&directory, RecursiveMode::NonRecursive)
+                .map_err(|e| format!("{}: {}", directory.display(), e))?;
+            self.directory = Some(directory.clone());
+        }
+        self.file = Some(directory.join(name));
+        Ok(())
+    }
+
+    /// Whether a change sent for `path` is to the patch file watched.
+    pub fn is_watching(&self, path: &Path) -> bool {
+        self.file.as_deref() == Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Patch {
+        match Patch::parse(text) {
+            Ok(patch) => patch,
+            Err(message) => panic!("{}", message),
+        }
+    }
+
+    fn error(text: &str) -> String {
+        Patch::parse(text).err().expect("parsed a broken patch")
+    }
+
+    #[test]
+    fn what_is_left_out_is_the_default() {
+        let patch = parse(
+            "name = \"Soft square\"\n\
+             [oscillator]\nshape = \"square\"\n\
+             [envelope]\nattack = 0.05\nrelease = 0.4\n\
+             [effects.reverb]\nmix = 0.3\n",
+        );
+        assert_eq!(patch.name, "Soft square");
+        assert_eq!(patch.oscillator.shape, "square");
+        assert_eq!(
+            patch.oscillator.harmonics,
+            OscillatorPatch::default().harmonics
+        );
+        assert_eq!((patch.envelope.attack, patch.envelope.release), (0.05, 0.4));
+        assert_eq!(patch.envelope.sustain, EnvelopePatch::default().sustain);
+        assert_eq!(patch.effects.reverb.mix, 0.3);
+        assert!(patch.filter == FilterPatch::default());
+        assert!(patch.drive.is_none());
+        assert!(parse("") == Patch::default());
+    }
+
+    #[test]
+    fn lfo_effects_default_to_their_own_settings() {
+        let patch = parse("[effects.flanger]\nmix = 0.5\n[effects.phaser]\nrate = 2.0\n");
+        let flanger = patch.effects.flanger.settings();
+        assert_eq!(flanger.mix, 0.5);
+        assert_eq!(flanger.rate, FlangerDefaults::DEFAULTS.rate);
+        assert_eq!(flanger.feedback, FlangerDefaults::DEFAULTS.feedback);
+        let phaser = patch.effects.phaser.settings();
+        assert_eq!((phaser.rate, phaser.mix), (2.0, 0.0));
+        assert_eq!(phaser.depth, PhaserDefaults::DEFAULTS.depth);
+        assert!(patch.effects.chorus == LfoEffectPatch::default());
+    }
+
+    #[test]
+    fn names_are_checked() {
+        assert!(error("[oscillator]\nshape = \"wobble\"\n").contains("wobble"));
+        assert!(error("velocity-curve = \"steep\"\n").contains("steep"));
+        assert!(error("[glide]\nmode = \"slow\"\n").contains("slow"));
+        assert!(error("[drive]\nmode = \"loud\"\n").contains("loud"));
+        assert!(error("[effects.delay]\ntime = \"1/0\"\n").contains("1/0"));
+        assert!(error("[effects]\neq = [\"notch\"]\n").contains("notch"));
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        assert!(error("colour = \"red\"\n").contains("colour"));
+        assert!(error("[envelope]\nhold = 0.1\n").contains("hold"));
+        assert!(error("[effects.chorus]\nspeed = 1.0\n").contains("speed"));
+        error("[filter]\ncutoff = \"high\"\n");
+    }
+
+    #[test]
+    fn note_length_delays_are_saved_as_note_lengths() {
+        for name in ["1/8", "1/8d", "1/4t", "3/16", "5/7", "250"] {
+            let time = DelayTime::from_name(name).unwrap();
+            assert!(DelayTime::from_name(&time.name()) == Some(time), "{}", name);
+        }
+        assert_eq!(DelayTime::Division(0.375).name(), "1/4d");
+        let mut patch = parse("[effects.delay]\ntime = \"350\"\n");
+        let delay = |ms, note| {
+            Parameters::new(&[
+                (Parameter::DelayTime, ms),
+                (Parameter::DelayNote, note),
+                (Parameter::Tempo, 100.0),
+            ])
+        };
+        patch.update(&delay(450.0, 0.1875));
+        assert_eq!(patch.effects.delay.time, "1/8d");
+        patch.update(&delay(450.0, 0.0));
+        assert_eq!(patch.effects.delay.time, "450");
+    }
+
+    #[test]
+    fn saved_patches_read_back_the_same() {
+        for text in [
+            include_str!("../presets/acid-bass.toml"),
+            include_str!("../presets/slow-pad.toml"),
+            include_str!("../presets/soft-square.toml"),
+        ] {
+            let patch = parse(text);
+            let saved = toml::to_string(&patch).unwrap();
+            assert!(parse(&saved) == patch, "{} changed when saved", patch.name);
+        }
+    }
+}
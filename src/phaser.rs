@@ -0,0 +1,87 @@
+use crate::effect::Effect;
+use crate::lfo::{Lfo, ModulationSettings};
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+const STAGES: usize = 6;
+/// Lowest frequency the allpass chain is swept to, in Hz.
+const MIN_FREQUENCY: f32 = 200.0;
+/// How many octaves above `MIN_FREQUENCY` the sweep reaches at full depth.
+const SWEEP_OCTAVES: f32 = 4.0;
+
+/// One channel of first order allpass stages.
+#[derive(Copy, Clone, Default)]
+struct AllpassChain {
+    state: [f32; STAGES],
+    /// Output of the last sample, fed back into the input.
+    last: f32,
+}
+
+impl AllpassChain {
+    fn process(&mut self, input: f32, coefficient: f32, feedback: f32) -> f32 {
+        let mut value = input + self.last * feedback;
+        for state in self.state.iter_mut() {
+            let output = coefficient * value + *state;
+            *state = value - coefficient * output;
+            value = output;
+        }
+        self.last = value;
+        value
+    }
+}
+
+/// Notches swept up and down the spectrum by mixing the input with a copy
+/// shifted in phase by a chain of allpass filters.
+pub struct Phaser {
+    sample_rate: f32,
+    settings: ModulationSettings,
+    lfo: Lfo,
+    left: AllpassChain,
+    right: AllpassChain,
+}
+
+impl Phaser {
+    pub fn new(sample_rate: u32, settings: ModulationSettings) -> Phaser {
+        Phaser {
+            sample_rate: sample_rate as f32,
+            settings,
+            lfo: Lfo::new(sample_rate, settings.rate),
+            left: AllpassChain::default(),
+            right: AllpassChain::default(),
+        }
+    }
+
+    /// Allpass coefficient placing the phase shift of the stages at the
+    /// frequency the LFO has swept to.
+    fn coefficient(&self, lfo: f32) -> f32 {
+        let octaves = SWEEP_OCTAVES * self.settings.depth.clamp(0.0, 1.0) * (lfo + 1.0) * 0.5;
+        let frequency = (MIN_FREQUENCY * 2.0_f32.powf(octaves)).min(self.sample_rate * 0.45);
+        let t = (std::f32::consts::PI * frequency / self.sample_rate).tan();
+        (t - 1.0) / (t + 1.0)
+    }
+}
+
+impl Effect for Phaser {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let (left_lfo, right_lfo) = self.lfo.next_stereo();
+        let feedback = self.settings.feedback.clamp(-0.95, 0.95);
+        let wet_left = self
+            .left
+            .process(left, self.coefficient(left_lfo), feedback);
+        let wet_right = self
+            .right
+            .process(right, self.coefficient(right_lfo), feedback);
+
+        let mix = self.settings.mix.clamp(0.0, 1.0);
+        (
+            left * (1.0 - mix) + wet_left * mix,
+            right * (1.0 - mix) + wet_right * mix,
+        )
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        if let NoteEvent::Parameter(Parameter::PhaserMix, mix) = event {
+            self.settings.mix = mix;
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::parameter::Parameter;
+
+/// Vibrato speed in Hz, unless `Parameter::VibratoRate` changes it.
+pub const VIBRATO_RATE: f32 = 5.5;
+/// Vibrato depth in semitones with the mod wheel all the way up, unless
+/// `Parameter::Vibrato` changes it.
+pub const VIBRATO_DEPTH: f32 = 0.5;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum GlideMode {
+    /// New frequencies are reached instantly.
+    Off,
+    /// Every slide takes the glide time, however far it goes.
+    ConstantTime,
+    /// Slides move at a fixed speed, the glide time being the time per octave.
+    ConstantRate,
+}
+
+impl GlideMode {
+    pub fn from_name(name: &str) -> Option<GlideMode> {
+        match name {
+            "off" => Some(GlideMode::Off),
+            "time" => Some(GlideMode::ConstantTime),
+            "rate" => Some(GlideMode::ConstantRate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct GlideSettings {
+    pub mode: GlideMode,
+    /// Seconds, see `GlideMode`.
+    pub time: f32,
+}
+
+impl Default for GlideSettings {
+    fn default() -> Self {
+        GlideSettings {
+            mode: GlideMode::Off,
+            time: 0.1,
+        }
+    }
+}
+
+/// The frequency an oscillator plays at, sliding between notes, bent by
+/// the pitch bend and with vibrato from the mod wheel. Pitches are kept in
+/// semitones relative to A4 so slides sound even across the keyboard.
+pub struct Pitch {
+    sample_rate: f32,
+    glide: GlideSettings,
+    current: f32,
+    target: f32,
+    /// Semitones moved per sample while sliding.
+    step: f32,
+    bend: f32,
+    /// Mod wheel position from 0.0 to 1.0.
+    modulation: f32,
+    vibrato_depth: f32,
+    vibrato_rate: f32,
+    vibrato_phase: f32,
+    has_played: bool,
+}
+
+impl Pitch {
+    pub fn new(sample_rate: u32, glide: GlideSettings) -> Pitch {
+        Pitch {
+            sample_rate: sample_rate as f32,
+            glide,
+            current: 0.0,
+            target: 0.0,
+            step: 0.0,
+            bend: 0.0,
+            modulation: 0.0,
+            vibrato_depth: VIBRATO_DEPTH,
+            vibrato_rate: VIBRATO_RATE,
+            vibrato_phase: 0.0,
+            has_played: false,
+        }
+    }
+
+    pub fn set_glide(&mut self, glide: GlideSettings) {
+        self.glide = glide;
+    }
+
+    /// Moves towards `frequency`, gliding from the previous note if enabled.
+    pub fn set_frequency(&mut self, frequency: f32) {
+        if frequency <= 0.0 {
+            return;
+        }
+        self.target = 12.0 * (frequency / 440.0).log2();
+
+        let glide_samples = (self.glide.time * self.sample_rate).max(1.0);
+        let distance = self.target - self.current;
+        // the very first note has nothing to slide from
+        self.step = match self.glide.mode {
+            _ if !self.has_played => 0.0,
+            GlideMode::Off => 0.0,
+            GlideMode::ConstantTime => distance.abs() / glide_samples,
+            GlideMode::ConstantRate => 12.0 / glide_samples,
+        };
+        if self.step == 0.0 {
+            self.current = self.target;
+        }
+        self.has_played = true;
+    }
+
+    /// Pitch bend in semitones, applied on top of the note.
+    pub fn set_bend(&mut self, semitones: f32) {
+        self.bend = semitones;
+    }
+
+    /// Mod wheel position from 0.0 to 1.0.
+    pub fn set_modulation(&mut self, amount: f32) {
+        self.modulation = amount.clamp(0.0, 1.0);
+    }
+
+    /// Takes up the glide time and vibrato parameters, ignoring the rest.
+    pub fn set_parameter(&mut self, parameter: Parameter, value: f32) {
+        match parameter {
+            Parameter::Glide => self.glide.time = value.max(0.0),
+            Parameter::Vibrato => self.vibrato_depth = value,
+            Parameter::VibratoRate => self.vibrato_rate = value.max(0.0),
+            _ => {}
+        }
+    }
+
+    /// Advances the slide by one sample and returns the frequency to play.
+    pub fn next_frequency(&mut self) -> f32 {
+        if !self.has_played {
+            return 0.0;
+        }
+        if self.current < self.target {
+            self.current = (self.current + self.step).min(self.target);
+        } else if self.current > self.target {
+            self.current = (self.current - self.step).max(self.target);
+        }
+        let mut semitones = self.current + self.bend;
+        let vibrato = self.modulation * self.vibrato_depth;
+        if vibrato != 0.0 {
+            self.vibrato_phase =
+                (self.vibrato_phase + self.vibrato_rate / self.sample_rate).fract();
+            semitones += vibrato * (2.0 * std::f32::consts::PI * self.vibrato_phase).sin();
+        }
+        440.0 * 2.0_f32.powf(semitones / 12.0)
+    }
+}
@@ -0,0 +1,45 @@
+use crate::midi_file::{MidiMessage, TimedMessage};
+use crate::musical_keyboard::NoteEvent;
+use crate::tuning::Tuning;
+
+/// Plays a song, MML or a MIDI file, on the live instrument. Samples are
+/// counted on the audio thread so every note starts on time. The live
+/// instrument has a single sound, so every channel plays on it and program
+/// changes and controllers are left out.
+pub struct Playback {
+    sample_rate: f64,
+    messages: Vec<TimedMessage>,
+    tuning: Tuning,
+    next: usize,
+    frame: u64,
+}
+
+impl Playback {
+    pub fn new(sample_rate: u32, messages: Vec<TimedMessage>, tuning: Tuning) -> Playback {
+        Playback {
+            sample_rate: sample_rate as f64,
+            messages,
+            tuning,
+            next: 0,
+            frame: 0,
+        }
+    }
+
+    /// Advances by one sample, playing what is due.
+    pub fn tick<F: FnMut(NoteEvent)>(&mut self, mut play: F) {
+        let time = self.frame as f64 / self.sample_rate;
+        while let Some(timed) = self.messages.get(self.next).filter(|m| m.time <= time) {
+            match timed.message {
+                MidiMessage::NoteOn { key, velocity, .. } => {
+                    if let Some(note) = self.tuning.note(key, velocity) {
+                        play(NoteEvent::Press(note));
+                    }
+                }
+                MidiMessage::NoteOff { key, .. } => play(NoteEvent::Up(key)),
+                _ => {}
+            }
+            self.next += 1;
+        }
+        self.frame += 1;
+    }
+}
@@ -0,0 +1,483 @@
+use rodio::Source;
+use std::{
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
+
+use crate::arpeggiator::Arpeggiator;
+use crate::envvelope::Envelope;
+use crate::looper::Looper;
+use crate::music_data::MusicData;
+use crate::musical_keyboard::{Note, NoteEvent, Pedal};
+use crate::parameter::Parameter;
+use crate::playback::Playback;
+use crate::sequencer::{Sequencer, StepEvent, Transport};
+use crate::velocity::VelocityCurve;
+
+/// Which of the held keys a monophonic voice plays.
+#[derive(Copy, Clone, PartialEq)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+impl NotePriority {
+    pub fn from_name(name: &str) -> Option<NotePriority> {
+        match name {
+            "last" => Some(NotePriority::Last),
+            "low" => Some(NotePriority::Low),
+            "high" => Some(NotePriority::High),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum VoiceMode {
+    Poly,
+    /// A single voice, every new note restarts the envelope.
+    Mono(NotePriority),
+    /// A single voice, notes played while another is held only change its
+    /// pitch and the envelope carries on.
+    Legato(NotePriority),
+}
+
+struct Voice<S>
+where
+    S: Iterator<Item = MusicData> + Source,
+{
+    sender: Sender<NoteEvent>,
+    envelope: Envelope<S>,
+    /// Key currently held on this voice, None once released.
+    key: Option<u8>,
+    /// When the voice was last started, for stealing the oldest voice.
+    started: u64,
+}
+
+/// Plays several notes at once by routing note events to a set of voices and
+/// mixing them down to a stereo signal.
+///
+/// Every voice is a complete oscillator and envelope chain that gets its
+/// events through its own channel, just like a single voice listening to the
+/// keyboard does.
+pub struct Polyphony<S>
+where
+    S: Iterator<Item = MusicData> + Source,
+{
+    sample_rate: u32,
+    receiver: Receiver<NoteEvent>,
+    voices: Vec<Voice<S>>,
+    press_count: u64,
+    mode: VoiceMode,
+    velocity_curve: VelocityCurve,
+    /// Keys held down in the mono modes, in the order they were pressed.
+    held: Vec<Note>,
+    /// Keys currently held down.
+    down: [bool; 128],
+    sustain: bool,
+    /// Keys that were down when the sostenuto pedal went down.
+    latched: [bool; 128],
+    /// Keys released while a pedal holds them, still sounding.
+    sustained: Vec<u8>,
+    /// Plays along with the events from the receiver.
+    sequencer: Option<Sequencer>,
+    /// Takes the presses and releases before the voices do.
+    arpeggiator: Option<Arpeggiator>,
+    /// A song playing along.
+    playback: Option<Playback>,
+    /// Repeats what was played from the receiver.
+    looper: Option<Looper>,
+    right: Option<f32>,
+}
+
+impl<S> Polyphony<S>
+where
+    S: Iterator<Item = MusicData> + Source,
+{
+    /// Creates `voice_count` voices with `new_voice`, which is handed the
+    /// receiving end of the voice's event channel.
+    pub fn new<F>(
+        sample_rate: u32,
+        voice_count: usize,
+        receiver: Receiver<NoteEvent>,
+        mut new_voice: F,
+    ) -> Polyphony<S>
+    where
+        F: FnMut(Receiver<NoteEvent>) -> Envelope<S>,
+    {
+        let voices = (0..voice_count.max(1))
+            .map(|_| {
+                let (sender, voice_receiver) = mpsc::channel();
+                Voice {
+                    sender,
+                    envelope: new_voice(voice_receiver),
+                    key: None,
+                    started: 0,
+                }
+            })
+            .collect();
+
+        Polyphony {
+            sample_rate,
+            receiver,
+            voices,
+            press_count: 0,
+            mode: VoiceMode::Poly,
+            velocity_curve: VelocityCurve::Linear,
+            held: Vec::with_capacity(128),
+            down: [false; 128],
+            sustain: false,
+            latched: [false; 128],
+            sustained: Vec::with_capacity(128),
+            sequencer: None,
+            arpeggiator: None,
+            playback: None,
+            looper: None,
+            right: None,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: VoiceMode) {
+        self.mode = mode;
+        self.held.clear();
+    }
+
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_curve = curve;
+    }
+
+    pub fn set_sequencer(&mut self, sequencer: Option<Sequencer>) {
+        self.sequencer = sequencer;
+    }
+
+    pub fn set_arpeggiator(&mut self, arpeggiator: Option<Arpeggiator>) {
+        self.arpeggiator = arpeggiator;
+    }
+
+    pub fn set_playback(&mut self, playback: Option<Playback>) {
+        self.playback = playback;
+    }
+
+    pub fn set_looper(&mut self, looper: Option<Looper>) {
+        self.looper = looper;
+    }
+
+    /// Picks the voice for a new note: one already playing the key, an idle
+    /// one, or failing that the one started longest ago.
+    fn allocate(&self, key: u8) -> usize {
+        if let Some(index) = self.voices.iter().position(|v| v.key == Some(key)) {
+            return index;
+        }
+        if let Some(index) = self.voices.iter().position(|v| !v.envelope.is_active()) {
+            return index;
+        }
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| v.started)
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        let event = match event {
+            NoteEvent::Press(mut note) => {
+                note.velocity = self.velocity_curve.apply(note.velocity);
+                // a sustained key that is struck again is held once more
+                self.sustained.retain(|&key| key != note.key);
+                self.down[note.key as usize & 127] = true;
+                NoteEvent::Press(note)
+            }
+            NoteEvent::Up(key) => {
+                self.down[key as usize & 127] = false;
+                event
+            }
+            event => event,
+        };
+        match (event, self.mode) {
+            (NoteEvent::Up(key), _) if self.is_held_by_pedal(key) => {
+                if !self.sustained.contains(&key) {
+                    self.sustained.push(key);
+                }
+            }
+            (NoteEvent::Pedal(Pedal::Sustain, down), _) => {
+                self.sustain = down;
+                self.release_sustained();
+            }
+            (NoteEvent::Pedal(Pedal::Sostenuto, down), _) => {
+                self.latched = if down { self.down } else { [false; 128] };
+                self.release_sustained();
+            }
+            (NoteEvent::Press(_), _) | (NoteEvent::Up(_), _) => match self.arpeggiator.as_mut() {
+                Some(arpeggiator) => arpeggiator.handle_event(event),
+                None => self.play(event),
+            },
+            (NoteEvent::Transport(Transport::Loop), _) => {
+                if let Some(looper) = self.looper.as_mut() {
+                    looper.record();
+                }
+            }
+            (NoteEvent::Transport(Transport::ClearLoop), _) => {
+                if let Some(mut looper) = self.looper.take() {
+                    looper.clear(|event| self.handle_event(event));
+                    self.looper = Some(looper);
+                }
+            }
+            (NoteEvent::Transport(transport), _) => {
+                if let Some(sequencer) = self.sequencer.as_mut() {
+                    sequencer.transport(transport);
+                }
+            }
+            (NoteEvent::Parameter(Parameter::Tempo, tempo), _) => {
+                if let Some(sequencer) = self.sequencer.as_mut() {
+                    sequencer.set_tempo(tempo);
+                }
+                if let Some(arpeggiator) = self.arpeggiator.as_mut() {
+                    arpeggiator.set_tempo(tempo);
+                }
+                if let Some(looper) = self.looper.as_mut() {
+                    looper.set_tempo(tempo);
+                }
+            }
+            (NoteEvent::Parameter(Parameter::Swing, swing), _) => {
+                if let Some(sequencer) = self.sequencer.as_mut() {
+                    sequencer.set_swing(swing);
+                }
+            }
+            (NoteEvent::Parameter(parameter, _), _) if parameter.is_voice() => {
+                for voice in self.voices.iter() {
+                    let _ = voice.sender.send(event);
+                }
+            }
+            (NoteEvent::PitchBend(_), _)
+            | (NoteEvent::Modulation(_), _)
+            | (NoteEvent::Pressure(_), _) => {
+                for voice in self.voices.iter() {
+                    let _ = voice.sender.send(event);
+                }
+            }
+            // the other parameters are for the stages after the voices, they pass on downstream
+            (NoteEvent::Hold, _) | (NoteEvent::Legato(_), _) | (NoteEvent::Parameter(..), _) => {}
+        }
+    }
+
+    /// Starts or releases a note on the voices.
+    fn play(&mut self, event: NoteEvent) {
+        match (event, self.mode) {
+            (NoteEvent::Press(note), VoiceMode::Poly) => {
+                let index = self.allocate(note.key);
+                self.press_count += 1;
+                let voice = &mut self.voices[index];
+                voice.key = Some(note.key);
+                voice.started = self.press_count;
+                let _ = voice.sender.send(event);
+            }
+            (NoteEvent::Up(key), VoiceMode::Poly) => {
+                for voice in self.voices.iter_mut().filter(|v| v.key == Some(key)) {
+                    voice.key = None;
+                    let _ = voice.sender.send(event);
+                }
+            }
+            (NoteEvent::Press(note), VoiceMode::Mono(priority))
+            | (NoteEvent::Press(note), VoiceMode::Legato(priority)) => {
+                self.held.retain(|held| held.key != note.key);
+                if self.held.len() < self.held.capacity() {
+                    self.held.push(note);
+                }
+                self.play_held_note(priority);
+            }
+            (NoteEvent::Up(key), VoiceMode::Mono(priority))
+            | (NoteEvent::Up(key), VoiceMode::Legato(priority)) => {
+                self.held.retain(|held| held.key != key);
+                if self.held.is_empty() {
+                    let voice = &mut self.voices[0];
+                    if let Some(sounding) = voice.key.take() {
+                        let _ = voice.sender.send(NoteEvent::Up(sounding));
+                    }
+                } else {
+                    self.play_held_note(priority);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_step(&mut self, event: StepEvent) {
+        match (event, self.mode) {
+            (StepEvent::Press(note), _) => self.handle_event(NoteEvent::Press(note)),
+            (StepEvent::Up(key), _) => self.handle_event(NoteEvent::Up(key)),
+            (StepEvent::Slide(from, note), VoiceMode::Poly) => {
+                match self.voices.iter_mut().find(|v| v.key == Some(from)) {
+                    Some(voice) => {
+                        voice.key = Some(note.key);
+                        let _ = voice.sender.send(NoteEvent::Legato(note));
+                        self.down[from as usize & 127] = false;
+                        self.down[note.key as usize & 127] = true;
+                    }
+                    None => {
+                        self.handle_event(NoteEvent::Press(note));
+                        self.handle_event(NoteEvent::Up(from));
+                    }
+                }
+            }
+            // the mono modes slide when the new note is held before the old one is let go
+            (StepEvent::Slide(from, note), _) => {
+                self.handle_event(NoteEvent::Press(note));
+                self.handle_event(NoteEvent::Up(from));
+            }
+        }
+    }
+
+    /// Plays what the song, the sequencer, the loop and the arpeggiator have
+    /// due at this frame.
+    fn tick(&mut self) {
+        // taken out while they run, as their events change the voices
+        if let Some(mut playback) = self.playback.take() {
+            playback.tick(|event| self.handle_event(event));
+            self.playback = Some(playback);
+        }
+        if let Some(mut sequencer) = self.sequencer.take() {
+            sequencer.tick(|event| self.handle_step(event));
+            self.sequencer = Some(sequencer);
+        }
+        // the loop keeps to the sequencer's bars while it plays
+        let sequencer_bar = self
+            .sequencer
+            .as_ref()
+            .filter(|sequencer| sequencer.is_playing())
+            .map(|sequencer| sequencer.bar_started());
+        if let Some(mut looper) = self.looper.take() {
+            looper.tick(sequencer_bar, |event| self.handle_event(event));
+            self.looper = Some(looper);
+        }
+        if let Some(mut arpeggiator) = self.arpeggiator.take() {
+            arpeggiator.tick(|event| self.play(event));
+            self.arpeggiator = Some(arpeggiator);
+        }
+    }
+
+    fn is_held_by_pedal(&self, key: u8) -> bool {
+        self.sustain || self.latched[key as usize & 127]
+    }
+
+    /// Releases the sustained keys no pedal holds any longer.
+    fn release_sustained(&mut self) {
+        let mut index = 0;
+        while index < self.sustained.len() {
+            let key = self.sustained[index];
+            if self.is_held_by_pedal(key) {
+                index += 1;
+            } else {
+                self.sustained.swap_remove(index);
+                self.handle_event(NoteEvent::Up(key));
+            }
+        }
+    }
+
+    /// Makes the single voice of the mono modes play the held key that has
+    /// priority, if it is not already playing it.
+    fn play_held_note(&mut self, priority: NotePriority) {
+        let note = match priority {
+            NotePriority::Last => self.held.last(),
+            NotePriority::Low => self.held.iter().min_by_key(|note| note.key),
+            NotePriority::High => self.held.iter().max_by_key(|note| note.key),
+        };
+        let note = match note {
+            Some(note) => *note,
+            None => return,
+        };
+
+        let legato = matches!(self.mode, VoiceMode::Legato(_));
+        let voice = &mut self.voices[0];
+        if voice.key == Some(note.key) {
+            return;
+        }
+        let event = if legato && voice.key.is_some() {
+            NoteEvent::Legato(note)
+        } else {
+            NoteEvent::Press(note)
+        };
+        voice.key = Some(note.key);
+        let _ = voice.sender.send(event);
+    }
+
+    /// Mixes one sample of every voice into a stereo frame.
+    fn get_frame(&mut self) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for voice in self.voices.iter_mut() {
+            if let Some(data) = voice.envelope.next() {
+                if let Some(voice_right) = data.right {
+                    left += data.wave_data;
+                    right += voice_right;
+                } else {
+                    let pan = data.pan.clamp(-1.0, 1.0);
+                    left += data.wave_data * (1.0 - pan).min(1.0);
+                    right += data.wave_data * (1.0 + pan).min(1.0);
+                }
+            }
+        }
+        (left, right)
+    }
+}
+
+impl<S> Source for Polyphony<S>
+where
+    S: Iterator<Item = MusicData> + Source,
+{
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl<S> Iterator for Polyphony<S>
+where
+    S: Iterator<Item = MusicData> + Source,
+{
+    type Item = MusicData;
+
+    /// Yields interleaved left and right samples. Like the oscillators, at
+    /// most one event is taken from the receiver per sample, and it is passed
+    /// on with that sample.
+    fn next(&mut self) -> Option<Self::Item> {
+        let current_event = self.receiver.try_recv().ok();
+        if let Some(event) = current_event {
+            // only what is played live goes into the loop
+            if let Some(looper) = self.looper.as_mut() {
+                looper.handle_event(event);
+            }
+            self.handle_event(event);
+        }
+
+        let wave_data = match self.right.take() {
+            Some(right) => right,
+            None => {
+                self.tick();
+                let (left, right) = self.get_frame();
+                self.right = Some(right);
+                left
+            }
+        };
+
+        Some(MusicData {
+            current_event,
+            wave_data,
+            envelope: None,
+            pan: 0.0,
+            right: None,
+        })
+    }
+}
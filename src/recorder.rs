@@ -0,0 +1,105 @@
+use std::sync::mpsc::{SendError, Sender};
+use std::time::Instant;
+
+use crate::midi_file::{MidiMessage, TimedMessage};
+use crate::musical_keyboard::{NoteEvent, Pedal};
+
+/// Sends what is played straight to the voices, and to the recorder with the
+/// time it was played, so recording adds nothing on the way to the voices.
+#[derive(Clone)]
+pub struct Input {
+    synth: Sender<NoteEvent>,
+    recorder: Sender<(Instant, NoteEvent)>,
+}
+
+impl Input {
+    pub fn new(synth: Sender<NoteEvent>, recorder: Sender<(Instant, NoteEvent)>) -> Input {
+        Input { synth, recorder }
+    }
+
+    pub fn send(&self, event: NoteEvent) -> Result<(), SendError<NoteEvent>> {
+        // the recorder going away is no reason to stop playing
+        let _ = self.recorder.send((Instant::now(), event));
+        self.synth.send(event)
+    }
+}
+
+/// Records what is played, from every input, as MIDI messages on channel 1
+/// timed from when the recording started.
+pub struct Recorder {
+    /// Semitones a full pitch bend is written as.
+    bend_range: f32,
+    started: Option<Instant>,
+    messages: Vec<TimedMessage>,
+}
+
+impl Recorder {
+    pub fn new(bend_range: f32) -> Recorder {
+        Recorder {
+            bend_range,
+            started: None,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.started = Some(Instant::now());
+        self.messages.clear();
+    }
+
+    /// Stops recording and hands over what was recorded.
+    pub fn stop(&mut self) -> Vec<TimedMessage> {
+        self.started = None;
+        std::mem::take(&mut self.messages)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Records an event played at `time`, which is left out when recording
+    /// started after it.
+    pub fn record(&mut self, time: Instant, event: &NoteEvent) {
+        let elapsed = match self
+            .started
+            .and_then(|started| time.checked_duration_since(started))
+        {
+            Some(elapsed) => elapsed,
+            None => return,
+        };
+        let channel = 0;
+        let controller = |controller, value| MidiMessage::Controller {
+            channel,
+            controller,
+            value,
+        };
+        let message = match *event {
+            NoteEvent::Press(note) => MidiMessage::NoteOn {
+                channel,
+                key: note.key,
+                velocity: note.velocity.max(1),
+            },
+            NoteEvent::Up(key) => MidiMessage::NoteOff { channel, key },
+            NoteEvent::PitchBend(semitones) => MidiMessage::PitchBend {
+                channel,
+                value: semitones / self.bend_range,
+            },
+            NoteEvent::Modulation(amount) => {
+                controller(1, (amount.clamp(0.0, 1.0) * 127.0).round() as u8)
+            }
+            NoteEvent::Pressure(value) => MidiMessage::Pressure { channel, value },
+            NoteEvent::Pedal(pedal, down) => controller(
+                if pedal == Pedal::Sustain { 64 } else { 66 },
+                if down { 127 } else { 0 },
+            ),
+            NoteEvent::Hold
+            | NoteEvent::Legato(_)
+            | NoteEvent::Parameter(..)
+            | NoteEvent::Transport(_) => return,
+        };
+        self.messages.push(TimedMessage {
+            time: elapsed.as_secs_f64(),
+            message,
+        });
+    }
+}
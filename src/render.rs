@@ -0,0 +1,255 @@
+//! Offline rendering of MIDI files through a SoundFont to a WAV file.
+use std::{
+    path::Path,
+    sync::mpsc::{self, Sender},
+};
+
+use crate::envvelope::Envelope;
+use crate::midi_file::{MidiMessage, TimedMessage};
+use crate::musical_keyboard::{NoteEvent, Pedal};
+use crate::polyphony::Polyphony;
+use crate::sampler::Sampler;
+use crate::sf2::{SoundFont, PERCUSSION_BANK};
+use crate::tuning::Tuning;
+
+/// How long to keep rendering after the last message, for release tails.
+const TAIL_SECONDS: f64 = 2.0;
+const VOICES_PER_PRESET: usize = 24;
+const PERCUSSION_CHANNEL: u8 = 9;
+
+struct Channel {
+    bank: u16,
+    program: u16,
+    volume: f32,
+    expression: f32,
+    pan: f32,
+    /// Bend wheel position, -1.0 to 1.0.
+    bend: f32,
+    /// Semitones the bend wheel reaches, set through RPN 0.
+    bend_range: f32,
+    /// Registered parameter selected by CC 101 and 100.
+    rpn: (u8, u8),
+    /// Which player each held key was started on.
+    notes: [Option<usize>; 128],
+}
+
+impl Channel {
+    fn gains(&self) -> (f32, f32) {
+        let gain = (self.volume * self.expression).powi(2);
+        (
+            gain * (1.0 - self.pan).min(1.0),
+            gain * (1.0 + self.pan).min(1.0),
+        )
+    }
+}
+
+/// The voices playing one preset on one channel.
+struct Player {
+    channel: u8,
+    bank: u16,
+    program: u16,
+    sender: Sender<NoteEvent>,
+    voices: Polyphony<Sampler>,
+}
+
+pub struct Renderer<'a> {
+    sound_font: &'a SoundFont,
+    sample_rate: u32,
+    tuning: Tuning,
+    channels: Vec<Channel>,
+    players: Vec<Player>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(sound_font: &'a SoundFont, sample_rate: u32, tuning: Tuning) -> Renderer<'a> {
+        let channels = (0..16)
+            .map(|channel| Channel {
+                bank: if channel == PERCUSSION_CHANNEL {
+                    PERCUSSION_BANK
+                } else {
+                    0
+                },
+                program: 0,
+                volume: 100.0 / 127.0,
+                expression: 1.0,
+                pan: 0.0,
+                bend: 0.0,
+                bend_range: 2.0,
+                rpn: (127, 127),
+                notes: [None; 128],
+            })
+            .collect();
+
+        Renderer {
+            sound_font,
+            sample_rate,
+            tuning,
+            channels,
+            players: Vec::new(),
+        }
+    }
+
+    fn player(&mut self, channel: u8) -> Option<usize> {
+        let Channel {
+            bank,
+            program,
+            bend,
+            bend_range,
+            ..
+        } = self.channels[channel as usize];
+        if let Some(index) = self
+            .players
+            .iter()
+            .position(|p| p.channel == channel && p.bank == bank && p.program == program)
+        {
+            return Some(index);
+        }
+
+        let sample_map = &self.sound_font.preset(bank, program)?.sample_map;
+        let (sender, receiver) = mpsc::channel();
+        let sample_rate = self.sample_rate;
+        let voices = Polyphony::new(sample_rate, VOICES_PER_PRESET, receiver, |voice_receiver| {
+            Envelope::new(Sampler::new(
+                sample_rate,
+                sample_map.clone(),
+                voice_receiver,
+            ))
+        });
+        if bend != 0.0 {
+            let _ = sender.send(NoteEvent::PitchBend(bend * bend_range));
+        }
+        self.players.push(Player {
+            channel,
+            bank,
+            program,
+            sender,
+            voices,
+        });
+        Some(self.players.len() - 1)
+    }
+
+    fn send_bend(&mut self, channel: u8) {
+        let state = &self.channels[channel as usize];
+        self.send(channel, NoteEvent::PitchBend(state.bend * state.bend_range));
+    }
+
+    /// Sends an event to every player on the channel.
+    fn send(&self, channel: u8, event: NoteEvent) {
+        for player in self.players.iter().filter(|p| p.channel == channel) {
+            let _ = player.sender.send(event);
+        }
+    }
+
+    fn release(&mut self, channel: u8, key: u8) {
+        if let Some(index) = self.channels[channel as usize].notes[key as usize].take() {
+            let _ = self.players[index].sender.send(NoteEvent::Up(key));
+        }
+    }
+
+    fn handle_message(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => {
+                self.release(channel, key);
+                let note = match self.tuning.note(key, velocity) {
+                    Some(note) => note,
+                    None => return,
+                };
+                if let Some(index) = self.player(channel) {
+                    self.channels[channel as usize].notes[key as usize] = Some(index);
+                    let _ = self.players[index].sender.send(NoteEvent::Press(note));
+                }
+            }
+            MidiMessage::NoteOff { channel, key } => self.release(channel, key),
+            MidiMessage::ProgramChange { channel, program } => {
+                self.channels[channel as usize].program = program as u16
+            }
+            MidiMessage::PitchBend { channel, value } => {
+                self.channels[channel as usize].bend = value;
+                self.send_bend(channel);
+            }
+            MidiMessage::Pressure { channel, value } => {
+                self.send(channel, NoteEvent::Pressure(value))
+            }
+            MidiMessage::Controller {
+                channel,
+                controller,
+                value,
+            } => {
+                let state = &mut self.channels[channel as usize];
+                let raw = value;
+                let value = value as f32 / 127.0;
+                match controller {
+                    0 if channel != PERCUSSION_CHANNEL => state.bank = raw as u16,
+                    // data entry for the pitch bend range, semitones then cents
+                    6 if state.rpn == (0, 0) => {
+                        state.bend_range = raw as f32 + state.bend_range.fract();
+                        self.send_bend(channel);
+                    }
+                    38 if state.rpn == (0, 0) => {
+                        state.bend_range = state.bend_range.trunc() + raw.min(99) as f32 / 100.0;
+                        self.send_bend(channel);
+                    }
+                    1 => self.send(channel, NoteEvent::Modulation(value)),
+                    64 => self.send(channel, NoteEvent::Pedal(Pedal::Sustain, raw >= 64)),
+                    66 => self.send(channel, NoteEvent::Pedal(Pedal::Sostenuto, raw >= 64)),
+                    7 => state.volume = value,
+                    10 => state.pan = value * 2.0 - 1.0,
+                    11 => state.expression = value,
+                    100 => state.rpn.1 = raw,
+                    101 => state.rpn.0 = raw,
+                    // all notes off
+                    123 => {
+                        for key in 0..128 {
+                            self.release(channel, key);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Renders the messages, which must be sorted by time, into a 16 bit stereo WAV file.
+    pub fn render<P: AsRef<Path>>(
+        &mut self,
+        messages: &[TimedMessage],
+        path: P,
+    ) -> Result<(), hound::Error> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+
+        let end_time = messages.last().map(|m| m.time).unwrap_or(0.0) + TAIL_SECONDS;
+        let frame_count = (end_time * self.sample_rate as f64) as u64;
+        let mut next_message = 0;
+
+        for frame in 0..frame_count {
+            let time = frame as f64 / self.sample_rate as f64;
+            while next_message < messages.len() && messages[next_message].time <= time {
+                self.handle_message(messages[next_message].message);
+                next_message += 1;
+            }
+
+            let mut left = 0.0;
+            let mut right = 0.0;
+            for player in self.players.iter_mut() {
+                let (left_gain, right_gain) = self.channels[player.channel as usize].gains();
+                left += player.voices.next().map(|d| d.wave_data).unwrap_or(0.0) * left_gain;
+                right += player.voices.next().map(|d| d.wave_data).unwrap_or(0.0) * right_gain;
+            }
+
+            for sample in [left, right] {
+                writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+            }
+        }
+        writer.finalize()
+    }
+}
@@ -0,0 +1,198 @@
+use crate::effect::Effect;
+use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+
+/// Delay lengths of the Freeverb combs and allpasses, in samples at 44100 Hz.
+/// They are scaled to the actual sample rate so the room sounds the same
+/// at any rate.
+const COMB_LENGTHS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_LENGTHS: [usize; 4] = [556, 441, 341, 225];
+/// How much longer the right channel's delays are, for decorrelation.
+const STEREO_SPREAD: usize = 23;
+const TUNING_SAMPLE_RATE: f32 = 44100.0;
+const MAX_PRE_DELAY_SECONDS: f32 = 0.5;
+/// Keeps the sum of the eight combs in range.
+const INPUT_GAIN: f32 = 0.015;
+
+#[derive(Copy, Clone)]
+pub struct ReverbSettings {
+    /// 0.0 (small) to 1.0 (large), sets how long the tail rings.
+    pub room_size: f32,
+    /// 0.0 (bright) to 1.0 (dark), how quickly the highs die away.
+    pub damping: f32,
+    /// Seconds before the reverb starts.
+    pub pre_delay: f32,
+    /// 0.0 (mono) to 1.0 (full stereo).
+    pub width: f32,
+    /// 0.0 (dry only) to 1.0 (reverb only).
+    pub mix: f32,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        ReverbSettings {
+            room_size: 0.5,
+            damping: 0.5,
+            pre_delay: 0.02,
+            width: 1.0,
+            mix: 0.0,
+        }
+    }
+}
+
+/// Feedback comb filter with a low pass in the loop.
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Comb {
+        Comb {
+            buffer: vec![0.0; length.max(1)],
+            index: 0,
+            filter_store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.buffer[self.index] = input + self.filter_store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+/// Schroeder allpass, smearing the echoes of the combs into a dense tail.
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Allpass {
+        Allpass {
+            buffer: vec![0.0; length.max(1)],
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// The combs and allpasses of one output channel.
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Tank {
+    fn new(scale: f32, spread: usize) -> Tank {
+        let length = |tuning: usize| ((tuning + spread) as f32 * scale) as usize;
+        Tank {
+            combs: COMB_LENGTHS.iter().map(|&l| Comb::new(length(l))).collect(),
+            allpasses: ALLPASS_LENGTHS
+                .iter()
+                .map(|&l| Allpass::new(length(l)))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let mut output = 0.0;
+        for comb in self.combs.iter_mut() {
+            output += comb.process(input, feedback, damping);
+        }
+        for allpass in self.allpasses.iter_mut() {
+            output = allpass.process(output);
+        }
+        output
+    }
+}
+
+/// A Freeverb style stereo reverb: eight parallel combs and four allpasses
+/// in series per channel, fed with the mono sum of the input.
+pub struct Reverb {
+    settings: ReverbSettings,
+    pre_delay: Vec<f32>,
+    pre_delay_index: usize,
+    left: Tank,
+    right: Tank,
+    /// Sample rate relative to the rate the delays are tuned for.
+    scale: f32,
+    feedback: f32,
+    damping: f32,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32, settings: ReverbSettings) -> Reverb {
+        let sample_rate = sample_rate as f32;
+        let scale = sample_rate / TUNING_SAMPLE_RATE;
+        let pre_delay =
+            (settings.pre_delay.clamp(0.0, MAX_PRE_DELAY_SECONDS) * sample_rate) as usize + 1;
+
+        let mut reverb = Reverb {
+            settings,
+            pre_delay: vec![0.0; pre_delay],
+            pre_delay_index: 0,
+            left: Tank::new(scale, 0),
+            right: Tank::new(scale, STEREO_SPREAD),
+            scale,
+            feedback: 0.0,
+            damping: 0.0,
+        };
+        reverb.set_room_size(settings.room_size);
+        reverb.set_damping(settings.damping);
+        reverb
+    }
+
+    fn set_room_size(&mut self, room_size: f32) {
+        self.settings.room_size = room_size;
+        self.feedback = room_size.clamp(0.0, 1.0) * 0.28 + 0.7;
+    }
+
+    fn set_damping(&mut self, damping: f32) {
+        self.settings.damping = damping;
+        // the damping filter runs once per sample, so at higher rates it has
+        // to be gentler for the same effect per second
+        self.damping = (damping.clamp(0.0, 1.0) * 0.4).powf(1.0 / self.scale);
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let input = self.pre_delay[self.pre_delay_index];
+        self.pre_delay[self.pre_delay_index] = (left + right) * INPUT_GAIN;
+        self.pre_delay_index = (self.pre_delay_index + 1) % self.pre_delay.len();
+
+        let wet_left = self.left.process(input, self.feedback, self.damping);
+        let wet_right = self.right.process(input, self.feedback, self.damping);
+
+        let mix = self.settings.mix.clamp(0.0, 1.0);
+        let width = self.settings.width.clamp(0.0, 1.0);
+        // Freeverb's wet scaling, brings the tail up to about the dry level
+        let wet = mix * 3.0;
+        let wet1 = wet * (width / 2.0 + 0.5);
+        let wet2 = wet * ((1.0 - width) / 2.0);
+        (
+            left * (1.0 - mix) + wet_left * wet1 + wet_right * wet2,
+            right * (1.0 - mix) + wet_right * wet1 + wet_left * wet2,
+        )
+    }
+
+    fn handle_event(&mut self, event: NoteEvent) {
+        match event {
+            NoteEvent::Parameter(Parameter::ReverbMix, mix) => self.settings.mix = mix,
+            NoteEvent::Parameter(Parameter::RoomSize, room_size) => self.set_room_size(room_size),
+            NoteEvent::Parameter(Parameter::Damping, damping) => self.set_damping(damping),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use rodio::Source;
+use std::{
+    path::Path,
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+use crate::envvelope::EnvelopeSettings;
+use crate::music_data::MusicData;
+use crate::musical_keyboard::{frequency_from_key, Note, NoteEvent};
+use crate::pitch::{GlideSettings, Pitch};
+
+/// A recorded sample, mixed down to mono.
+pub struct SampleData {
+    pub frames: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+impl SampleData {
+    pub fn load_wav<P: AsRef<Path>>(path: P) -> Result<SampleData, hound::Error> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let channels = spec.channels.max(1) as usize;
+        let frames = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+
+        Ok(SampleData {
+            frames,
+            sample_rate: spec.sample_rate,
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LoopMode {
+    /// Play the sample once, stop at its end.
+    NoLoop,
+    /// Play the whole sample once, ignoring the key release.
+    OneShot,
+    /// Loop between the loop points for as long as the voice sounds.
+    LoopContinuous,
+    /// Loop while the key is held, then play on past the loop end.
+    LoopSustain,
+}
+
+/// A sample mapped to a range of keys and velocities.
+#[derive(Clone)]
+pub struct Region {
+    pub sample: Arc<SampleData>,
+    pub lokey: u8,
+    pub hikey: u8,
+    pub lovel: u8,
+    pub hivel: u8,
+    /// The key at which the sample plays back at its recorded pitch.
+    pub pitch_keycenter: u8,
+    /// Fine tuning in cents.
+    pub tune: f32,
+    /// Linear gain applied to the sample.
+    pub gain: f32,
+    pub loop_mode: LoopMode,
+    /// First frame of the loop.
+    pub loop_start: usize,
+    /// Frame just past the end of the loop.
+    pub loop_end: usize,
+    pub envelope: EnvelopeSettings,
+    /// Stereo position, -1.0 (left) to 1.0 (right).
+    pub pan: f32,
+}
+
+impl Region {
+    pub fn new(sample: Arc<SampleData>) -> Region {
+        let loop_end = sample.frames.len();
+        Region {
+            sample,
+            lokey: 0,
+            hikey: 127,
+            lovel: 0,
+            hivel: 127,
+            pitch_keycenter: 60,
+            tune: 0.0,
+            gain: 1.0,
+            loop_mode: LoopMode::NoLoop,
+            loop_start: 0,
+            loop_end,
+            envelope: EnvelopeSettings {
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                // long enough not to click when the key is let go
+                release: 0.01,
+                ..EnvelopeSettings::default()
+            },
+            pan: 0.0,
+        }
+    }
+
+    fn matches(&self, note: &Note) -> bool {
+        (self.lokey..=self.hikey).contains(&note.key)
+            && (self.lovel..=self.hivel).contains(&note.velocity)
+    }
+
+    /// Frequency the sample sounds at when played back unshifted.
+    fn root_frequency(&self) -> f32 {
+        frequency_from_key(self.pitch_keycenter) * 2.0_f32.powf(-self.tune / 1200.0)
+    }
+}
+
+/// The key/velocity map of a sampled instrument.
+#[derive(Default)]
+pub struct SampleMap {
+    pub regions: Vec<Region>,
+}
+
+impl SampleMap {
+    /// Index of the first region that should play `note`.
+    fn find(&self, note: &Note) -> Option<usize> {
+        self.regions.iter().position(|region| region.matches(note))
+    }
+}
+
+/// A voice that plays back the samples of a `SampleMap`, pitch shifted to the
+/// frequency of the pressed note.
+pub struct Sampler {
+    sample_rate: u32,
+    receiver: Receiver<NoteEvent>,
+    sample_map: Arc<SampleMap>,
+    region: Option<usize>,
+    /// The key playing, let go of when the sample ends.
+    key: u8,
+    position: f64,
+    pitch: Pitch,
+    released: bool,
+    current_event: Option<NoteEvent>,
+    envelope: Option<EnvelopeSettings>,
+    pan: f32,
+}
+
+impl Sampler {
+    pub fn new(
+        sample_rate: u32,
+        sample_map: Arc<SampleMap>,
+        receiver: Receiver<NoteEvent>,
+    ) -> Sampler {
+        Sampler {
+            sample_rate,
+            receiver,
+            sample_map,
+            region: None,
+            key: 0,
+            position: 0.0,
+            pitch: Pitch::new(sample_rate, GlideSettings::default()),
+            released: false,
+            current_event: None,
+            envelope: None,
+            pan: 0.0,
+        }
+    }
+
+    pub fn set_glide(&mut self, glide: GlideSettings) {
+        self.pitch.set_glide(glide);
+    }
+
+    fn start(&mut self, note: Note) {
+        self.region = self.sample_map.find(&note);
+        self.key = note.key;
+        self.position = 0.0;
+        self.released = false;
+        self.envelope = None;
+        self.pitch.set_frequency(note.frequency);
+
+        if let Some(index) = self.region {
+            let region = &self.sample_map.regions[index];
+            self.envelope = Some(region.envelope);
+            self.pan = region.pan;
+        }
+    }
+
+    fn get_sample(&mut self) -> f32 {
+        self.current_event = None;
+        self.envelope = None;
+        if let Ok(event) = self.receiver.try_recv() {
+            self.current_event = Some(event);
+            match event {
+                NoteEvent::Press(note) => self.start(note),
+                // keep playing the current sample, only the pitch changes
+                NoteEvent::Legato(note) => {
+                    self.key = note.key;
+                    self.pitch.set_frequency(note.frequency)
+                }
+                NoteEvent::PitchBend(semitones) => self.pitch.set_bend(semitones),
+                NoteEvent::Modulation(amount) => self.pitch.set_modulation(amount),
+                NoteEvent::Parameter(parameter, value) => {
+                    self.pitch.set_parameter(parameter, value)
+                }
+                NoteEvent::Hold
+                | NoteEvent::Pressure(_)
+                | NoteEvent::Pedal(..)
+                | NoteEvent::Transport(_) => {}
+                NoteEvent::Up(_) => {
+                    self.released = true;
+                    // one shot samples play to their end, so the envelope never hears the release
+                    if let Some(index) = self.region {
+                        if self.sample_map.regions[index].loop_mode == LoopMode::OneShot {
+                            self.current_event = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        let frequency = self.pitch.next_frequency();
+        let index = match self.region {
+            Some(index) => index,
+            None => return 0.0,
+        };
+        let region = &self.sample_map.regions[index];
+        let frames = &region.sample.frames;
+
+        let looping = match region.loop_mode {
+            LoopMode::LoopContinuous => true,
+            LoopMode::LoopSustain => !self.released,
+            LoopMode::NoLoop | LoopMode::OneShot => false,
+        } && region.loop_start < region.loop_end
+            && region.loop_end <= frames.len();
+
+        if looping {
+            let loop_length = (region.loop_end - region.loop_start) as f64;
+            while self.position >= region.loop_end as f64 {
+                self.position -= loop_length;
+            }
+        } else if self.position >= frames.len() as f64 {
+            // the envelope is let go so the voice is freed, one shot samples
+            // never sent it the release. A sample carries one event, so this
+            // waits for one without
+            if self.current_event.is_none() {
+                self.current_event = Some(NoteEvent::Up(self.key));
+                self.region = None;
+            }
+            return 0.0;
+        }
+
+        // 4 point Hermite interpolation around the read position
+        let frame = |i: i64| -> f32 {
+            let mut i = i;
+            if looping && i >= region.loop_end as i64 {
+                i -= (region.loop_end - region.loop_start) as i64;
+            }
+            if i < 0 || i >= frames.len() as i64 {
+                0.0
+            } else {
+                frames[i as usize]
+            }
+        };
+        let whole = self.position.floor() as i64;
+        let t = (self.position - whole as f64) as f32;
+        let (y0, y1, y2, y3) = (
+            frame(whole - 1),
+            frame(whole),
+            frame(whole + 1),
+            frame(whole + 2),
+        );
+        let c1 = 0.5 * (y2 - y0);
+        let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+        let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+        let sample = ((c3 * t + c2) * t + c1) * t + y1;
+
+        self.position += (frequency / region.root_frequency()) as f64
+            * region.sample.sample_rate as f64
+            / self.sample_rate as f64;
+
+        sample * region.gain
+    }
+}
+
+impl Source for Sampler {
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Iterator for Sampler {
+    type Item = MusicData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let wave_data = self.get_sample();
+        Some(MusicData {
+            current_event: self.current_event,
+            wave_data,
+            envelope: self.envelope,
+            pan: self.pan,
+            right: None,
+        })
+    }
+}
@@ -3,53 +3,171 @@ use rodio::Source;
 use std::{sync::mpsc::Receiver, time::Duration};
 
 use crate::musical_keyboard::NoteEvent;
+use crate::parameter::Parameter;
+use crate::pitch::{GlideSettings, Pitch};
+
+/// Waveforms built from a sum of harmonics.
+#[derive(Copy, Clone, PartialEq)]
+pub enum OscillatorShape {
+    /// Every harmonic, falling off as 1/n.
+    Saw,
+    /// The odd harmonics, falling off as 1/n.
+    Square,
+    /// The odd harmonics with alternating signs, falling off as 1/n².
+    Triangle,
+    /// The fundamental alone.
+    Sine,
+}
+
+impl OscillatorShape {
+    const ALL: [OscillatorShape; 4] = [
+        OscillatorShape::Saw,
+        OscillatorShape::Square,
+        OscillatorShape::Triangle,
+        OscillatorShape::Sine,
+    ];
+
+    pub fn from_name(name: &str) -> Option<OscillatorShape> {
+        OscillatorShape::ALL
+            .iter()
+            .find(|shape| shape.name() == name)
+            .copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OscillatorShape::Saw => "saw",
+            OscillatorShape::Square => "square",
+            OscillatorShape::Triangle => "triangle",
+            OscillatorShape::Sine => "sine",
+        }
+    }
+
+    /// The number `Parameter::Shape` carries for the shape.
+    pub fn index(&self) -> f32 {
+        *self as usize as f32
+    }
+
+    pub fn from_index(index: f32) -> Option<OscillatorShape> {
+        OscillatorShape::ALL.get(index.round() as usize).copied()
+    }
+
+    /// Level of harmonic `n` relative to the fundamental.
+    fn harmonic(&self, n: usize) -> f32 {
+        let odd = n % 2 == 1;
+        match self {
+            OscillatorShape::Saw => 1.0 / n as f32,
+            OscillatorShape::Square if odd => 1.0 / n as f32,
+            OscillatorShape::Triangle if odd => {
+                let sign = if n % 4 == 1 { 1.0 } else { -1.0 };
+                sign / (n * n) as f32
+            }
+            OscillatorShape::Sine if n == 1 => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Brings the peak of the sum of harmonics to about 1.0.
+    fn gain(&self) -> f32 {
+        match self {
+            OscillatorShape::Saw => 2.0 / std::f32::consts::PI,
+            OscillatorShape::Square => 4.0 / std::f32::consts::PI,
+            OscillatorShape::Triangle => 8.0 / (std::f32::consts::PI * std::f32::consts::PI),
+            OscillatorShape::Sine => 1.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct OscillatorSettings {
+    pub shape: OscillatorShape,
+    /// The most harmonics added, 0 for one per octave up to half the
+    /// sample rate, which keeps high notes free of aliasing and low ones
+    /// cheap.
+    pub harmonics: u32,
+    /// Level lost per second while a note plays, so held notes fade.
+    pub decay: f32,
+}
+
+impl Default for OscillatorSettings {
+    fn default() -> Self {
+        OscillatorSettings {
+            shape: OscillatorShape::Saw,
+            harmonics: 0,
+            decay: 0.441,
+        }
+    }
+}
 
 pub struct SawWaveOscilatorBandLimited {
     sample_rate: u32,
     receiver: Receiver<NoteEvent>,
+    settings: OscillatorSettings,
     amplitude: f32,
     current_frequency: f32,
+    pitch: Pitch,
     phase: f32,
     current_event: Option<NoteEvent>,
 }
 
 impl SawWaveOscilatorBandLimited {
+    #[allow(clippy::needless_return)]
     pub fn new(sample_rate: u32, receiver: Receiver<NoteEvent>) -> SawWaveOscilatorBandLimited {
         return SawWaveOscilatorBandLimited {
             sample_rate,
             receiver,
+            settings: OscillatorSettings::default(),
             amplitude: 0.0,
             current_frequency: 0.0,
+            pitch: Pitch::new(sample_rate, GlideSettings::default()),
             phase: 0.0,
             current_event: None,
         };
     }
 
+    pub fn set_glide(&mut self, glide: GlideSettings) {
+        self.pitch.set_glide(glide);
+    }
+
     fn set_frequency(&mut self, frequency: f32) {
-        if frequency != self.current_frequency {
-            self.current_frequency = frequency;
-        }
+        self.pitch.set_frequency(frequency);
     }
 
+    #[allow(clippy::needless_return)]
     fn get_sample(&mut self) -> f32 {
         self.current_event = None;
-        match self.receiver.try_recv() {
-            Ok(f) => {
-                self.amplitude = 1.0;
-                match f {
-                    NoteEvent::Press(frequency) => self.set_frequency(frequency),
-                    NoteEvent::Hold => {}
-                    NoteEvent::Up => {}
+        if let Ok(f) = self.receiver.try_recv() {
+            match f {
+                NoteEvent::Press(note) | NoteEvent::Legato(note) => {
+                    self.amplitude = 1.0;
+                    self.set_frequency(note.frequency)
+                }
+                NoteEvent::Hold | NoteEvent::Up(_) => self.amplitude = 1.0,
+                NoteEvent::PitchBend(semitones) => self.pitch.set_bend(semitones),
+                NoteEvent::Modulation(amount) => self.pitch.set_modulation(amount),
+                NoteEvent::Parameter(Parameter::Shape, index) => {
+                    if let Some(shape) = OscillatorShape::from_index(index) {
+                        self.settings.shape = shape;
+                    }
+                }
+                NoteEvent::Parameter(Parameter::Harmonics, harmonics) => {
+                    self.settings.harmonics = harmonics.max(0.0) as u32
                 }
-                self.current_event = Some(f);
-                //self.set_frequency(f);
+                NoteEvent::Parameter(Parameter::OscillatorDecay, decay) => {
+                    self.settings.decay = decay.max(0.0)
+                }
+                NoteEvent::Parameter(parameter, value) => {
+                    self.pitch.set_parameter(parameter, value)
+                }
+                NoteEvent::Pressure(_) | NoteEvent::Pedal(..) | NoteEvent::Transport(_) => (),
             }
-            Err(_) => (),
+            self.current_event = Some(f);
+            //self.set_frequency(f);
         }
+        self.current_frequency = self.pitch.next_frequency();
 
         //advance the phase
-        self.phase = self.phase
-            + 2.0 * std::f32::consts::PI * self.current_frequency / self.sample_rate as f32;
+        self.phase += 2.0 * std::f32::consts::PI * self.current_frequency / self.sample_rate as f32;
 
         while self.phase >= 2.0 * std::f32::consts::PI {
             self.phase -= 2.0 * std::f32::consts::PI;
@@ -59,34 +177,45 @@ impl SawWaveOscilatorBandLimited {
             self.phase += 2.0 * std::f32::consts::PI;
         }
 
-        let mut number_of_harmonics = 0;
+        let nyquist = self.sample_rate as f32 * 0.5;
+        let mut number_of_harmonics = self.settings.harmonics as usize + 1;
         //if num harmonics is zero, calculate how many max harmonics we can do
         //without going over the nyquist frequency (half of sample rate frequency)
-        if number_of_harmonics == 0 && self.current_frequency != 0.0 {
+        if self.settings.harmonics == 0 && self.current_frequency != 0.0 {
+            number_of_harmonics = 0;
             let mut temporary_frequency = self.current_frequency;
 
-            while temporary_frequency < self.sample_rate as f32 * 0.5 {
-                number_of_harmonics = number_of_harmonics + 1;
-                temporary_frequency = temporary_frequency * 2.0;
+            while temporary_frequency < nyquist {
+                number_of_harmonics += 1;
+                temporary_frequency *= 2.0;
             }
         }
 
-        //calculate the saw wave sample
+        //calculate the sample, leaving out harmonics above the nyquist frequency
+        let shape = self.settings.shape;
         let mut result = 0.0;
-        for current_harmonic in 1..number_of_harmonics {
-            result =
-                result + (self.phase * current_harmonic as f32).sin() / current_harmonic as f32;
+        // the fundamental is always played
+        for current_harmonic in 1..number_of_harmonics.max(2) {
+            if current_harmonic as f32 * self.current_frequency >= nyquist {
+                break;
+            }
+            let level = shape.harmonic(current_harmonic);
+            if level != 0.0 {
+                result += (self.phase * current_harmonic as f32).sin() * level;
+            }
         }
 
         //adjust the volume
-        result = result * 2.0 / std::f32::consts::PI;
+        result *= shape.gain();
 
-        self.amplitude = clamp(self.amplitude - 0.00001, 0.0, 1.0); // decay
+        let decay = self.settings.decay / self.sample_rate as f32;
+        self.amplitude = clamp(self.amplitude - decay, 0.0, 1.0);
 
         return result * self.amplitude;
     }
 }
 
+#[allow(clippy::needless_return)]
 impl Source for SawWaveOscilatorBandLimited {
     fn channels(&self) -> u16 {
         return 1;
@@ -105,6 +234,7 @@ impl Source for SawWaveOscilatorBandLimited {
     }
 }
 
+#[allow(clippy::needless_return)]
 impl Iterator for SawWaveOscilatorBandLimited {
     type Item = crate::music_data::MusicData;
 
@@ -112,6 +242,9 @@ impl Iterator for SawWaveOscilatorBandLimited {
         return Some(crate::music_data::MusicData {
             current_event: self.current_event,
             wave_data: self.get_sample(),
+            envelope: None,
+            pan: 0.0,
+            right: None,
         });
     }
 }
@@ -0,0 +1,368 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::musical_keyboard::{key_from_name, Note};
+use crate::tuning::Tuning;
+
+/// The steps are sixteenth notes.
+const STEPS_PER_BEAT: f32 = 4.0;
+const STEPS_PER_BAR: usize = 16;
+/// How many steps a pattern can have.
+const PATTERN_LENGTHS: [usize; 3] = [16, 32, 64];
+const DEFAULT_VELOCITY: u8 = 100;
+const DEFAULT_GATE: f32 = 0.5;
+/// Added to the velocity of accented steps.
+const ACCENT: u8 = 32;
+pub const DEFAULT_TEMPO: f32 = 120.0;
+/// Straight sixteenths, see `Sequencer::set_swing`.
+pub const DEFAULT_SWING: f32 = 50.0;
+
+#[derive(Copy, Clone)]
+pub struct Step {
+    pub key: u8,
+    pub velocity: u8,
+    /// How long the note is held, as a fraction of the step.
+    pub gate: f32,
+    /// Hold the note into the next step and glide to it there without
+    /// restarting the envelope.
+    pub slide: bool,
+    pub accent: bool,
+}
+
+impl Step {
+    /// Reads a step such as `c3`, `f#2:a:s` or `60:v90:g0.25`: a note name
+    /// or key number followed by `v` and a velocity, `g` and a gate length,
+    /// `a` for accent and `s` for slide. A `.` is a rest.
+    fn parse(text: &str) -> Result<Option<Step>, String> {
+        if text == "." {
+            return Ok(None);
+        }
+        let mut parts = text.split(':');
+        let note = parts.next().unwrap_or("");
+        let key = match note.parse::<u8>() {
+            Ok(key) if key <= 127 => key,
+            _ => key_from_name(note).ok_or_else(|| format!("bad note {:?}", note))?,
+        };
+        let mut step = Step {
+            key,
+            velocity: DEFAULT_VELOCITY,
+            gate: DEFAULT_GATE,
+            slide: false,
+            accent: false,
+        };
+        for part in parts {
+            let bad = || format!("bad step {:?}", text);
+            match part {
+                "a" => step.accent = true,
+                "s" => step.slide = true,
+                _ if part.starts_with('v') => {
+                    step.velocity = match part[1..].parse() {
+                        Ok(velocity @ 1..=127) => velocity,
+                        _ => return Err(bad()),
+                    }
+                }
+                _ if part.starts_with('g') => {
+                    step.gate = part[1..]
+                        .parse()
+                        .ok()
+                        .filter(|gate| *gate > 0.0 && *gate <= 1.0)
+                        .ok_or_else(bad)?;
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(Some(step))
+    }
+
+    fn velocity(&self) -> u8 {
+        if self.accent {
+            self.velocity.saturating_add(ACCENT).min(127)
+        } else {
+            self.velocity
+        }
+    }
+}
+
+pub struct Pattern {
+    pub steps: Vec<Option<Step>>,
+}
+
+/// A sequence file:
+///
+/// ```toml
+/// tempo = 120
+/// swing = 56
+/// # pattern numbers, from 1; every pattern once, in order, when left out
+/// chain = [1, 1, 2]
+///
+/// [[pattern]]
+/// steps = """
+/// c2:a . c2 . c3:s c2 . . c2 . d#2:g0.9 . c2 . g2:v70 .
+/// """
+/// ```
+///
+/// A pattern has 16, 32 or 64 steps, see `Step::parse`.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct SequenceFile {
+    tempo: Option<f32>,
+    swing: Option<f32>,
+    chain: Option<Vec<usize>>,
+    pattern: Vec<PatternFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatternFile {
+    steps: String,
+}
+
+/// Patterns and the order to play them in.
+pub struct Sequence {
+    pub patterns: Vec<Pattern>,
+    /// Indices into `patterns`.
+    pub chain: Vec<usize>,
+    pub tempo: f32,
+    pub swing: f32,
+}
+
+impl Sequence {
+    /// Reads a sequence file, see `SequenceFile`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Sequence, String> {
+        let path = path.as_ref();
+        let error = |e: String| format!("{}: {}", path.display(), e);
+        let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let file: SequenceFile = toml::from_str(&text).map_err(|e| error(e.to_string()))?;
+
+        let mut patterns = Vec::new();
+        for (number, pattern) in file.pattern.iter().enumerate() {
+            let steps = pattern
+                .steps
+                .split_whitespace()
+                .map(Step::parse)
+                .collect::<Result<Vec<Option<Step>>, String>>()
+                .map_err(|e| error(format!("pattern {}: {}", number + 1, e)))?;
+            if !PATTERN_LENGTHS.contains(&steps.len()) {
+                return Err(error(format!(
+                    "pattern {} has {} steps instead of 16, 32 or 64",
+                    number + 1,
+                    steps.len()
+                )));
+            }
+            patterns.push(Pattern { steps });
+        }
+        if patterns.is_empty() {
+            return Err(error("no patterns".to_string()));
+        }
+        let chain = match file.chain {
+            Some(chain) => chain
+                .iter()
+                .map(|&number| match number {
+                    1.. if number <= patterns.len() => Ok(number - 1),
+                    _ => Err(error(format!("no pattern {} to chain", number))),
+                })
+                .collect::<Result<Vec<usize>, String>>()?,
+            None => (0..patterns.len()).collect(),
+        };
+        if chain.is_empty() {
+            return Err(error("empty chain".to_string()));
+        }
+        Ok(Sequence {
+            patterns,
+            chain,
+            tempo: file.tempo.unwrap_or(DEFAULT_TEMPO),
+            swing: file.swing.unwrap_or(DEFAULT_SWING),
+        })
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Transport {
+    Start,
+    Stop,
+    /// Play a pattern over and over once the current one ends, or go back
+    /// to the chain when None.
+    Cue(Option<usize>),
+    /// Record a loop, or turn overdubbing on and off once there is one.
+    Loop,
+    /// Forget the loop.
+    ClearLoop,
+}
+
+/// What the sequencer asks of the voices.
+pub enum StepEvent {
+    Press(Note),
+    /// Move the note playing the first key to a new note without
+    /// restarting it.
+    Slide(u8, Note),
+    Up(u8),
+}
+
+/// Plays a sequence, counting samples on the audio thread so every step
+/// starts on the exact sample it is due.
+pub struct Sequencer {
+    sample_rate: f32,
+    sequence: Sequence,
+    tuning: Tuning,
+    tempo: f32,
+    swing: f32,
+    playing: bool,
+    /// What to play after the current pattern, see `Transport::Cue`.
+    cued: Option<Option<usize>>,
+    /// The pattern that repeats, None while following the chain.
+    repeating: Option<usize>,
+    chain_position: usize,
+    pattern: usize,
+    step: usize,
+    /// Samples since the current step started, None before the first step.
+    elapsed: Option<f32>,
+    step_length: f32,
+    gate_length: f32,
+    /// Key of the note playing and whether it slides into the next step.
+    sounding: Option<(u8, bool)>,
+    /// Whether a bar started at the last tick.
+    bar_started: bool,
+}
+
+impl Sequencer {
+    pub fn new(sample_rate: u32, sequence: Sequence, tuning: Tuning) -> Sequencer {
+        Sequencer {
+            sample_rate: sample_rate as f32,
+            tempo: sequence.tempo,
+            swing: sequence.swing,
+            pattern: sequence.chain[0],
+            sequence,
+            tuning,
+            playing: false,
+            cued: None,
+            repeating: None,
+            chain_position: 0,
+            step: 0,
+            elapsed: None,
+            step_length: 0.0,
+            gate_length: 0.0,
+            sounding: None,
+            bar_started: false,
+        }
+    }
+
+    /// Beats per minute, taken up from the next step.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo.clamp(20.0, 400.0);
+    }
+
+    /// How far into each pair of steps the second one starts, in percent:
+    /// 50 plays straight and 66 close to triplets.
+    pub fn set_swing(&mut self, swing: f32) {
+        self.swing = swing.clamp(50.0, 75.0);
+    }
+
+    pub fn transport(&mut self, transport: Transport) {
+        match transport {
+            Transport::Start if !self.playing => {
+                self.playing = true;
+                self.elapsed = None;
+                self.step = 0;
+                self.chain_position = 0;
+                self.repeating = self.cued.take().unwrap_or(self.repeating);
+                self.pattern = self.repeating.unwrap_or(self.sequence.chain[0]);
+            }
+            Transport::Start => {}
+            Transport::Stop => self.playing = false,
+            Transport::Cue(Some(pattern)) if pattern >= self.sequence.patterns.len() => {}
+            Transport::Cue(cue) => self.cued = Some(cue),
+            Transport::Loop | Transport::ClearLoop => {}
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Whether a bar started at the last tick, for playing along in time.
+    pub fn bar_started(&self) -> bool {
+        self.bar_started
+    }
+
+    /// Advances by one sample, playing what is due.
+    pub fn tick<F: FnMut(StepEvent)>(&mut self, mut play: F) {
+        self.bar_started = false;
+        if !self.playing {
+            if let Some((key, _)) = self.sounding.take() {
+                play(StepEvent::Up(key));
+            }
+            return;
+        }
+
+        let elapsed = match self.elapsed {
+            None => {
+                self.start_step(&mut play);
+                0.0
+            }
+            // what is left over keeps the steps from drifting
+            Some(elapsed) if elapsed >= self.step_length => {
+                self.next_step();
+                self.start_step(&mut play);
+                elapsed - self.step_length
+            }
+            Some(elapsed) => elapsed,
+        };
+        if let Some((key, false)) = self.sounding {
+            if elapsed >= self.gate_length {
+                self.sounding = None;
+                play(StepEvent::Up(key));
+            }
+        }
+        self.elapsed = Some(elapsed + 1.0);
+    }
+
+    fn next_step(&mut self) {
+        self.step += 1;
+        if self.step < self.sequence.patterns[self.pattern].steps.len() {
+            return;
+        }
+        self.step = 0;
+        if let Some(cue) = self.cued.take() {
+            self.repeating = cue;
+            self.chain_position = self.sequence.chain.len() - 1;
+        }
+        self.pattern = match self.repeating {
+            Some(pattern) => pattern,
+            None => {
+                self.chain_position = (self.chain_position + 1) % self.sequence.chain.len();
+                self.sequence.chain[self.chain_position]
+            }
+        };
+    }
+
+    fn start_step<F: FnMut(StepEvent)>(&mut self, play: &mut F) {
+        self.bar_started = self.step.is_multiple_of(STEPS_PER_BAR);
+        let sixteenth = self.sample_rate * 60.0 / self.tempo / STEPS_PER_BEAT;
+        let swing = self.swing / 50.0;
+        self.step_length = if self.step.is_multiple_of(2) {
+            sixteenth * swing
+        } else {
+            sixteenth * (2.0 - swing)
+        };
+
+        let step = self.sequence.patterns[self.pattern].steps[self.step];
+        let note = step.and_then(|step| self.tuning.note(step.key, step.velocity()));
+        match (self.sounding.take(), note) {
+            (Some((from, true)), Some(note)) => play(StepEvent::Slide(from, note)),
+            (sounding, note) => {
+                if let Some((key, _)) = sounding {
+                    play(StepEvent::Up(key));
+                }
+                if let Some(note) = note {
+                    play(StepEvent::Press(note));
+                }
+            }
+        }
+        if let (Some(step), Some(note)) = (step, note) {
+            self.sounding = Some((note.key, step.slide));
+            self.gate_length = step.gate * self.step_length;
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! Loader for SoundFont 2 (.sf2) banks.
+//!
+//! Every preset is flattened into a `SampleMap`: each preset zone is combined
+//! with the zones of its instrument into sampler regions. Supported
+//! generators are key and velocity ranges, root key and tuning, loop points
+//! and modes, attenuation, pan and the volume envelope. Modulators, the
+//! modulation envelope, LFOs and sample address offsets are ignored.
+use std::{collections::HashMap, convert::TryInto, fmt, fs, io, path::Path, sync::Arc};
+
+use crate::envvelope::EnvelopeSettings;
+use crate::sampler::{LoopMode, Region, SampleData, SampleMap};
+
+pub enum Sf2Error {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for Sf2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sf2Error::Io(e) => write!(f, "could not read sf2 file: {}", e),
+            Sf2Error::Format(message) => write!(f, "invalid sf2 file: {}", message),
+        }
+    }
+}
+
+impl From<io::Error> for Sf2Error {
+    fn from(e: io::Error) -> Self {
+        Sf2Error::Io(e)
+    }
+}
+
+pub struct Preset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    pub sample_map: Arc<SampleMap>,
+}
+
+pub struct SoundFont {
+    pub presets: Vec<Preset>,
+}
+
+impl SoundFont {
+    /// Finds a preset. Like General MIDI players, missing melodic presets fall
+    /// back to bank 0 and missing drum kits to the standard kit.
+    pub fn preset(&self, bank: u16, program: u16) -> Option<&Preset> {
+        let find = |bank: u16, program: u16| {
+            self.presets
+                .iter()
+                .find(|p| p.bank == bank && p.program == program)
+        };
+        find(bank, program)
+            .or_else(|| {
+                if bank == PERCUSSION_BANK {
+                    find(bank, 0)
+                } else {
+                    find(0, program)
+                }
+            })
+            .or_else(|| self.presets.first())
+    }
+}
+
+/// The bank General MIDI percussion kits live in.
+pub const PERCUSSION_BANK: u16 = 128;
+
+// generator operators
+const START_LOOP_ADDRS_OFFSET: usize = 2;
+const END_LOOP_ADDRS_OFFSET: usize = 3;
+const PAN: usize = 17;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const START_LOOP_ADDRS_COARSE_OFFSET: usize = 45;
+const INITIAL_ATTENUATION: usize = 48;
+const END_LOOP_ADDRS_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const GENERATOR_COUNT: usize = 61;
+
+/// Generators a preset zone adds on top of the instrument's values.
+const ADDITIVE: [usize; 10] = [
+    PAN,
+    DELAY_VOL_ENV,
+    ATTACK_VOL_ENV,
+    HOLD_VOL_ENV,
+    DECAY_VOL_ENV,
+    SUSTAIN_VOL_ENV,
+    RELEASE_VOL_ENV,
+    INITIAL_ATTENUATION,
+    COARSE_TUNE,
+    FINE_TUNE,
+];
+
+/// The raw generator amounts of a zone, None where the zone does not set them.
+#[derive(Clone)]
+struct Generators([Option<u16>; GENERATOR_COUNT]);
+
+impl Generators {
+    fn empty() -> Generators {
+        Generators([None; GENERATOR_COUNT])
+    }
+
+    /// Values of `other` replace ours where set.
+    fn overridden_by(&self, other: &Generators) -> Generators {
+        let mut result = self.clone();
+        for (value, other) in result.0.iter_mut().zip(other.0.iter()) {
+            if other.is_some() {
+                *value = *other;
+            }
+        }
+        result
+    }
+
+    fn signed(&self, operator: usize, default: i16) -> i16 {
+        self.0[operator].map(|v| v as i16).unwrap_or(default)
+    }
+
+    fn range(&self, operator: usize) -> (u8, u8) {
+        match self.0[operator] {
+            Some(v) => ((v & 0xff) as u8, (v >> 8) as u8),
+            None => (0, 127),
+        }
+    }
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    start_loop: u32,
+    end_loop: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<SoundFont, Sf2Error> {
+    parse(&fs::read(path)?)
+}
+
+pub fn parse(data: &[u8]) -> Result<SoundFont, Sf2Error> {
+    let (id, body) = read_chunk(data, 0)?.0;
+    if id != b"RIFF" || body.len() < 4 || &body[0..4] != b"sfbk" {
+        return Err(format_error("not a RIFF sfbk file"));
+    }
+
+    let mut sample_data: &[u8] = &[];
+    let mut pdta: HashMap<[u8; 4], &[u8]> = HashMap::new();
+    for (id, list) in chunks(&body[4..])? {
+        if id != b"LIST" || list.len() < 4 {
+            continue;
+        }
+        let list_type = &list[0..4];
+        for (sub_id, sub_chunk) in chunks(&list[4..])? {
+            if list_type == b"sdta" && sub_id == b"smpl" {
+                sample_data = sub_chunk;
+            } else if list_type == b"pdta" {
+                pdta.insert(*sub_id, sub_chunk);
+            }
+        }
+    }
+
+    let table = |name: &[u8; 4], record_size: usize| -> Result<Vec<&[u8]>, Sf2Error> {
+        let chunk = pdta.get(name).ok_or_else(|| {
+            format_error(&format!("missing {} chunk", String::from_utf8_lossy(name)))
+        })?;
+        Ok(chunk.chunks_exact(record_size).collect())
+    };
+    let phdr = table(b"phdr", 38)?;
+    let pbag = table(b"pbag", 4)?;
+    let pgen = table(b"pgen", 4)?;
+    let inst = table(b"inst", 22)?;
+    let ibag = table(b"ibag", 4)?;
+    let igen = table(b"igen", 4)?;
+    let shdr = table(b"shdr", 46)?;
+
+    let sample_headers: Vec<SampleHeader> = shdr
+        .iter()
+        .map(|r| SampleHeader {
+            start: u32_at(r, 20),
+            end: u32_at(r, 24),
+            start_loop: u32_at(r, 28),
+            end_loop: u32_at(r, 32),
+            sample_rate: u32_at(r, 36),
+            original_pitch: r[40],
+            pitch_correction: r[41] as i8,
+            sample_type: u16_at(r, 44),
+        })
+        .collect();
+
+    // instrument zones, each with the instrument's global zone applied
+    let mut instruments: Vec<Vec<Generators>> = Vec::new();
+    for i in 0..inst.len().saturating_sub(1) {
+        let bags = u16_at(inst[i], 20) as usize..u16_at(inst[i + 1], 20) as usize;
+        instruments.push(zones(bags, &ibag, &igen, SAMPLE_ID)?);
+    }
+
+    let mut builder = MapBuilder {
+        sample_data,
+        sample_headers: &sample_headers,
+        samples: HashMap::new(),
+    };
+
+    let mut presets = Vec::new();
+    // the last preset header is the terminal "EOP" record
+    for p in 0..phdr.len().saturating_sub(1) {
+        let bags = u16_at(phdr[p], 24) as usize..u16_at(phdr[p + 1], 24) as usize;
+        let mut sample_map = SampleMap::default();
+
+        for preset_zone in zones(bags, &pbag, &pgen, INSTRUMENT)? {
+            let instrument = match preset_zone.0[INSTRUMENT] {
+                Some(index) => instruments
+                    .get(index as usize)
+                    .ok_or_else(|| format_error("preset zone refers to a missing instrument"))?,
+                None => continue,
+            };
+            for instrument_zone in instrument {
+                if let Some(region) = builder.region(&preset_zone, instrument_zone)? {
+                    sample_map.regions.push(region);
+                }
+            }
+        }
+
+        presets.push(Preset {
+            name: name_at(phdr[p]),
+            program: u16_at(phdr[p], 20),
+            bank: u16_at(phdr[p], 22),
+            sample_map: Arc::new(sample_map),
+        });
+    }
+
+    Ok(SoundFont { presets })
+}
+
+/// Reads the zones of a preset or instrument. A first zone that does not end
+/// in `terminal` is a global zone whose generators apply to the other zones.
+fn zones(
+    bags: std::ops::Range<usize>,
+    bag_table: &[&[u8]],
+    generator_table: &[&[u8]],
+    terminal: usize,
+) -> Result<Vec<Generators>, Sf2Error> {
+    let mut global = Generators::empty();
+    let mut result = Vec::new();
+
+    for bag in bags.clone() {
+        if bag + 1 >= bag_table.len() {
+            return Err(format_error("zone index out of range"));
+        }
+        let generators = u16_at(bag_table[bag], 0) as usize..u16_at(bag_table[bag + 1], 0) as usize;
+        let mut zone = Generators::empty();
+        for record in generator_table.get(generators).unwrap_or(&[]) {
+            let operator = u16_at(record, 0) as usize;
+            if operator < GENERATOR_COUNT {
+                zone.0[operator] = Some(u16_at(record, 2));
+            }
+        }
+
+        if zone.0[terminal].is_none() {
+            if bag == bags.start {
+                global = zone;
+            }
+            continue;
+        }
+        result.push(global.overridden_by(&zone));
+    }
+    Ok(result)
+}
+
+struct MapBuilder<'a> {
+    sample_data: &'a [u8],
+    sample_headers: &'a [SampleHeader],
+    samples: HashMap<usize, Arc<SampleData>>,
+}
+
+impl MapBuilder<'_> {
+    fn sample(&mut self, index: usize) -> Result<Arc<SampleData>, Sf2Error> {
+        if let Some(sample) = self.samples.get(&index) {
+            return Ok(Arc::clone(sample));
+        }
+        let header = &self.sample_headers[index];
+        let start = header.start as usize * 2;
+        let end = (header.end as usize * 2).min(self.sample_data.len());
+        if start > end {
+            return Err(format_error("sample header out of range"));
+        }
+        let frames = self.sample_data[start..end]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect();
+        let sample = Arc::new(SampleData {
+            frames,
+            sample_rate: header.sample_rate.max(1),
+        });
+        self.samples.insert(index, Arc::clone(&sample));
+        Ok(sample)
+    }
+
+    fn region(
+        &mut self,
+        preset: &Generators,
+        instrument: &Generators,
+    ) -> Result<Option<Region>, Sf2Error> {
+        let sample_index = instrument.signed(SAMPLE_ID, 0) as u16 as usize;
+        let header = match self.sample_headers.get(sample_index) {
+            // ROM samples are not in the file
+            Some(header) if header.sample_type & 0x8000 == 0 => header,
+            _ => return Ok(None),
+        };
+
+        // preset generators are relative to the instrument's, ranges narrow each other
+        let mut generators = instrument.clone();
+        for &operator in ADDITIVE.iter() {
+            if let Some(offset) = preset.0[operator] {
+                let value = generators.signed(operator, default_value(operator)) as i32
+                    + offset as i16 as i32;
+                generators.0[operator] = Some(value.clamp(-32768, 32767) as i16 as u16);
+            }
+        }
+        let intersect = |operator: usize| {
+            let (low, high) = instrument.range(operator);
+            let (preset_low, preset_high) = preset.range(operator);
+            (low.max(preset_low), high.min(preset_high))
+        };
+        let (lokey, hikey) = intersect(KEY_RANGE);
+        let (lovel, hivel) = intersect(VEL_RANGE);
+        if lokey > hikey || lovel > hivel {
+            return Ok(None);
+        }
+
+        let mut region = Region::new(self.sample(sample_index)?);
+        region.lokey = lokey.min(127);
+        region.hikey = hikey.min(127);
+        region.lovel = lovel.min(127);
+        region.hivel = hivel.min(127);
+
+        let root_key = generators.signed(OVERRIDING_ROOT_KEY, -1);
+        region.pitch_keycenter = if (0..=127).contains(&root_key) {
+            root_key as u8
+        } else {
+            header.original_pitch.min(127)
+        };
+        region.tune = generators.signed(COARSE_TUNE, 0) as f32 * 100.0
+            + generators.signed(FINE_TUNE, 0) as f32
+            + header.pitch_correction as f32;
+
+        region.gain = centibels_to_gain(generators.signed(INITIAL_ATTENUATION, 0));
+        region.pan = (generators.signed(PAN, 0) as f32 / 500.0).clamp(-1.0, 1.0);
+
+        region.loop_mode = match generators.signed(SAMPLE_MODES, 0) & 3 {
+            1 => LoopMode::LoopContinuous,
+            3 => LoopMode::LoopSustain,
+            _ => LoopMode::NoLoop,
+        };
+        let loop_offset = |fine: usize, coarse: usize| {
+            generators.signed(fine, 0) as i64 + generators.signed(coarse, 0) as i64 * 32768
+        };
+        let loop_start = header.start_loop as i64 - header.start as i64
+            + loop_offset(START_LOOP_ADDRS_OFFSET, START_LOOP_ADDRS_COARSE_OFFSET);
+        let loop_end = header.end_loop as i64 - header.start as i64
+            + loop_offset(END_LOOP_ADDRS_OFFSET, END_LOOP_ADDRS_COARSE_OFFSET);
+        let length = region.sample.frames.len() as i64;
+        region.loop_start = loop_start.clamp(0, length) as usize;
+        region.loop_end = loop_end.clamp(0, length) as usize;
+
+        // the envelope has no delay and hold stages, those generators are dropped
+        let timecents = |operator: usize| -> f32 {
+            2.0_f32.powf(generators.signed(operator, default_value(operator)) as f32 / 1200.0)
+        };
+        region.envelope = EnvelopeSettings {
+            attack: timecents(ATTACK_VOL_ENV),
+            decay: timecents(DECAY_VOL_ENV),
+            sustain: centibels_to_gain(generators.signed(SUSTAIN_VOL_ENV, 0).clamp(0, 1440)),
+            release: timecents(RELEASE_VOL_ENV),
+            ..EnvelopeSettings::default()
+        };
+
+        Ok(Some(region))
+    }
+}
+
+fn default_value(operator: usize) -> i16 {
+    match operator {
+        DELAY_VOL_ENV | ATTACK_VOL_ENV | HOLD_VOL_ENV | DECAY_VOL_ENV | RELEASE_VOL_ENV => -12000,
+        _ => 0,
+    }
+}
+
+fn centibels_to_gain(centibels: i16) -> f32 {
+    10.0_f32.powf(-(centibels.max(0) as f32) / 200.0)
+}
+
+fn format_error(message: &str) -> Sf2Error {
+    Sf2Error::Format(message.to_string())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn name_at(data: &[u8]) -> String {
+    let name = &data[..20];
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).trim().to_string()
+}
+
+type Chunk<'a> = (&'a [u8; 4], &'a [u8]);
+
+/// Reads the RIFF chunk at `offset`, returning it and the offset of the next one.
+fn read_chunk(data: &[u8], offset: usize) -> Result<(Chunk<'_>, usize), Sf2Error> {
+    if offset + 8 > data.len() {
+        return Err(format_error("truncated chunk header"));
+    }
+    let id: &[u8; 4] = data[offset..offset + 4].try_into().unwrap();
+    let length = u32_at(data, offset + 4) as usize;
+    let start = offset + 8;
+    let end = start
+        .checked_add(length)
+        .filter(|&end| end <= data.len())
+        .ok_or_else(|| format_error("truncated chunk"))?;
+    // chunks are padded to an even length
+    Ok(((id, &data[start..end]), end + (length & 1)))
+}
+
+fn chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, Sf2Error> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let (chunk, next) = read_chunk(data, offset)?;
+        result.push(chunk);
+        offset = next;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(list_type: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = list_type.to_vec();
+        for sub_chunk in chunks {
+            body.extend_from_slice(sub_chunk);
+        }
+        chunk(b"LIST", &body)
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(20, 0);
+        bytes
+    }
+
+    /// Records of u16 values, such as bags and generators.
+    fn words(records: &[&[u16]]) -> Vec<u8> {
+        records
+            .iter()
+            .flat_map(|record| record.iter())
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    fn preset_header(preset_name: &str, program: u16, bank: u16, bag: u16) -> Vec<u8> {
+        let mut record = name(preset_name);
+        for word in [program, bank, bag] {
+            record.extend_from_slice(&word.to_le_bytes());
+        }
+        // library, genre and morphology
+        record.extend_from_slice(&[0; 12]);
+        record
+    }
+
+    fn instrument_header(instrument_name: &str, bag: u16) -> Vec<u8> {
+        let mut record = name(instrument_name);
+        record.extend_from_slice(&bag.to_le_bytes());
+        record
+    }
+
+    fn sample_header(sample_name: &str, positions: [u32; 4], sample_type: u16) -> Vec<u8> {
+        let mut record = name(sample_name);
+        for position in positions {
+            record.extend_from_slice(&position.to_le_bytes());
+        }
+        record.extend_from_slice(&22050u32.to_le_bytes());
+        // original pitch, pitch correction and sample link
+        record.extend_from_slice(&[57, 0, 0, 0]);
+        record.extend_from_slice(&sample_type.to_le_bytes());
+        record
+    }
+
+    /// A bank with one piano preset over one instrument, which plays a
+    /// looped sample of eight frames from C3 to C5.
+    fn sound_font() -> Vec<u8> {
+        let samples: Vec<u8> = (0..8i16).flat_map(|i| (i * 1000).to_le_bytes()).collect();
+        let pdta = [
+            chunk(
+                b"phdr",
+                &[
+                    preset_header("Piano", 0, 0, 0),
+                    preset_header("EOP", 0, 0, 1),
+                ]
+                .concat(),
+            ),
+            chunk(b"pbag", &words(&[&[0, 0], &[1, 0]])),
+            chunk(b"pgen", &words(&[&[INSTRUMENT as u16, 0], &[0, 0]])),
+            chunk(
+                b"inst",
+                &[instrument_header("Piano", 0), instrument_header("EOI", 2)].concat(),
+            ),
+            chunk(b"ibag", &words(&[&[0, 0], &[1, 0], &[5, 0]])),
+            chunk(
+                b"igen",
+                &words(&[
+                    // a global zone, the release of every zone
+                    &[RELEASE_VOL_ENV as u16, 0],
+                    &[KEY_RANGE as u16, 48 | 72 << 8],
+                    &[OVERRIDING_ROOT_KEY as u16, 60],
+                    &[SAMPLE_MODES as u16, 1],
+                    &[SAMPLE_ID as u16, 0],
+                    &[0, 0],
+                ]),
+            ),
+            chunk(
+                b"shdr",
+                &[
+                    sample_header("Tone", [0, 8, 2, 6], 1),
+                    sample_header("EOS", [0; 4], 0),
+                ]
+                .concat(),
+            ),
+        ];
+        let body = [
+            b"sfbk".to_vec(),
+            list(b"INFO", &[chunk(b"INAM", b"Test\0")]),
+            list(b"sdta", &[chunk(b"smpl", &samples)]),
+            list(b"pdta", &pdta),
+        ]
+        .concat();
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn presets_become_sample_maps() {
+        let sound_font = parse(&sound_font()).ok().unwrap();
+        assert_eq!(sound_font.presets.len(), 1);
+        let preset = &sound_font.presets[0];
+        assert_eq!(
+            (preset.name.as_str(), preset.bank, preset.program),
+            ("Piano", 0, 0)
+        );
+
+        let regions = &preset.sample_map.regions;
+        assert_eq!(regions.len(), 1);
+        let region = &regions[0];
+        assert_eq!((region.lokey, region.hikey), (48, 72));
+        // the root key overrides the sample's own pitch
+        assert_eq!(region.pitch_keycenter, 60);
+        assert!(region.loop_mode == LoopMode::LoopContinuous);
+        assert_eq!((region.loop_start, region.loop_end), (2, 6));
+        // 0 timecents is a second, from the global zone
+        assert_eq!(region.envelope.release, 1.0);
+        assert_eq!(region.sample.sample_rate, 22050);
+        assert_eq!(region.sample.frames.len(), 8);
+        assert!((region.sample.frames[1] - 1000.0 / 32768.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn missing_presets_fall_back() {
+        let sound_font = parse(&sound_font()).ok().unwrap();
+        assert_eq!(sound_font.preset(0, 0).map(|p| p.program), Some(0));
+        assert_eq!(sound_font.preset(8, 0).map(|p| p.program), Some(0));
+        assert!(sound_font.preset(0, 40).is_some());
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        let data = sound_font();
+        assert!(parse(&data[..data.len() - 10]).is_err());
+        assert!(parse(b"RIFF\x04\0\0\0WAVE").is_err());
+        assert!(parse(&[]).is_err());
+    }
+}
@@ -0,0 +1,399 @@
+//! Loader for SFZ instrument files.
+//!
+//! Supports the `<control>`, `<global>`, `<master>`, `<group>` and `<region>`
+//! headers, with opcodes inherited from the enclosing headers. Opcodes that
+//! are not understood are ignored, like most SFZ players do.
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::musical_keyboard::key_from_name;
+use crate::sampler::{LoopMode, Region, SampleData, SampleMap};
+
+pub enum SfzError {
+    Io(io::Error),
+    Sample(PathBuf, hound::Error),
+    Opcode { line: usize, message: String },
+}
+
+impl fmt::Display for SfzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SfzError::Io(e) => write!(f, "could not read sfz file: {}", e),
+            SfzError::Sample(path, e) => write!(f, "could not load {}: {}", path.display(), e),
+            SfzError::Opcode { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl From<io::Error> for SfzError {
+    fn from(e: io::Error) -> Self {
+        SfzError::Io(e)
+    }
+}
+
+#[derive(Clone)]
+struct Opcode {
+    name: String,
+    value: String,
+    line: usize,
+}
+
+#[derive(PartialEq)]
+enum Header {
+    None,
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<SampleMap, SfzError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    let base_directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut loader = Loader {
+        base_directory: base_directory.to_path_buf(),
+        default_path: String::new(),
+        samples: HashMap::new(),
+        map: SampleMap::default(),
+    };
+
+    let mut header = Header::None;
+    let mut global: Vec<Opcode> = Vec::new();
+    let mut master: Vec<Opcode> = Vec::new();
+    let mut group: Vec<Opcode> = Vec::new();
+    let mut region: Vec<Opcode> = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+
+        for token in tokenize(line) {
+            match token {
+                Token::Header(name) => {
+                    if header == Header::Region {
+                        loader.add_region(&[&global, &master, &group, &region])?;
+                    }
+                    header = match name {
+                        "control" => Header::Control,
+                        "global" => {
+                            global.clear();
+                            Header::Global
+                        }
+                        "master" => {
+                            master.clear();
+                            group.clear();
+                            Header::Master
+                        }
+                        "group" => {
+                            group.clear();
+                            Header::Group
+                        }
+                        "region" => {
+                            region.clear();
+                            Header::Region
+                        }
+                        // unknown headers (e.g. <curve>, <effect>) swallow their opcodes
+                        _ => Header::None,
+                    };
+                }
+                Token::Opcode(name, value) => {
+                    let opcode = Opcode {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                        line: line_index + 1,
+                    };
+                    match header {
+                        Header::None => (),
+                        Header::Control => {
+                            if opcode.name == "default_path" {
+                                loader.default_path = opcode.value.replace('\\', "/");
+                            }
+                        }
+                        Header::Global => global.push(opcode),
+                        Header::Master => master.push(opcode),
+                        Header::Group => group.push(opcode),
+                        Header::Region => region.push(opcode),
+                    }
+                }
+            }
+        }
+    }
+    if header == Header::Region {
+        loader.add_region(&[&global, &master, &group, &region])?;
+    }
+
+    Ok(loader.map)
+}
+
+enum Token<'a> {
+    Header(&'a str),
+    Opcode(&'a str, &'a str),
+}
+
+/// Splits a line into headers and `name=value` opcodes. A value runs until
+/// the next opcode or header, so sample paths may contain spaces.
+fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = line;
+
+    while !rest.trim().is_empty() {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('<') {
+            let end = after.find('>').unwrap_or(after.len());
+            tokens.push(Token::Header(after[..end].trim()));
+            rest = after.get(end + 1..).unwrap_or("");
+            continue;
+        }
+
+        let equals = match rest.find('=') {
+            Some(equals) => equals,
+            None => break,
+        };
+        let name = rest[..equals].trim();
+        let after = &rest[equals + 1..];
+
+        // the value ends where the next `name=` or `<header>` starts
+        let mut end = after.len();
+        if let Some(next_header) = after.find('<') {
+            end = next_header;
+        }
+        if let Some(next_equals) = after[..end].find('=') {
+            let name_start = after[..next_equals]
+                .trim_end()
+                .rfind(char::is_whitespace)
+                .unwrap_or(0);
+            end = name_start;
+        }
+
+        tokens.push(Token::Opcode(name, after[..end].trim()));
+        rest = &after[end..];
+    }
+    tokens
+}
+
+struct Loader {
+    base_directory: PathBuf,
+    default_path: String,
+    samples: HashMap<PathBuf, Arc<SampleData>>,
+    map: SampleMap,
+}
+
+impl Loader {
+    fn sample(&mut self, name: &str) -> Result<Arc<SampleData>, SfzError> {
+        let path = self
+            .base_directory
+            .join(&self.default_path)
+            .join(name.replace('\\', "/"));
+        if let Some(sample) = self.samples.get(&path) {
+            return Ok(Arc::clone(sample));
+        }
+
+        let sample = SampleData::load_wav(&path).map_err(|e| SfzError::Sample(path.clone(), e))?;
+        let sample = Arc::new(sample);
+        self.samples.insert(path, Arc::clone(&sample));
+        Ok(sample)
+    }
+
+    fn add_region(&mut self, levels: &[&Vec<Opcode>]) -> Result<(), SfzError> {
+        // later levels override earlier ones, so the region's own opcodes win
+        let opcodes: Vec<&Opcode> = levels.iter().flat_map(|level| level.iter()).collect();
+
+        let sample_name = match opcodes.iter().rev().find(|opcode| opcode.name == "sample") {
+            Some(opcode) => opcode.value.clone(),
+            None => return Ok(()), // regions without a sample are silent
+        };
+        let mut region = Region::new(self.sample(&sample_name)?);
+        let mut loop_end_set = false;
+        let mut transpose = 0.0;
+        let mut tune = 0.0;
+
+        for opcode in opcodes {
+            match opcode.name.as_str() {
+                "lokey" => region.lokey = parse_key(opcode)?,
+                "hikey" => region.hikey = parse_key(opcode)?,
+                "key" => {
+                    let key = parse_key(opcode)?;
+                    region.lokey = key;
+                    region.hikey = key;
+                    region.pitch_keycenter = key;
+                }
+                "pitch_keycenter" => region.pitch_keycenter = parse_key(opcode)?,
+                "lovel" => region.lovel = parse_number::<u8>(opcode)?.min(127),
+                "hivel" => region.hivel = parse_number::<u8>(opcode)?.min(127),
+                "tune" => tune = parse_number::<f32>(opcode)?,
+                "transpose" => transpose = parse_number::<f32>(opcode)?,
+                "volume" => region.gain = 10.0_f32.powf(parse_number::<f32>(opcode)? / 20.0),
+                "loop_mode" | "loopmode" => {
+                    region.loop_mode = match opcode.value.as_str() {
+                        "no_loop" => LoopMode::NoLoop,
+                        "one_shot" => LoopMode::OneShot,
+                        "loop_continuous" => LoopMode::LoopContinuous,
+                        "loop_sustain" => LoopMode::LoopSustain,
+                        other => {
+                            return Err(invalid(opcode, &format!("unknown loop mode `{}`", other)))
+                        }
+                    }
+                }
+                "loop_start" | "loopstart" => region.loop_start = parse_number(opcode)?,
+                "loop_end" | "loopend" => {
+                    // sfz loop ends are inclusive
+                    region.loop_end = parse_number::<usize>(opcode)? + 1;
+                    loop_end_set = true;
+                }
+                "ampeg_attack" => region.envelope.attack = parse_number(opcode)?,
+                "ampeg_decay" => region.envelope.decay = parse_number(opcode)?,
+                "ampeg_sustain" => region.envelope.sustain = parse_number::<f32>(opcode)? / 100.0,
+                "ampeg_release" => region.envelope.release = parse_number(opcode)?,
+                "ampeg_vel2attack" => region.envelope.velocity_attack = parse_number(opcode)?,
+                "amp_veltrack" => {
+                    region.envelope.velocity_track = parse_number::<f32>(opcode)? / 100.0
+                }
+                "pan" => region.pan = (parse_number::<f32>(opcode)? / 100.0).clamp(-1.0, 1.0),
+                _ => (),
+            }
+        }
+
+        region.tune = tune + transpose * 100.0;
+        if !loop_end_set {
+            region.loop_end = region.sample.frames.len();
+        }
+        region.loop_end = region.loop_end.min(region.sample.frames.len());
+
+        self.map.regions.push(region);
+        Ok(())
+    }
+}
+
+fn invalid(opcode: &Opcode, message: &str) -> SfzError {
+    SfzError::Opcode {
+        line: opcode.line,
+        message: format!("{}={}: {}", opcode.name, opcode.value, message),
+    }
+}
+
+fn parse_number<N: std::str::FromStr>(opcode: &Opcode) -> Result<N, SfzError> {
+    opcode
+        .value
+        .parse()
+        .map_err(|_| invalid(opcode, "not a valid number"))
+}
+
+/// Parses a key given either as a MIDI number or as a note name such as
+/// `c4`, `f#3` or `eb5` (where `c4` is middle C, key 60).
+fn parse_key(opcode: &Opcode) -> Result<u8, SfzError> {
+    if let Ok(key) = opcode.value.parse::<u8>() {
+        return Ok(key.min(127));
+    }
+    key_from_name(&opcode.value.to_lowercase()).ok_or_else(|| invalid(opcode, "not a valid key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opcode(value: &str) -> Opcode {
+        Opcode {
+            name: "key".to_string(),
+            value: value.to_string(),
+            line: 1,
+        }
+    }
+
+    /// Writes an instrument and a short sample next to it in a directory of
+    /// its own, returning the path of the instrument.
+    fn write_instrument(name: &str, sfz: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("rust_synth_sfz_{}", name));
+        fs::create_dir_all(directory.join("samples")).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 22050,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer =
+            hound::WavWriter::create(directory.join("samples/a tone.wav"), spec).unwrap();
+        for i in 0..100 {
+            writer.write_sample((i * 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        let path = directory.join("instrument.sfz");
+        fs::write(&path, sfz).unwrap();
+        path
+    }
+
+    #[test]
+    fn values_run_until_the_next_opcode() {
+        let tokens = tokenize("<region> sample=a tone.wav lokey=c4 <group>");
+        assert_eq!(tokens.len(), 4);
+        match (&tokens[0], &tokens[1], &tokens[2], &tokens[3]) {
+            (
+                Token::Header("region"),
+                Token::Opcode("sample", "a tone.wav"),
+                Token::Opcode("lokey", "c4"),
+                Token::Header("group"),
+            ) => {}
+            _ => panic!("wrong tokens"),
+        }
+    }
+
+    #[test]
+    fn keys_are_numbers_or_note_names() {
+        assert_eq!(parse_key(&opcode("64")).ok(), Some(64));
+        assert_eq!(parse_key(&opcode("c4")).ok(), Some(60));
+        assert_eq!(parse_key(&opcode("F#3")).ok(), Some(54));
+        assert_eq!(parse_key(&opcode("eb5")).ok(), Some(75));
+        assert_eq!(parse_key(&opcode("c-1")).ok(), Some(0));
+        assert!(parse_key(&opcode("h2")).is_err());
+    }
+
+    #[test]
+    fn regions_inherit_from_their_headers() {
+        let path = write_instrument(
+            "inherit",
+            "<control> default_path=samples\n\
+             <global> ampeg_release=0.5 // the same for every region\n\
+             <group> sample=a tone.wav loop_mode=loop_continuous volume=-6\n\
+             <region> key=48 loop_start=10 loop_end=19\n\
+             <region> lokey=c4 hikey=b4 pitch_keycenter=62 ampeg_release=1\n",
+        );
+        let map = load(&path).ok().unwrap();
+        assert_eq!(map.regions.len(), 2);
+
+        let low = &map.regions[0];
+        assert_eq!((low.lokey, low.hikey, low.pitch_keycenter), (48, 48, 48));
+        assert!(low.loop_mode == LoopMode::LoopContinuous);
+        // loop ends are inclusive in the file
+        assert_eq!((low.loop_start, low.loop_end), (10, 20));
+        assert_eq!(low.envelope.release, 0.5);
+        assert!((low.gain - 0.501).abs() < 0.001);
+        assert_eq!(low.sample.frames.len(), 100);
+
+        let high = &map.regions[1];
+        assert_eq!((high.lokey, high.hikey, high.pitch_keycenter), (60, 71, 62));
+        assert_eq!(high.loop_end, 100);
+        assert_eq!(high.envelope.release, 1.0);
+        // both regions play the same sample
+        assert!(Arc::ptr_eq(&low.sample, &high.sample));
+    }
+
+    #[test]
+    fn bad_opcodes_give_their_line() {
+        let path = write_instrument(
+            "bad_opcode",
+            "<region> sample=samples/a tone.wav\n<region> sample=samples/a tone.wav lovel=loud\n",
+        );
+        match load(&path) {
+            Err(error) => assert_eq!(error.to_string(), "line 2: lovel=loud: not a valid number"),
+            Ok(_) => panic!("loaded a region with a bad velocity"),
+        }
+    }
+}
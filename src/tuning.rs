@@ -0,0 +1,512 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::atomic_f32::AtomicF32;
+use crate::musical_keyboard::{calc_frequency, Note};
+
+/// A4, the reference pitch when none is given.
+pub const DEFAULT_REFERENCE: f32 = 440.0;
+
+/// Sub-IDs of the MIDI Tuning Standard messages that are understood.
+const MTS_BULK_DUMP: u8 = 0x01;
+const MTS_NOTE_CHANGE: u8 = 0x02;
+const MTS_BANK_NOTE_CHANGE: u8 = 0x07;
+/// Length of the tuning name in a bulk dump.
+const MTS_NAME_LENGTH: usize = 16;
+
+/// An MTS frequency: the key whose equal tempered pitch is just below and
+/// how far above it, in 1/16384 of a semitone. 7f 7f 7f means no change.
+fn mts_frequency(data: &[u8]) -> Option<f32> {
+    match *data {
+        [0x7f, 0x7f, 0x7f] => None,
+        [key, high, low] => {
+            let fraction = ((high as u32) << 7 | low as u32) as f32 / 16384.0;
+            Some(calc_frequency(4.0, key as f32 + fraction - 69.0))
+        }
+        _ => None,
+    }
+}
+
+/// The steps of a scale in cents above its first note, which is left out.
+/// The last step is the interval the scale repeats at, usually the octave.
+#[derive(Clone)]
+pub struct Scale {
+    cents: Vec<f64>,
+}
+
+fn ratio_cents(numerator: f64, denominator: f64) -> f64 {
+    1200.0 * (numerator / denominator).log2()
+}
+
+impl Scale {
+    /// The octave split into `divisions` equal steps.
+    pub fn equal(divisions: u32) -> Scale {
+        Scale {
+            cents: (1..=divisions)
+                .map(|step| 1200.0 * step as f64 / divisions as f64)
+                .collect(),
+        }
+    }
+
+    fn from_ratios(ratios: &[(u32, u32)]) -> Scale {
+        Scale {
+            cents: ratios
+                .iter()
+                .map(|&(numerator, denominator)| ratio_cents(numerator as f64, denominator as f64))
+                .collect(),
+        }
+    }
+
+    /// Five-limit just intonation on C.
+    pub fn just() -> Scale {
+        Scale::from_ratios(&[
+            (16, 15),
+            (9, 8),
+            (6, 5),
+            (5, 4),
+            (4, 3),
+            (45, 32),
+            (3, 2),
+            (8, 5),
+            (5, 3),
+            (9, 5),
+            (15, 8),
+            (2, 1),
+        ])
+    }
+
+    /// Pure fifths on C, from the D flat five fifths below to the F sharp
+    /// six above.
+    pub fn pythagorean() -> Scale {
+        Scale::from_ratios(&[
+            (256, 243),
+            (9, 8),
+            (32, 27),
+            (81, 64),
+            (4, 3),
+            (729, 512),
+            (3, 2),
+            (128, 81),
+            (27, 16),
+            (16, 9),
+            (243, 128),
+            (2, 1),
+        ])
+    }
+
+    /// `just`, `pythagorean` or `<n>edo` such as `19edo`.
+    pub fn from_name(name: &str) -> Option<Scale> {
+        match name {
+            "just" => Some(Scale::just()),
+            "pythagorean" => Some(Scale::pythagorean()),
+            _ => match name.strip_suffix("edo")?.parse() {
+                Ok(divisions @ 1..=1200) => Some(Scale::equal(divisions)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Reads a Scala `.scl` file: a description, the number of notes and
+    /// then one pitch per line, in cents when it has a dot and as a ratio
+    /// such as `3/2` or `2` otherwise. Lines starting with `!` are comments.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scale, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Scale::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> Result<Scale, String> {
+        let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+        // the description may be empty, but its line has to be there
+        lines.next().ok_or("no description")?;
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .ok_or("no number of notes")?
+            .parse()
+            .map_err(|_| "bad number of notes")?;
+        let cents = lines
+            .take(count)
+            .map(|line| {
+                let pitch = line.split_whitespace().next().unwrap_or("");
+                parse_pitch(pitch).ok_or_else(|| format!("bad pitch {:?}", pitch))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+        if cents.len() != count || count == 0 {
+            return Err(format!("{} notes listed, {} given", count, cents.len()));
+        }
+        Ok(Scale { cents })
+    }
+
+    fn len(&self) -> i32 {
+        self.cents.len() as i32
+    }
+
+    /// Cents of a step counted from the first note, repeating the scale
+    /// above and below.
+    fn step_cents(&self, step: i32) -> f64 {
+        let period = self.cents[self.cents.len() - 1];
+        let (repeats, step) = (step.div_euclid(self.len()), step.rem_euclid(self.len()));
+        let cents = if step == 0 {
+            0.0
+        } else {
+            self.cents[step as usize - 1]
+        };
+        repeats as f64 * period + cents
+    }
+}
+
+fn parse_pitch(pitch: &str) -> Option<f64> {
+    if pitch.contains('.') {
+        return pitch.parse().ok();
+    }
+    let (numerator, denominator) = match pitch.split_once('/') {
+        Some((numerator, denominator)) => (numerator.parse().ok()?, denominator.parse().ok()?),
+        None => (pitch.parse().ok()?, 1.0),
+    };
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return None;
+    }
+    Some(ratio_cents(numerator, denominator))
+}
+
+/// Which keys play which steps of a scale, and the pitch it is tuned to:
+/// what a Scala `.kbm` file holds.
+#[derive(Clone)]
+pub struct KeyboardMapping {
+    /// Keys outside these play nothing.
+    pub first_key: u8,
+    pub last_key: u8,
+    /// The key that plays the first note of the scale.
+    pub middle_key: u8,
+    /// The key tuned to `reference_frequency`.
+    pub reference_key: u8,
+    pub reference_frequency: f32,
+    /// The step a pattern of keys repeats at, see `mapping`.
+    pub octave_step: i32,
+    /// A pattern of steps that repeats every `mapping.len()` keys from the
+    /// middle key, None for keys that play nothing. Empty to play the steps
+    /// in order, one per key.
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    /// The scale from middle C, tuned so A4 sounds at 440 Hz.
+    fn default() -> Self {
+        KeyboardMapping {
+            first_key: 0,
+            last_key: 127,
+            middle_key: 60,
+            reference_key: 69,
+            reference_frequency: DEFAULT_REFERENCE,
+            octave_step: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    /// Reads a Scala `.kbm` file: the size of the mapping, the first and
+    /// last key, the middle key, the reference key and frequency, the step
+    /// of the formal octave and then the mapping, `x` for keys that play
+    /// nothing. Lines starting with `!` are comments.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<KeyboardMapping, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        KeyboardMapping::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> Result<KeyboardMapping, String> {
+        let mut values = text
+            .lines()
+            .filter(|line| !line.starts_with('!'))
+            .filter_map(|line| line.split_whitespace().next());
+        let mut number = |name: &str| -> Result<f64, String> {
+            values
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| format!("bad {}", name))
+        };
+        let size = number("mapping size")? as usize;
+        let key = |value: f64, name: &str| -> Result<u8, String> {
+            if (0.0..=127.0).contains(&value) {
+                Ok(value as u8)
+            } else {
+                Err(format!("{} {} is not a MIDI key", name, value))
+            }
+        };
+        let first_key = key(number("first key")?, "first key")?;
+        let last_key = key(number("last key")?, "last key")?;
+        let middle_key = key(number("middle key")?, "middle key")?;
+        let reference_key = key(number("reference key")?, "reference key")?;
+        let reference_frequency = number("reference frequency")? as f32;
+        let octave_step = number("octave step")? as i32;
+        let mapping = values
+            .take(size)
+            .map(|value| match value {
+                "x" => Ok(None),
+                _ => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("bad mapping {:?}", value)),
+            })
+            .collect::<Result<Vec<Option<i32>>, String>>()?;
+        if mapping.len() != size {
+            return Err(format!("{} keys mapped, {} given", size, mapping.len()));
+        }
+        if reference_frequency <= 0.0 {
+            return Err(format!("reference frequency {}", reference_frequency));
+        }
+        Ok(KeyboardMapping {
+            first_key,
+            last_key,
+            middle_key,
+            reference_key,
+            reference_frequency,
+            octave_step,
+            mapping,
+        })
+    }
+
+    /// Cents of a key above the middle key, None for keys that play nothing.
+    fn cents(&self, scale: &Scale, key: u8) -> Option<f64> {
+        let offset = key as i32 - self.middle_key as i32;
+        if self.mapping.is_empty() {
+            return Some(scale.step_cents(offset));
+        }
+        let size = self.mapping.len() as i32;
+        let step = self.mapping[offset.rem_euclid(size) as usize]?;
+        let octave = if self.octave_step == 0 {
+            scale.len()
+        } else {
+            self.octave_step
+        };
+        Some(offset.div_euclid(size) as f64 * scale.step_cents(octave) + scale.step_cents(step))
+    }
+}
+
+/// The frequency of every MIDI key. Everything that turns keys into notes
+/// goes through it. Clones share the table, so a tuning changed while
+/// playing is heard everywhere.
+#[derive(Clone)]
+pub struct Tuning {
+    /// Hz, 0 for keys that play nothing.
+    frequencies: Arc<[AtomicF32]>,
+}
+
+impl Default for Tuning {
+    /// Twelve equal steps to the octave, A4 at 440 Hz.
+    fn default() -> Self {
+        Tuning::new(&Scale::equal(12), &KeyboardMapping::default())
+    }
+}
+
+impl Tuning {
+    pub fn new(scale: &Scale, mapping: &KeyboardMapping) -> Tuning {
+        // the reference key sounds at the reference frequency even when the
+        // mapping leaves it out
+        let reference_cents = mapping
+            .cents(scale, mapping.reference_key)
+            .unwrap_or_else(|| {
+                scale.step_cents(mapping.reference_key as i32 - mapping.middle_key as i32)
+            });
+        let frequencies = (0..128u8)
+            .map(|key| {
+                let playing = (mapping.first_key..=mapping.last_key).contains(&key);
+                let frequency = match mapping.cents(scale, key).filter(|_| playing) {
+                    Some(cents) => {
+                        mapping.reference_frequency as f64
+                            * 2f64.powf((cents - reference_cents) / 1200.0)
+                    }
+                    None => 0.0,
+                };
+                AtomicF32::new(frequency as f32)
+            })
+            .collect();
+        Tuning { frequencies }
+    }
+
+    pub fn frequency(&self, key: u8) -> Option<f32> {
+        let frequency = self.frequencies.get(key as usize)?.get();
+        if frequency > 0.0 {
+            Some(frequency)
+        } else {
+            None
+        }
+    }
+
+    /// The frequency of a fractional key, between the frequencies of the keys
+    /// on either side.
+    pub fn frequency_between(&self, key: f32) -> Option<f32> {
+        let key = key.clamp(0.0, 127.0);
+        let below = self.frequency(key.floor() as u8)?;
+        let above = self.frequency(key.ceil() as u8).unwrap_or(below);
+        Some(below * (above / below).powf(key.fract()))
+    }
+
+    /// Applies a MIDI Tuning Standard SysEx message, without the f0 and f7
+    /// around it: a single note tuning change, with or without a bank, or a
+    /// bulk dump. The program and bank numbers are ignored, every message
+    /// changes this table. Returns the name of a bulk dump, an empty name
+    /// for other tuning messages and None for messages that are not about
+    /// tuning or are damaged.
+    pub fn apply_mts(&self, message: &[u8]) -> Option<String> {
+        // universal real time or non real time, any device, MIDI tuning
+        let (universal, body) = match message {
+            [universal @ (0x7e | 0x7f), _device, 0x08, body @ ..] => (*universal, body),
+            _ => return None,
+        };
+        match body {
+            [MTS_NOTE_CHANGE, _program, count, changes @ ..] => {
+                self.apply_changes(*count, changes);
+                Some(String::new())
+            }
+            [MTS_BANK_NOTE_CHANGE, _bank, _program, count, changes @ ..] => {
+                self.apply_changes(*count, changes);
+                Some(String::new())
+            }
+            [MTS_BULK_DUMP, _program, rest @ ..] if universal == 0x7e => {
+                let (name, rest) = rest.split_at_checked(MTS_NAME_LENGTH)?;
+                let (frequencies, checksum) = rest.split_at_checked(3 * 128)?;
+                // the checksum is the xor of everything after f0
+                let sum = message[..message.len() - checksum.len()]
+                    .iter()
+                    .fold(0, |sum, byte| sum ^ byte)
+                    & 0x7f;
+                if checksum.first() != Some(&sum) {
+                    return None;
+                }
+                for (key, data) in frequencies.chunks(3).enumerate() {
+                    if let Some(frequency) = mts_frequency(data) {
+                        self.frequencies[key].set(frequency);
+                    }
+                }
+                Some(String::from_utf8_lossy(name).trim_end().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Changes of single notes, four bytes each: the key and its frequency.
+    fn apply_changes(&self, count: u8, changes: &[u8]) {
+        for change in changes.chunks_exact(4).take(count as usize) {
+            if let (Some(current), Some(frequency)) = (
+                self.frequencies.get(change[0] as usize),
+                mts_frequency(&change[1..]),
+            ) {
+                current.set(frequency);
+            }
+        }
+    }
+
+    /// The note a key plays, None when it plays nothing.
+    pub fn note(&self, key: u8, velocity: u8) -> Option<Note> {
+        Some(Note {
+            key,
+            frequency: self.frequency(key)?,
+            velocity,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    fn scale(text: &str) -> Scale {
+        Scale::parse(text).unwrap()
+    }
+
+    fn mapping(text: &str) -> KeyboardMapping {
+        KeyboardMapping::parse(text).unwrap()
+    }
+
+    #[test]
+    fn scales_read_cents_and_ratios() {
+        let scale = scale("! meantone.scl\n!\nA test\n 4 notes\n!\n100.0\n3/2 fifth\n 5/4\n2\n");
+        let expected = [100.0, 701.955, 386.314, 1200.0];
+        assert_eq!(scale.cents.len(), expected.len());
+        for (cents, expected) in scale.cents.iter().zip(expected.iter()) {
+            assert!(close(*cents, *expected), "{} is not {}", cents, expected);
+        }
+        // the description may be empty
+        assert_eq!(Scale::parse("\n1\n1200.\n").unwrap().cents, [1200.0]);
+    }
+
+    #[test]
+    fn bad_scales_are_errors() {
+        assert!(Scale::parse("").is_err());
+        assert!(Scale::parse("Test\nmany\n").is_err());
+        assert!(Scale::parse("Test\n0\n").is_err());
+        assert!(Scale::parse("Test\n3\n100.0\n200.0\n").is_err());
+        assert!(Scale::parse("Test\n1\n-3/2\n").is_err());
+        assert!(Scale::parse("Test\n1\nfifth\n").is_err());
+    }
+
+    #[test]
+    fn mappings_read_every_value() {
+        let mapping = mapping(
+            "! white.kbm\n7\n21\n108\n60\n69\n432.0\n12\n! the white keys\n\
+             0\nx\n2\nx\n4\n5\nx\n",
+        );
+        assert_eq!(
+            (mapping.first_key, mapping.last_key, mapping.middle_key),
+            (21, 108, 60)
+        );
+        assert_eq!(
+            (mapping.reference_key, mapping.reference_frequency),
+            (69, 432.0)
+        );
+        assert_eq!(mapping.octave_step, 12);
+        assert_eq!(
+            mapping.mapping,
+            [Some(0), None, Some(2), None, Some(4), Some(5), None]
+        );
+    }
+
+    #[test]
+    fn bad_mappings_are_errors() {
+        assert!(KeyboardMapping::parse("0\n0\n127\n60\n69\n440.0\n").is_err());
+        assert!(KeyboardMapping::parse("0\n0\n128\n60\n69\n440.0\n0\n").is_err());
+        assert!(KeyboardMapping::parse("0\n0\n127\n60\n69\n0\n0\n").is_err());
+        assert!(KeyboardMapping::parse("2\n0\n127\n60\n69\n440.0\n12\n0\n").is_err());
+        assert!(KeyboardMapping::parse("1\n0\n127\n60\n69\n440.0\n12\ny\n").is_err());
+    }
+
+    #[test]
+    fn tunings_follow_the_scale_and_mapping() {
+        let equal = Tuning::default();
+        assert_eq!(equal.frequency(69), Some(440.0));
+        assert!(close(equal.frequency(60).unwrap() as f64, 261.626));
+
+        // pure fifths and thirds from middle C, tuned to C at 256 Hz
+        let just = Tuning::new(
+            &scale("Just\n3\n5/4\n3/2\n2/1\n"),
+            &mapping("3\n60\n72\n60\n60\n256.0\n3\n0\n1\n2\n"),
+        );
+        assert_eq!(just.frequency(60), Some(256.0));
+        assert_eq!(just.frequency(61), Some(320.0));
+        assert_eq!(just.frequency(62), Some(384.0));
+        assert_eq!(just.frequency(63), Some(512.0));
+        // outside the mapped keys
+        assert_eq!(just.frequency(59), None);
+        assert_eq!(just.frequency(73), None);
+    }
+
+    #[test]
+    fn unmapped_keys_play_nothing() {
+        let tuning = Tuning::new(
+            &Scale::equal(12),
+            &mapping("2\n0\n127\n60\n60\n256.0\n2\n0\nx\n"),
+        );
+        assert_eq!(tuning.frequency(60), Some(256.0));
+        assert_eq!(tuning.frequency(61), None);
+        assert!(close(tuning.frequency(62).unwrap() as f64, 287.350));
+        assert!(tuning.note(61, 100).is_none());
+    }
+}
@@ -0,0 +1,35 @@
+/// How the strike velocity of a note is reshaped before it is played.
+#[derive(Copy, Clone, PartialEq)]
+pub enum VelocityCurve {
+    Linear,
+    /// Soft notes come out softer, leaving more room at the top.
+    Exponential,
+    /// Every note plays at full velocity.
+    Fixed,
+}
+
+impl VelocityCurve {
+    pub fn from_name(name: &str) -> Option<VelocityCurve> {
+        match name {
+            "linear" => Some(VelocityCurve::Linear),
+            "exponential" => Some(VelocityCurve::Exponential),
+            "fixed" => Some(VelocityCurve::Fixed),
+            _ => None,
+        }
+    }
+
+    /// Maps a velocity in 0..=127 through the curve. Notes that were struck
+    /// at all stay above zero, since a zero velocity means note off in MIDI.
+    pub fn apply(&self, velocity: u8) -> u8 {
+        if velocity == 0 {
+            return 0;
+        }
+        let x = velocity.min(127) as f32 / 127.0;
+        let y = match self {
+            VelocityCurve::Linear => x,
+            VelocityCurve::Exponential => ((4.0 * x).exp() - 1.0) / (4.0_f32.exp() - 1.0),
+            VelocityCurve::Fixed => 1.0,
+        };
+        ((y * 127.0).round() as u8).max(1)
+    }
+}
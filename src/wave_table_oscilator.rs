@@ -43,6 +43,11 @@ impl WavetableOscillator {
         };
     }
 
+    /// The single cycle of the waveform this oscillator plays.
+    pub fn wave_table(&self) -> &[f32] {
+        &self.wave_table
+    }
+
     fn set_frequency(&mut self, frequency: f32) {
         if frequency != self.current_frequency {
             self.index_increment =
@@ -0,0 +1,239 @@
+use crate::biquad::{Biquad, Coefficients};
+use crate::effect::Effect;
+
+/// Taps of the anti-alias filter per step of oversampling.
+const TAPS_PER_FACTOR: usize = 16;
+/// Offset added before the tube curve so it clips one side earlier.
+const TUBE_BIAS: f32 = 0.3;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ShapeMode {
+    /// Smooth saturation, tanh.
+    SoftClip,
+    HardClip,
+    /// Saturates positive and negative halves differently, adding even harmonics.
+    Tube,
+    /// Peaks above 1.0 are folded back down instead of clipped.
+    Foldback,
+    /// Fewer bits and a lower sample rate, see `WaveshaperSettings`.
+    Bitcrush,
+}
+
+impl ShapeMode {
+    pub fn from_name(name: &str) -> Option<ShapeMode> {
+        match name {
+            "soft" => Some(ShapeMode::SoftClip),
+            "hard" => Some(ShapeMode::HardClip),
+            "tube" => Some(ShapeMode::Tube),
+            "fold" => Some(ShapeMode::Foldback),
+            "crush" => Some(ShapeMode::Bitcrush),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct WaveshaperSettings {
+    pub mode: ShapeMode,
+    /// Gain in dB into the shaper, more drives it harder.
+    pub pre_gain: f32,
+    /// Gain in dB after the shaper.
+    pub post_gain: f32,
+    /// Runs the shaper at 1, 2 or 4 times the sample rate, keeping the
+    /// harmonics it adds from folding back as aliasing.
+    pub oversampling: usize,
+    /// Bit depth of the bitcrusher.
+    pub bits: u32,
+    /// The bitcrusher holds every sample for this many samples.
+    pub downsample: usize,
+}
+
+impl Default for WaveshaperSettings {
+    fn default() -> Self {
+        WaveshaperSettings {
+            mode: ShapeMode::SoftClip,
+            pre_gain: 0.0,
+            post_gain: 0.0,
+            oversampling: 1,
+            bits: 8,
+            downsample: 4,
+        }
+    }
+}
+
+/// A windowed sinc low pass FIR.
+#[derive(Clone)]
+struct Fir {
+    taps: Vec<f32>,
+    history: Vec<f32>,
+    index: usize,
+}
+
+impl Fir {
+    /// `cutoff` is a fraction of the sample rate the filter runs at.
+    fn low_pass(length: usize, cutoff: f32) -> Fir {
+        let middle = (length - 1) as f32 / 2.0;
+        let pi = std::f32::consts::PI;
+        let mut taps: Vec<f32> = (0..length)
+            .map(|i| {
+                let n = i as f32 - middle;
+                let sinc = if n == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (2.0 * pi * cutoff * n).sin() / (pi * n)
+                };
+                // Blackman window
+                let phase = 2.0 * pi * i as f32 / (length - 1) as f32;
+                sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+            })
+            .collect();
+        let sum: f32 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap /= sum);
+
+        Fir {
+            history: vec![0.0; length],
+            taps,
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.history[self.index] = input;
+        let length = self.history.len();
+        let mut output = 0.0;
+        for (i, tap) in self.taps.iter().enumerate() {
+            output += tap * self.history[(self.index + length - i) % length];
+        }
+        self.index = (self.index + 1) % length;
+        output
+    }
+}
+
+/// Everything one channel of the shaper keeps between samples.
+#[derive(Clone)]
+struct Channel {
+    up: Fir,
+    down: Fir,
+    /// Sample held by the bitcrusher.
+    held: f32,
+    hold_count: usize,
+    dc_blocker: Biquad,
+}
+
+impl Channel {
+    fn shape(&mut self, x: f32, settings: &WaveshaperSettings, hold_length: usize) -> f32 {
+        match settings.mode {
+            ShapeMode::SoftClip => x.tanh(),
+            ShapeMode::HardClip => x.clamp(-1.0, 1.0),
+            ShapeMode::Tube => (x + TUBE_BIAS).tanh() - TUBE_BIAS.tanh(),
+            ShapeMode::Foldback => 1.0 - ((x + 1.0).rem_euclid(4.0) - 2.0).abs(),
+            ShapeMode::Bitcrush => {
+                if self.hold_count == 0 {
+                    let levels = 2.0_f32.powi(settings.bits.clamp(1, 24) as i32 - 1);
+                    self.held = (x.clamp(-1.0, 1.0) * levels).round() / levels;
+                }
+                self.hold_count = (self.hold_count + 1) % hold_length;
+                self.held
+            }
+        }
+    }
+
+    fn process(
+        &mut self,
+        input: f32,
+        settings: &WaveshaperSettings,
+        factor: usize,
+        dc_blocker: &Coefficients,
+    ) -> f32 {
+        let output = self.oversampled_shape(input, settings, factor);
+        // the tube curve in particular shifts the signal off centre
+        self.dc_blocker.process(dc_blocker, output)
+    }
+
+    fn oversampled_shape(
+        &mut self,
+        input: f32,
+        settings: &WaveshaperSettings,
+        factor: usize,
+    ) -> f32 {
+        let hold_length = settings.downsample.max(1) * factor;
+        if factor == 1 {
+            return self.shape(input, settings, hold_length);
+        }
+        // zero stuffing, then the filters take out the images above the
+        // original band before and the harmonics above it after shaping
+        let mut output = 0.0;
+        for i in 0..factor {
+            let stuffed = if i == 0 { input * factor as f32 } else { 0.0 };
+            let upsampled = self.up.process(stuffed);
+            let shaped = self.shape(upsampled, settings, hold_length);
+            output = self.down.process(shaped);
+        }
+        output
+    }
+}
+
+/// Distortion by a static curve, for a single voice or the whole mix.
+pub struct Waveshaper {
+    settings: WaveshaperSettings,
+    factor: usize,
+    pre_gain: f32,
+    post_gain: f32,
+    dc_blocker: Coefficients,
+    left: Channel,
+    right: Channel,
+}
+
+impl Waveshaper {
+    pub fn new(sample_rate: u32, settings: WaveshaperSettings) -> Waveshaper {
+        let factor = match settings.oversampling {
+            0 | 1 => 1,
+            2 | 3 => 2,
+            _ => 4,
+        };
+        // cut a little below the original Nyquist frequency
+        let fir = Fir::low_pass(TAPS_PER_FACTOR * factor + 1, 0.45 / factor as f32);
+        let channel = Channel {
+            up: fir.clone(),
+            down: fir,
+            held: 0.0,
+            hold_count: 0,
+            dc_blocker: Biquad::default(),
+        };
+        let decibels = |db: f32| 10.0_f32.powf(db / 20.0);
+
+        Waveshaper {
+            settings,
+            factor,
+            pre_gain: decibels(settings.pre_gain),
+            post_gain: decibels(settings.post_gain),
+            dc_blocker: Coefficients::dc_blocker(sample_rate),
+            left: channel.clone(),
+            right: channel,
+        }
+    }
+}
+
+impl Effect for Waveshaper {
+    fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let settings = &self.settings;
+        let dc_blocker = &self.dc_blocker;
+        (
+            self.left
+                .process(left * self.pre_gain, settings, self.factor, dc_blocker)
+                * self.post_gain,
+            self.right
+                .process(right * self.pre_gain, settings, self.factor, dc_blocker)
+                * self.post_gain,
+        )
+    }
+
+    fn process_mono(&mut self, input: f32) -> f32 {
+        self.left.process(
+            input * self.pre_gain,
+            &self.settings,
+            self.factor,
+            &self.dc_blocker,
+        ) * self.post_gain
+    }
+}